@@ -0,0 +1,186 @@
+//! Derive macros for `espionox`, enabled with its `derive` feature
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, Fields, Lit, Meta};
+
+/// Describes a type to a model as a `Function`.
+///
+/// On a struct with named fields, implements `TypedFunction`. The function is named after the
+/// struct in snake case and described by its doc comment, each field becomes a parameter described
+/// by its own doc comment. `Option` fields aren't required, every other field is. Fields must
+/// implement `FromArgument`, which `from_arguments` reads them with.
+///
+/// On an enum of unit variants, implements `FromArgument` as a `ParamType::Enum` of its variants
+/// in snake case, so that it can be a field of such a struct.
+#[proc_macro_derive(EspxFunction)]
+pub fn derive_espx_function(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let expanded = match &input.data {
+        Data::Struct(_) => expand_struct(&input),
+        Data::Enum(_) => expand_enum(&input),
+        Data::Union(_) => Err(Error::new_spanned(
+            &input.ident,
+            "EspxFunction can't be derived for unions",
+        )),
+    };
+    expanded.unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_struct(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        unreachable!("only called on structs")
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "EspxFunction can only be derived for structs with named fields",
+        ));
+    };
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let name = snake_case(&ident.to_string());
+    let description = doc_comment(&input.attrs).unwrap_or_default();
+
+    let params = fields.named.iter().map(|field| {
+        let field_name = field.ident.as_ref().expect("fields are named").to_string();
+        let ty = &field.ty;
+        let description = match doc_comment(&field.attrs) {
+            Some(doc) => quote!(::std::option::Option::Some(::std::string::String::from(#doc))),
+            None => quote!(::std::option::Option::None),
+        };
+        quote! {
+            params.insert(
+                ::std::string::String::from(#field_name),
+                functions::FunctionParam {
+                    description: #description,
+                    typ: <#ty as functions::FromArgument>::param_type(),
+                    required: <#ty as functions::FromArgument>::REQUIRED,
+                },
+            );
+        }
+    });
+    let reads = fields.named.iter().map(|field| {
+        let field_ident = field.ident.as_ref().expect("fields are named");
+        let field_name = field_ident.to_string();
+        quote!(#field_ident: functions::typed::argument(&arguments, #field_name)?)
+    });
+
+    Ok(quote! {
+        const _: () = {
+            use ::espionox::language_models::completions::functions;
+            use ::espionox::__private::serde_json;
+
+            impl #impl_generics functions::TypedFunction for #ident #ty_generics #where_clause {
+                fn function() -> functions::Function {
+                    let mut params = ::std::collections::HashMap::new();
+                    #(#params)*
+                    functions::Function {
+                        name: ::std::string::String::from(#name),
+                        description: ::std::string::String::from(#description),
+                        params,
+                    }
+                }
+
+                fn from_arguments(
+                    arguments: serde_json::Value,
+                ) -> ::std::result::Result<Self, serde_json::Error> {
+                    ::std::result::Result::Ok(Self {
+                        #(#reads,)*
+                    })
+                }
+            }
+        };
+    })
+}
+
+fn expand_enum(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        unreachable!("only called on enums")
+    };
+    if let Some(variant) = data
+        .variants
+        .iter()
+        .find(|v| !matches!(v.fields, Fields::Unit))
+    {
+        return Err(Error::new_spanned(
+            variant,
+            "EspxFunction can only be derived for enums whose variants have no fields",
+        ));
+    }
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let variant_idents: Vec<_> = data.variants.iter().map(|v| &v.ident).collect();
+    let variant_names: Vec<_> = variant_idents
+        .iter()
+        .map(|v| snake_case(&v.to_string()))
+        .collect();
+
+    Ok(quote! {
+        const _: () = {
+            use ::espionox::language_models::completions::functions;
+            use ::espionox::__private::serde_json;
+
+            impl #impl_generics functions::FromArgument for #ident #ty_generics #where_clause {
+                fn param_type() -> functions::ParamType {
+                    functions::ParamType::Enum(::std::vec![
+                        #(::std::string::String::from(#variant_names)),*
+                    ])
+                }
+
+                fn from_argument(
+                    value: &serde_json::Value,
+                ) -> ::std::result::Result<Self, serde_json::Error> {
+                    match value.as_str() {
+                        #(::std::option::Option::Some(#variant_names) => {
+                            ::std::result::Result::Ok(Self::#variant_idents)
+                        })*
+                        _ => ::std::result::Result::Err(functions::typed::unknown_variant(
+                            value,
+                            &[#(#variant_names),*],
+                        )),
+                    }
+                }
+            }
+        };
+    })
+}
+
+/// Lines of the doc comment in `attrs` joined into one, `None` if there isn't one
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(nv) => match &nv.value {
+                Expr::Lit(expr) => match &expr.lit {
+                    Lit::Str(doc) => Some(doc.value().trim().to_string()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+fn snake_case(ident: &str) -> String {
+    let mut snake = String::new();
+    let mut prev_lower = false;
+    for c in ident.chars() {
+        if c.is_uppercase() {
+            if prev_lower {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+            prev_lower = false;
+        } else {
+            snake.push(c);
+            prev_lower = c.is_lowercase() || c.is_ascii_digit();
+        }
+    }
+    snake
+}
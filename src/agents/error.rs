@@ -7,6 +7,20 @@ pub enum AgentError {
     #[error(transparent)]
     Undefined(#[from] anyhow::Error),
     CompletionError(#[from] CompletionError),
+    ToolNotFound(String),
+    ToolIterationLimit(usize),
+    /// Vote couldn't pick an answer out of this many samples
+    NoConsensus(usize),
+    /// Model's output still didn't deserialize after `attempts` tries
+    InvalidOutput {
+        attempts: usize,
+        source: serde_json::Error,
+    },
+    /// Agent has already spent `spent` dollars of its `budget`
+    BudgetExceeded {
+        spent: f64,
+        budget: f64,
+    },
 }
 
 impl Debug for AgentError {
@@ -20,6 +34,22 @@ impl Display for AgentError {
         let display = match self {
             Self::Undefined(err) => err.to_string(),
             Self::CompletionError(err) => err.to_string(),
+            Self::ToolNotFound(name) => format!("Model called unregistered tool: {}", name),
+            Self::ToolIterationLimit(limit) => {
+                format!("Model was still calling tools after {} iterations", limit)
+            }
+            Self::NoConsensus(samples) => {
+                format!("Could not pick an answer out of {} samples", samples)
+            }
+            Self::InvalidOutput { attempts, .. } => {
+                format!("Model output was still invalid after {} attempts", attempts)
+            }
+            Self::BudgetExceeded { spent, budget } => {
+                format!(
+                    "Refusing completion, ${:.4} spent of a ${:.4} budget",
+                    spent, budget
+                )
+            }
         };
         write!(f, "{}", display)
     }
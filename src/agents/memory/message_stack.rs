@@ -140,13 +140,17 @@ impl<'stack> MessageStack {
     // }
 
     /// Push a message to the end of MessageStack, does nothing if the message's content is empty
+    /// and it contains no tool calls or images
     pub fn push(&mut self, message: Message) {
         if &MessageRole::System == message.role.actual() && self.len() > 0 {
             if let Some(sys_prompt) = self.mut_system_prompt_content() {
                 sys_prompt.push_str(&format!(" {}", message.content))
             }
         } else {
-            if message.content.is_empty() {
+            if message.content.is_empty()
+                && message.tool_calls.is_empty()
+                && message.images.is_empty()
+            {
                 warn!("cannot push message with empty content to message stack");
                 return;
             }
@@ -1,18 +1,85 @@
 use super::MessageStack;
+use crate::language_models::completions::functions::ToolCall;
 use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::fmt;
+use std::{fmt, path::Path};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
+    /// Functions the model called in this message, only ever present on assistant messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Images sent along with the text content, only supported on user messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<MessageImage>,
 }
 
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        self.role == other.role && self.content == other.content
+        self.role == other.role
+            && self.content == other.content
+            && self.tool_calls == other.tool_calls
+            && self.images == other.images
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MessageImage {
+    /// e.g. `image/png`
+    pub media_type: String,
+    pub source: ImageSource,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ImageSource {
+    /// Base64 encoded image data
+    Base64(String),
+    Url(String),
+}
+
+impl MessageImage {
+    pub fn from_bytes(bytes: &[u8], media_type: &str) -> Self {
+        Self {
+            media_type: media_type.to_owned(),
+            source: ImageSource::Base64(STANDARD.encode(bytes)),
+        }
+    }
+
+    /// Reads a local image file, media type is inferred from the file extension
+    pub fn from_path(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        let media_type = match extension.as_deref() {
+            Some("png") => "image/png",
+            Some("jpg") | Some("jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            other => return Err(anyhow!("Unsupported image extension: {:?}", other)),
+        };
+        let bytes = std::fs::read(path)?;
+        Ok(Self::from_bytes(&bytes, media_type))
+    }
+
+    pub fn from_url(url: &str, media_type: &str) -> Self {
+        Self {
+            media_type: media_type.to_owned(),
+            source: ImageSource::Url(url.to_owned()),
+        }
+    }
+
+    /// Either the image's url or a base64 data url
+    pub fn url(&self) -> String {
+        match &self.source {
+            ImageSource::Url(url) => url.to_owned(),
+            ImageSource::Base64(data) => format!("data:{};base64,{}", self.media_type, data),
+        }
     }
 }
 impl Eq for Message {}
@@ -30,6 +97,8 @@ impl ToMessage for String {
         Message {
             role,
             content: self.to_owned(),
+            tool_calls: vec![],
+            images: vec![],
         }
     }
 }
@@ -46,29 +115,24 @@ pub enum MessageRole {
     Assistant,
     User,
     System,
+    /// Output of a function the model called, `tool_call_id` is the id of the `ToolCall` it answers
+    Tool {
+        tool_call_id: String,
+        name: String,
+    },
     Other {
         alias: String,
         coerce_to: OtherRoleTo,
     },
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct FunctionMessage {
-    pub function_call: FunctionCall,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct FunctionCall {
-    name: String,
-    arguments: Vec<Value>,
-}
-
 impl ToString for MessageRole {
     fn to_string(&self) -> String {
         match &self {
             &Self::System => String::from("system"),
             &Self::User => String::from("user"),
             &Self::Assistant => String::from("assistant"),
+            &Self::Tool { .. } => String::from("tool"),
             &Self::Other { alias, .. } => alias.to_string(),
         }
     }
@@ -109,6 +173,8 @@ impl Message {
                 coerce_to,
             },
             content: content.to_string(),
+            tool_calls: vec![],
+            images: vec![],
         }
     }
 
@@ -116,6 +182,8 @@ impl Message {
         Message {
             role: MessageRole::System,
             content: content.to_string(),
+            tool_calls: vec![],
+            images: vec![],
         }
     }
 
@@ -123,6 +191,8 @@ impl Message {
         Message {
             role: MessageRole::User,
             content: content.to_string(),
+            tool_calls: vec![],
+            images: vec![],
         }
     }
 
@@ -130,26 +200,41 @@ impl Message {
         Message {
             role: MessageRole::Assistant,
             content: content.to_string(),
+            tool_calls: vec![],
+            images: vec![],
         }
     }
-}
 
-impl From<Value> for FunctionCall {
-    fn from(value: Value) -> Self {
-        let name = value.get("name").expect("Failed to get name").to_string();
-        let arguments = value
-            .get("arguments")
-            .expect("Failed to get args")
-            .as_array()
-            .expect("Failed to get arguments array")
-            .to_vec();
-        Self { name, arguments }
+    pub fn new_user_with_images(content: &str, images: Vec<MessageImage>) -> Self {
+        Message {
+            role: MessageRole::User,
+            content: content.to_string(),
+            tool_calls: vec![],
+            images,
+        }
     }
-}
 
-impl Into<Value> for FunctionCall {
-    fn into(self) -> Value {
-        json!({"name": self.name, "arguments": self.arguments})
+    /// Assistant message containing calls the model made to functions. `content` may be empty
+    pub fn new_tool_calls(content: &str, tool_calls: Vec<ToolCall>) -> Self {
+        Message {
+            role: MessageRole::Assistant,
+            content: content.to_string(),
+            tool_calls,
+            images: vec![],
+        }
+    }
+
+    /// Message containing the output of a function the model called
+    pub fn new_tool_result(call: &ToolCall, output: &str) -> Self {
+        Message {
+            role: MessageRole::Tool {
+                tool_call_id: call.id.to_owned(),
+                name: call.name.to_owned(),
+            },
+            content: output.to_string(),
+            tool_calls: vec![],
+            images: vec![],
+        }
     }
 }
 
@@ -166,7 +251,12 @@ impl TryFrom<Value> for Message {
             .get("content")
             .expect("Couldn't get content")
             .to_string();
-        Ok(Message { role, content })
+        Ok(Message {
+            role,
+            content,
+            tool_calls: vec![],
+            images: vec![],
+        })
     }
 }
 
@@ -1,18 +1,38 @@
 pub mod error;
 pub mod memory;
+pub mod tools;
 use crate::language_models::completions::{
-    functions::Function, streaming::ProviderStreamHandler, CompletionModel,
+    consistency::{Consensus, Vote},
+    functions::{Function, ToolChoice, ToolCompletion, TypedFunction},
+    metadata::{Completion, CompletionMetadata, TokenUsage},
+    streaming::ProviderStreamHandler,
+    CompletionModel,
 };
 pub use error::AgentError;
-use memory::MessageStack;
+use memory::{Message, MessageStack};
 use std::fmt::Debug;
+use tools::ToolBox;
 
 use error::AgentResult;
 
+/// Times `typed_completion` sends a model its invalid output back to be fixed
+pub const DEFAULT_REPAIR_ATTEMPTS: usize = 2;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Agent {
     pub cache: MessageStack,
     pub completion_model: CompletionModel,
+    /// Running total of the tokens used by every completion this agent has gotten
+    #[serde(default)]
+    pub usage: TokenUsage,
+    /// Running total, in dollars, of what every completion this agent has gotten cost. Only
+    /// counts completions from models with a known price
+    #[serde(default)]
+    pub cost: f64,
+    /// Dollars this agent may spend. Once `cost` reaches it completions are refused with
+    /// `AgentError::BudgetExceeded`
+    #[serde(default)]
+    pub budget: Option<f64>,
 }
 
 impl Agent {
@@ -25,32 +45,221 @@ impl Agent {
         Agent {
             cache,
             completion_model,
+            usage: TokenUsage::default(),
+            cost: 0.0,
+            budget: None,
+        }
+    }
+
+    /// Limit what this agent may spend on completions, in dollars
+    pub fn with_budget(mut self, budget: f64) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Dollars left to spend, `None` if the agent has no budget
+    pub fn remaining_budget(&self) -> Option<f64> {
+        self.budget.map(|b| (b - self.cost).max(0.0))
+    }
+
+    /// Errors if the agent has used up its budget. Checked before every request
+    fn check_budget(&self) -> AgentResult<()> {
+        match self.budget {
+            Some(budget) if self.cost >= budget => Err(AgentError::BudgetExceeded {
+                spent: self.cost,
+                budget,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the usage and cost of a completion to the running totals of both the agent and its model.
+    /// Cost is worked out with the price of whichever model in the fallback chain answered
+    pub(crate) fn record_usage(&mut self, metadata: &CompletionMetadata) {
+        if let Some(usage) = metadata.usage {
+            self.usage += usage;
+            self.cost += self
+                .completion_model
+                .fallback(metadata.fallback_index)
+                .cost(&usage);
+            self.completion_model.params.total_token_count += usage.total_tokens();
         }
     }
 
     /// Get a simple string response from a model
-    pub async fn io_completion(&mut self) -> AgentResult<String> {
-        Ok(self.completion_model.get_io_completion(&self.cache).await?)
+    pub async fn io_completion(&mut self) -> AgentResult<Completion<String>> {
+        self.check_budget()?;
+        let completion = self.completion_model.get_io_completion(&self.cache).await?;
+        self.record_usage(&completion.metadata);
+        Ok(completion)
+    }
+
+    /// Get `n` answers from a model. Providers with an `n` parameter, like OpenAi, return them all
+    /// from one request, others, like Anthropic, are sent `n` requests at the same time
+    pub async fn io_completions(&mut self, n: usize) -> AgentResult<Completion<Vec<String>>> {
+        self.check_budget()?;
+        let completion = self.completion_model.get_io_choices(&self.cache, n).await?;
+        self.record_usage(&completion.metadata);
+        Ok(completion)
     }
 
-    /// Get a streamed response from a model
+    /// Sample `n` answers and return the one `vote` picks. Samples only differ if the model has
+    /// some randomness, so give it a temperature above 0. Like `io_completion`, nothing is pushed
+    /// to the cache
+    pub async fn self_consistent_completion(
+        &mut self,
+        n: usize,
+        vote: &Vote,
+    ) -> AgentResult<Completion<Consensus>> {
+        let completion = self.io_completions(n).await?;
+        let consensus = vote
+            .decide(completion.content)
+            .ok_or(AgentError::NoConsensus(n))?;
+        Ok(Completion::new(consensus, completion.metadata))
+    }
+
+    /// Get a streamed response from a model. Usage is recorded once the stream finishes
     pub async fn stream_completion(&mut self) -> AgentResult<ProviderStreamHandler> {
+        self.check_budget()?;
         let cs = self
             .completion_model
-            .get_stream_completion(&self.cache)
+            .get_stream_completion(&self.cache, None)
             .await?;
 
         Ok(cs.into())
     }
 
+    /// Get a streamed response in which the model may call any of the given functions. Calls are
+    /// streamed as `StreamEvent`s as they're generated, and parsed once the stream finishes.
+    /// Only OpenAi and Anthropic models support this
+    pub async fn stream_tool_completion(
+        &mut self,
+        functions: &[Function],
+        choice: ToolChoice,
+    ) -> AgentResult<ProviderStreamHandler> {
+        self.check_budget()?;
+        let handler = self
+            .completion_model
+            .get_stream_completion(&self.cache, Some((functions, &choice)))
+            .await?;
+        Ok(handler)
+    }
+
     /// Get a function completion from a model, returns a JSON object
     pub async fn function_completion(
         &mut self,
         function: Function,
-    ) -> AgentResult<serde_json::Value> {
-        Ok(self
+    ) -> AgentResult<Completion<serde_json::Value>> {
+        self.check_budget()?;
+        let completion = self
             .completion_model
             .get_fn_completion(&self.cache, function)
-            .await?)
+            .await?;
+        self.record_usage(&completion.metadata);
+        Ok(completion)
+    }
+
+    /// Get a completion read into `T`, see `typed_completion_with_repairs`
+    pub async fn typed_completion<T: TypedFunction>(&mut self) -> AgentResult<Completion<T>> {
+        self.typed_completion_with_repairs(DEFAULT_REPAIR_ATTEMPTS)
+            .await
+    }
+
+    /// Get a completion read into `T`. Models which support JSON schemas are made to answer with
+    /// JSON matching `T::function()`, the rest are made to call it. When the output can't be read
+    /// into `T`, it's sent back to the model along with the error to be fixed, up to
+    /// `max_repairs` times. Neither the answer nor the repairs are pushed to the cache
+    pub async fn typed_completion_with_repairs<T: TypedFunction>(
+        &mut self,
+        max_repairs: usize,
+    ) -> AgentResult<Completion<T>> {
+        let function = T::function();
+        let mut messages = self.cache.clone();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.check_budget()?;
+            let completion = self
+                .completion_model
+                .get_structured_completion(&messages, &function)
+                .await?;
+            self.record_usage(&completion.metadata);
+            let typed = serde_json::from_str(&completion.content).and_then(T::from_arguments);
+            let err = match typed {
+                Ok(typed) => return Ok(Completion::new(typed, completion.metadata)),
+                Err(err) if attempt > max_repairs => {
+                    return Err(AgentError::InvalidOutput {
+                        attempts: attempt,
+                        source: err,
+                    })
+                }
+                Err(err) => err,
+            };
+            messages.push(Message::new_assistant(&completion.content));
+            messages.push(Message::new_user(&format!(
+                "That output is invalid: {}. Answer again with only the corrected JSON",
+                err
+            )));
+        }
+    }
+
+    /// Get a completion in which the model may call any of the given functions. Unlike
+    /// `function_completion`, every call the model makes is returned, along with its id
+    pub async fn tool_completion(
+        &mut self,
+        functions: &[Function],
+        choice: ToolChoice,
+    ) -> AgentResult<Completion<ToolCompletion>> {
+        self.check_budget()?;
+        let completion = self
+            .completion_model
+            .get_tool_completion(&self.cache, functions, &choice)
+            .await?;
+        self.record_usage(&completion.metadata);
+        Ok(completion)
+    }
+
+    /// Prompt the model with every function in `tools`. Each time the model calls one, the
+    /// matching handler is run and both the call and its result are pushed to the cache as tool
+    /// messages before prompting again. Returns the model's final answer, which is also pushed to the cache.
+    /// Errors if the model is still calling functions after `max_iterations` completions. If a
+    /// handler fails its error is returned and the calls it was part of aren't pushed
+    pub async fn run_with_tools(
+        &mut self,
+        tools: &ToolBox,
+        max_iterations: usize,
+    ) -> AgentResult<String> {
+        let functions = tools.functions();
+        for _ in 0..max_iterations {
+            self.check_budget()?;
+            let completion = self
+                .completion_model
+                .get_tool_completion(&self.cache, &functions, &ToolChoice::Auto)
+                .await?;
+            self.record_usage(&completion.metadata);
+            match completion.content {
+                ToolCompletion::Message(content) => {
+                    self.cache.push(Message::new_assistant(&content));
+                    return Ok(content);
+                }
+                ToolCompletion::Calls(calls) => {
+                    // Every call needs a result in the cache, so nothing is pushed until all the
+                    // handlers have succeeded
+                    let mut results = Vec::with_capacity(calls.len());
+                    for call in calls.iter() {
+                        let output = match tools.call(call).await? {
+                            serde_json::Value::String(s) => s,
+                            other => other.to_string(),
+                        };
+                        results.push(Message::new_tool_result(call, &output));
+                    }
+                    self.cache.push(Message::new_tool_calls("", calls));
+                    for result in results {
+                        self.cache.push(result);
+                    }
+                }
+            }
+        }
+        Err(AgentError::ToolIterationLimit(max_iterations))
     }
 }
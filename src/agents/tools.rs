@@ -0,0 +1,110 @@
+use super::error::{AgentError, AgentResult};
+use crate::language_models::completions::functions::{Function, ToolCall};
+use futures::Future;
+use serde_json::Value;
+use std::{fmt::Debug, pin::Pin, sync::Arc};
+
+pub type ToolHandlerReturn = Pin<Box<dyn Future<Output = AgentResult<Value>> + Send>>;
+pub type ToolHandler = Arc<dyn Fn(Value) -> ToolHandlerReturn + Send + Sync>;
+
+/// A `Function` paired with the async handler that runs when a model calls it
+#[derive(Clone)]
+pub struct Tool {
+    pub function: Function,
+    handler: ToolHandler,
+}
+
+impl Debug for Tool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tool")
+            .field("function", &self.function)
+            .field("handler", &"<<skipped>>")
+            .finish()
+    }
+}
+
+/// Collection of tools an agent can be given with `Agent::run_with_tools`. Tools are sent to the
+/// model in the order they were registered, so requests stay the same from run to run
+#[derive(Debug, Clone, Default)]
+pub struct ToolBox(Vec<Tool>);
+
+impl ToolBox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function along with its handler. The handler receives the arguments the model
+    /// gave as a JSON object. Registering a function with an existing name replaces the old one in
+    /// its place
+    pub fn register<F, Fut>(&mut self, function: Function, handler: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AgentResult<Value>> + Send + 'static,
+    {
+        let handler: ToolHandler = Arc::new(move |args| Box::pin(handler(args)));
+        let tool = Tool { function, handler };
+        match self.position(&tool.function.name) {
+            Some(index) => self.0[index] = tool,
+            None => self.0.push(tool),
+        }
+    }
+
+    pub fn functions(&self) -> Vec<Function> {
+        self.0.iter().map(|t| t.function.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Runs the handler of the tool the model called
+    pub async fn call(&self, call: &ToolCall) -> AgentResult<Value> {
+        let index = self
+            .position(&call.name)
+            .ok_or(AgentError::ToolNotFound(call.name.to_owned()))?;
+        (self.0[index].handler)(call.arguments.clone()).await
+    }
+
+    /// Index of the tool named `name`
+    fn position(&self, name: &str) -> Option<usize> {
+        self.0.iter().position(|t| t.function.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(name: &str) -> Function {
+        Function {
+            name: name.to_owned(),
+            description: String::new(),
+            params: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn tools_kept_in_registration_order() {
+        let mut tools = ToolBox::new();
+        for name in ["b", "a", "c"] {
+            tools.register(
+                function(name),
+                move |_| async move { Ok(Value::from(name)) },
+            );
+        }
+        tools.register(function("a"), |_| async { Ok(Value::from("new a")) });
+        let names: Vec<_> = tools.functions().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["b", "a", "c"]);
+
+        let call = ToolCall {
+            id: "call_a".to_owned(),
+            name: "a".to_owned(),
+            arguments: Value::Null,
+        };
+        assert_eq!(tools.call(&call).await.unwrap(), "new a");
+    }
+}
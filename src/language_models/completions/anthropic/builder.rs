@@ -1,15 +1,20 @@
 use super::{
     super::{
         error::CompletionResult,
+        functions::{Function, ToolCall, ToolChoice, ToolCompletion},
         inference::{CompletionRequest, CompletionRequestBuilder},
+        metadata::CompletionMetadata,
+        streaming::sse::SseCompletionRequest,
         ModelParameters,
     },
-    requests::AnthropicIoRequest,
+    requests::{anthropic_response_metadata, AnthropicIoRequest},
+    streaming::AnthropicStreamResponse,
 };
-use crate::agents::memory::{Message, MessageStack};
+use crate::agents::memory::{ImageSource, Message, MessageRole, MessageStack};
+use crate::language_models::pricing::{default_pricing, ModelPricing};
 use reqwest::header::HeaderMap;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::{de::Error, Deserialize, Serialize};
+use serde_json::{json, Value};
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub enum AnthropicCompletionModel {
@@ -23,6 +28,59 @@ const OPUS_MODEL_STR: &str = "claude-3-opus-20240229";
 const SONNET_MODEL_STR: &str = "claude-3-sonnet-20240229";
 const HAIKU_MODEL_STR: &str = "claude-3-haiku-20240307";
 
+impl AnthropicCompletionModel {
+    /// Anthropic role a message is sent as, along with its content blocks
+    fn message_content_blocks(message: &Message) -> (String, Vec<Value>) {
+        if let MessageRole::Tool { tool_call_id, .. } = &message.role {
+            let block = json!({
+                "type": "tool_result",
+                "tool_use_id": tool_call_id,
+                "content": message.content,
+            });
+            return (MessageRole::User.to_string(), vec![block]);
+        }
+        let mut blocks = vec![];
+        for image in message.images.iter() {
+            let source = match &image.source {
+                ImageSource::Base64(data) => json!({
+                    "type": "base64",
+                    "media_type": image.media_type,
+                    "data": data,
+                }),
+                ImageSource::Url(url) => json!({"type": "url", "url": url}),
+            };
+            blocks.push(json!({"type": "image", "source": source}));
+        }
+        if !message.content.is_empty() {
+            blocks.push(json!({"type": "text", "text": message.content}));
+        }
+        for call in message.tool_calls.iter() {
+            blocks.push(json!({
+                "type": "tool_use",
+                "id": call.id,
+                "name": call.name,
+                "input": call.arguments,
+            }));
+        }
+        (message.role.actual().to_string(), blocks)
+    }
+
+    /// Messages made only of text are sent with string content, like they always have been
+    fn blocks_into_message_json((role, blocks): (String, Vec<Value>)) -> Value {
+        let text: Option<Vec<&str>> = blocks
+            .iter()
+            .map(|b| match b.get("type").and_then(|t| t.as_str()) {
+                Some("text") => b.get("text").and_then(|t| t.as_str()),
+                _ => None,
+            })
+            .collect();
+        match text {
+            Some(text) => json!({"role": role, "content": text.join(". ")}),
+            None => json!({"role": role, "content": blocks}),
+        }
+    }
+}
+
 impl CompletionRequestBuilder for AnthropicCompletionModel {
     fn model_str(&self) -> &str {
         match self {
@@ -32,8 +90,11 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
         }
     }
 
-    fn url_str(&self) -> &str {
-        "https://api.anthropic.com/v1/messages"
+    fn pricing(&self) -> Option<ModelPricing> {
+        default_pricing(self.model_str())
+    }
+    fn url_str(&self) -> String {
+        "https://api.anthropic.com/v1/messages".to_owned()
     }
 
     fn headers(&self, api_key: &str) -> HeaderMap {
@@ -48,25 +109,22 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
         // Anthropic model requires that messages alternate from User to assistant. So we'll
         // concatenate all adjacent messages to one
         let mut val_vec: Vec<Value> = vec![];
-        let mut last_message: Option<Message> = None;
-        for message in stack.clone().into_iter() {
-            match last_message.take() {
-                Some(mut m) => {
-                    if message.role.to_string() == m.role.to_string() {
-                        m.content = format!("{}. {}", m.content, message.content);
-                        last_message = Some(m);
-                    } else {
-                        let val: Value = m.into();
-                        val_vec.push(val);
-                        last_message = Some(message);
+        let mut last_message: Option<(String, Vec<Value>)> = None;
+        for message in stack.as_ref().iter() {
+            let (role, blocks) = Self::message_content_blocks(message);
+            match last_message.as_mut() {
+                Some((last_role, last_blocks)) if *last_role == role => {
+                    last_blocks.extend(blocks);
+                }
+                _ => {
+                    if let Some(m) = last_message.replace((role, blocks)) {
+                        val_vec.push(Self::blocks_into_message_json(m));
                     }
                 }
-                None => last_message = Some(message),
             }
         }
         if let Some(m) = last_message {
-            let val: Value = m.into();
-            val_vec.push(val);
+            val_vec.push(Self::blocks_into_message_json(m));
         }
         val_vec.into()
     }
@@ -90,12 +148,106 @@ impl CompletionRequestBuilder for AnthropicCompletionModel {
             stack, params, *self, true,
         )))
     }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        let tools: Vec<Value> = functions
+            .iter()
+            .map(|f| {
+                json!({
+                    "name": f.name,
+                    "description": f.description,
+                    "input_schema": f.params_json_schema(),
+                })
+            })
+            .collect();
+        let tool_choice = match choice {
+            ToolChoice::Auto => json!({"type": "auto"}),
+            ToolChoice::None => json!({"type": "none"}),
+            ToolChoice::Required => json!({"type": "any"}),
+            ToolChoice::Function(name) => json!({"type": "tool", "name": name}),
+        };
+        let mut req = AnthropicIoRequest::new(stack, params, *self, false).as_json()?;
+        req["tools"] = tools.into();
+        req["tool_choice"] = tool_choice;
+        Ok(req)
+    }
+
+    fn into_tool_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        let mut json = self.serialize_tools(stack, params, functions, choice)?;
+        json["stream"] = true.into();
+        Ok(Box::new(
+            SseCompletionRequest::<AnthropicStreamResponse>::new(json),
+        ))
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        let content = response_json
+            .get("content")
+            .and_then(|c| c.as_array())
+            .ok_or(serde_json::Error::missing_field("content"))?;
+
+        let mut calls = vec![];
+        let mut text = vec![];
+        for block in content {
+            match block.get("type").and_then(|t| t.as_str()) {
+                Some("tool_use") => {
+                    let id = block
+                        .get("id")
+                        .and_then(|i| i.as_str())
+                        .ok_or(serde_json::Error::missing_field("id"))?
+                        .to_owned();
+                    let name = block
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .ok_or(serde_json::Error::missing_field("name"))?
+                        .to_owned();
+                    let arguments = block
+                        .get("input")
+                        .ok_or(serde_json::Error::missing_field("input"))?
+                        .to_owned();
+                    calls.push(ToolCall {
+                        id,
+                        name,
+                        arguments,
+                    });
+                }
+                Some("text") => {
+                    if let Some(t) = block.get("text").and_then(|t| t.as_str()) {
+                        text.push(t);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !calls.is_empty() {
+            tracing::info!("Tool calls: {:?}", calls);
+            return Ok(ToolCompletion::Calls(calls));
+        }
+        Ok(ToolCompletion::Message(text.join("")))
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        anthropic_response_metadata(response_json)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::agents::memory::OtherRoleTo;
+    use crate::agents::memory::{MessageImage, OtherRoleTo};
     #[test]
     fn anthropic_agent_cache_to_json() {
         let mut stack = MessageStack::new("SYSTEM");
@@ -119,4 +271,148 @@ mod tests {
             MessageStack::try_from(vals.as_array().unwrap().to_owned()).unwrap();
         assert_eq!(5, stack.len());
     }
+
+    #[test]
+    fn tools_response_processed_correctly() {
+        let model = AnthropicCompletionModel::default();
+        let call = json!({
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "stop_reason": "tool_use",
+            "content": [
+                {
+                    "type": "text",
+                    "text": "<thinking>I need to use get_current_weather</thinking>"
+                },
+                {
+                    "type": "tool_use",
+                    "id": "toolu_01",
+                    "name": "get_current_weather",
+                    "input": {"location": "Detroit, MI"}
+                }
+            ],
+            "usage": {"input_tokens": 10, "output_tokens": 20}
+        });
+        let expected = ToolCompletion::Calls(vec![ToolCall {
+            id: "toolu_01".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        }]);
+        assert_eq!(model.process_tools_response(call).unwrap(), expected);
+
+        let message = json!({
+            "content": [{"type": "text", "text": "It's sunny in Detroit"}],
+            "stop_reason": "end_turn",
+        });
+        let expected = ToolCompletion::Message("It's sunny in Detroit".to_owned());
+        assert_eq!(model.process_tools_response(message).unwrap(), expected);
+    }
+
+    #[test]
+    fn tools_serialized_with_input_schema() {
+        let function = Function::try_from(
+            r#"get_current_weather(location!: string)
+            where
+                i am 'get the current weather in a given location'
+            "#,
+        )
+        .unwrap();
+        let stack = MessageStack::new("SYSTEM");
+        let req = AnthropicCompletionModel::default()
+            .serialize_function(&stack, &ModelParameters::default(), function)
+            .unwrap();
+        assert_eq!(
+            req["tool_choice"],
+            json!({"type": "tool", "name": "get_current_weather"})
+        );
+        assert_eq!(req["tools"][0]["name"], json!("get_current_weather"));
+        assert_eq!(
+            req["tools"][0]["input_schema"]["required"],
+            json!(["location"])
+        );
+    }
+
+    #[test]
+    fn tool_messages_serialized_as_content_blocks() {
+        let call = ToolCall {
+            id: "toolu_01".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        };
+        let other_call = ToolCall {
+            id: "toolu_02".to_owned(),
+            ..call.clone()
+        };
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        stack.push(Message::new_tool_calls(
+            "Let me check",
+            vec![call.clone(), other_call.clone()],
+        ));
+        stack.push(Message::new_tool_result(&call, "sunny"));
+        stack.push(Message::new_tool_result(&other_call, "cloudy"));
+
+        let vals = AnthropicCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "user", "content": "What's the weather in Detroit?"},
+            {"role": "assistant", "content": [
+                {"type": "text", "text": "Let me check"},
+                {"type": "tool_use", "id": "toolu_01", "name": "get_current_weather", "input": {"location": "Detroit, MI"}},
+                {"type": "tool_use", "id": "toolu_02", "name": "get_current_weather", "input": {"location": "Detroit, MI"}},
+            ]},
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_01", "content": "sunny"},
+                {"type": "tool_result", "tool_use_id": "toolu_02", "content": "cloudy"},
+            ]},
+        ]);
+        assert_eq!(vals, expected);
+    }
+
+    #[test]
+    fn image_messages_serialized_as_base64_blocks() {
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_user_with_images(
+            "What is this image?",
+            vec![MessageImage::from_bytes(b"not really a png", "image/png")],
+        ));
+
+        let vals = AnthropicCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "user", "content": [
+                {"type": "image", "source": {
+                    "type": "base64",
+                    "media_type": "image/png",
+                    "data": "bm90IHJlYWxseSBhIHBuZw==",
+                }},
+                {"type": "text", "text": "What is this image?"},
+            ]},
+        ]);
+        assert_eq!(vals, expected);
+    }
+
+    #[test]
+    fn model_parameters_mapped_into_request() {
+        let mut params = ModelParameters {
+            temperature: Some(0.3),
+            top_k: Some(40),
+            stop: Some(vec!["Human:".to_owned()]),
+            frequency_penalty: Some(0.5),
+            ..Default::default()
+        };
+        params
+            .extra
+            .insert("metadata".to_owned(), json!({"user_id": "user-1234"}));
+        let stack = MessageStack::new("SYSTEM");
+        let req =
+            AnthropicIoRequest::new(&stack, &params, AnthropicCompletionModel::default(), false)
+                .as_json()
+                .unwrap();
+
+        assert_eq!(req["temperature"], json!(0.3f32));
+        assert_eq!(req["top_k"], json!(40));
+        assert_eq!(req["stop_sequences"], json!(["Human:"]));
+        assert_eq!(req["metadata"], json!({"user_id": "user-1234"}));
+        assert!(req.get("frequency_penalty").is_none());
+    }
 }
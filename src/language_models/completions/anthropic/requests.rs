@@ -8,25 +8,33 @@ use super::{
     streaming::AnthropicStreamResponse,
 };
 use crate::agents::memory::{MessageRole, MessageStack};
-use crate::language_models::completions::error::CompletionError;
 use crate::language_models::completions::inference::ProcessResponseReturn;
+use crate::language_models::completions::metadata::{
+    Completion, CompletionMetadata, FinishReason, TokenUsage,
+};
 use crate::language_models::completions::streaming::{
-    CompletionStream, ProviderStreamHandler, StreamedCompletionHandler,
+    sse::sse_json_stream, ProviderStreamHandler, StreamedCompletionHandler,
 };
-use futures::TryStreamExt;
-use reqwest_streams::JsonStreamResponse;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::time::Duration;
+use serde_json::{Map, Value};
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct AnthropicIoRequest {
     pub model: String,
     pub messages: Value,
-    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
     pub system: String,
     pub max_tokens: u32,
     pub stream: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl AnthropicIoRequest {
@@ -45,17 +53,17 @@ impl AnthropicIoRequest {
             .map(|m| m.content.as_str())
             .collect::<Vec<&str>>()
             .join(".");
-        let temperature = match params.temperature().ok() {
-            Some(t) => t,
-            None => 0.7,
-        };
         Self {
             model: typ.model_str().to_string(),
             messages: typ.serialize_messages(&sans_system_stack),
-            temperature,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            stop_sequences: params.stop.clone(),
             max_tokens: params.max_tokens.unwrap_or(1000),
             system,
             stream,
+            extra: params.extra.clone(),
         }
     }
 }
@@ -71,25 +79,18 @@ impl CompletionRequest for AnthropicIoRequest {
                 false => {
                     let json = response.json().await?;
                     tracing::warn!("got response:  {json:#?}");
-                    let response: AnthropicResponse = serde_json::from_value(json)?;
+                    let metadata = anthropic_response_metadata(&json);
+                    let response = AnthropicResponse::deserialize(&json)?;
                     match response {
                         AnthropicResponse::Success(mut suc) => {
                             let content = suc.content.remove(0).text;
-                            Ok(CompletionResponse::from(content))
+                            Ok(CompletionResponse::from(Completion::new(content, metadata)))
                         }
-                        AnthropicResponse::Err { error } => Err(error.into_error()),
+                        AnthropicResponse::Err { error } => Err(error.into_error(&json)),
                     }
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream = sse_json_stream(response.bytes_stream());
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<AnthropicStreamResponse>::from(response_stream)
                             .into();
@@ -100,6 +101,23 @@ impl CompletionRequest for AnthropicIoRequest {
     }
 }
 
+/// Read from the raw json because tool use responses don't fit `AnthropicSuccess`
+pub(super) fn anthropic_response_metadata(json: &Value) -> CompletionMetadata {
+    let str_field = |key: &str| json.get(key).and_then(|f| f.as_str()).map(String::from);
+    let usage = json
+        .get("usage")
+        .and_then(|u| serde_json::from_value::<AnthropicUsage>(u.to_owned()).ok())
+        .map(TokenUsage::from);
+    CompletionMetadata {
+        model: str_field("model"),
+        id: str_field("id"),
+        request_id: None,
+        usage,
+        finish_reason: str_field("stop_reason").as_deref().map(FinishReason::from),
+        fallback_index: None,
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum AnthropicResponse {
@@ -126,6 +144,14 @@ pub struct AnthropicResponseContent {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AnthropicUsage {
-    input_tokens: i32,
-    output_tokens: i32,
+    #[serde(default)]
+    pub input_tokens: u32,
+    #[serde(default)]
+    pub output_tokens: u32,
+}
+
+impl From<AnthropicUsage> for TokenUsage {
+    fn from(value: AnthropicUsage) -> Self {
+        TokenUsage::new(value.input_tokens, value.output_tokens)
+    }
 }
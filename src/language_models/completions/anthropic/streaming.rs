@@ -1,7 +1,52 @@
-use crate::language_models::completions::streaming::{CompletionStreamStatus, StreamResponse};
+use super::requests::AnthropicUsage;
+use crate::language_models::completions::{
+    metadata::{CompletionMetadata, FinishReason, TokenUsage},
+    streaming::{CompletionStreamStatus, StreamResponse, ToolCallChunk},
+};
 use serde::Deserialize;
 
-impl StreamResponse for AnthropicStreamResponse {}
+impl StreamResponse for AnthropicStreamResponse {
+    /// `message_start` carries the ids and input tokens, `message_delta` the stop reason and
+    /// output tokens
+    fn metadata(&self) -> Option<CompletionMetadata> {
+        match self {
+            Self::MessageStart { message } => Some(CompletionMetadata {
+                model: Some(message.model.to_owned()),
+                id: Some(message.id.to_owned()),
+                usage: Some(TokenUsage::from(message.usage.to_owned())),
+                ..Default::default()
+            }),
+            Self::MessageDelta { delta, usage } => Some(CompletionMetadata {
+                usage: Some(TokenUsage::new(0, usage.output_tokens)),
+                finish_reason: delta.stop_reason.as_deref().map(FinishReason::from),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Tool calls are content blocks of their own, identified by the block's index
+    fn tool_call_chunks(&self) -> Vec<ToolCallChunk> {
+        match self {
+            Self::ContentBlockStart {
+                index,
+                content_block: ContentBlock::ToolUse { id, name },
+            } => vec![ToolCallChunk::Start {
+                index: *index,
+                id: id.to_owned(),
+                name: name.to_owned(),
+            }],
+            Self::ContentBlockDelta {
+                index,
+                delta: Delta::InputJsonDelta { partial_json },
+            } if !partial_json.is_empty() => vec![ToolCallChunk::Arguments {
+                index: *index,
+                fragment: partial_json.to_owned(),
+            }],
+            _ => vec![],
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type")]
@@ -20,7 +65,10 @@ pub enum AnthropicStreamResponse {
     #[serde(rename = "content_block_stop")]
     ContentBlockStop { index: usize },
     #[serde(rename = "message_delta")]
-    MessageDelta { delta: MessageDelta, usage: Usage },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: AnthropicUsage,
+    },
     #[serde(rename = "message_stop")]
     MessageStop,
 }
@@ -35,14 +83,16 @@ struct Message {
     model: String,
     stop_reason: Option<String>,
     stop_sequence: Option<String>,
-    usage: Usage,
+    usage: AnthropicUsage,
 }
 
 #[derive(Debug, Deserialize, Clone)]
-struct ContentBlock {
-    #[serde(rename = "type")]
-    block_type: String,
-    text: String,
+#[serde(tag = "type")]
+enum ContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String },
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -50,12 +100,16 @@ struct ContentBlock {
 enum Delta {
     #[serde(rename = "text_delta")]
     TextDelta { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJsonDelta { partial_json: String },
 }
 
 impl Delta {
+    /// Tool input isn't part of the message's text
     fn inner_text(self) -> String {
         match self {
             Self::TextDelta { text } => text,
+            Self::InputJsonDelta { .. } => String::new(),
         }
     }
 }
@@ -66,16 +120,11 @@ struct MessageDelta {
     stop_sequence: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct Usage {
-    input_tokens: u32,
-    output_tokens: u32,
-}
-
 impl Into<CompletionStreamStatus> for AnthropicStreamResponse {
     fn into(self) -> CompletionStreamStatus {
         match self {
-            Self::MessageStop | Self::ContentBlockStop { .. } => CompletionStreamStatus::Finished,
+            // Usage and the stop reason come after the content block stops
+            Self::MessageStop => CompletionStreamStatus::Finished,
             Self::ContentBlockDelta { delta, .. } => {
                 return CompletionStreamStatus::Working(delta.inner_text());
             }
@@ -83,3 +132,82 @@ impl Into<CompletionStreamStatus> for AnthropicStreamResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stream_events_carry_metadata() {
+        let events = vec![
+            json!({
+                "type": "message_start",
+                "message": {
+                    "id": "msg_01",
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [],
+                    "model": "claude-3-haiku-20240307",
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": {"input_tokens": 25, "output_tokens": 1}
+                }
+            }),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hello"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({
+                "type": "message_delta",
+                "delta": {"stop_reason": "end_turn", "stop_sequence": null},
+                "usage": {"output_tokens": 15}
+            }),
+            json!({"type": "message_stop"}),
+        ];
+        let mut metadata = CompletionMetadata::default();
+        let mut finished_at = None;
+        for (i, event) in events.into_iter().enumerate() {
+            let event: AnthropicStreamResponse = serde_json::from_value(event).unwrap();
+            if let Some(m) = event.metadata() {
+                metadata.merge(m);
+            }
+            if let CompletionStreamStatus::Finished = event.into() {
+                finished_at.get_or_insert(i);
+            }
+        }
+        assert_eq!(finished_at, Some(5));
+        assert_eq!(metadata.id.as_deref(), Some("msg_01"));
+        assert_eq!(metadata.usage, Some(TokenUsage::new(25, 15)));
+        assert_eq!(metadata.finish_reason, Some(FinishReason::Stop));
+    }
+
+    #[test]
+    fn tool_use_blocks_become_tool_call_chunks() {
+        let events = vec![
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "toolu_01", "name": "get_weather", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": ""}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "{\"location\": \"Par"}}),
+        ];
+        let chunks: Vec<_> = events
+            .into_iter()
+            .flat_map(|e| {
+                let event: AnthropicStreamResponse = serde_json::from_value(e).unwrap();
+                event.tool_call_chunks()
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                ToolCallChunk::Start {
+                    index: 1,
+                    id: "toolu_01".to_owned(),
+                    name: "get_weather".to_owned()
+                },
+                ToolCallChunk::Arguments {
+                    index: 1,
+                    fragment: "{\"location\": \"Par".to_owned()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+type Judge = Box<dyn Fn(&[String]) -> usize + Send + Sync>;
+
+/// How `Agent::self_consistent_completion` picks one answer out of its samples
+pub enum Vote {
+    /// Most common answer, compared with surrounding whitespace trimmed
+    Majority,
+    /// Most common answer once each is reduced to a key, e.g. the number a chain of thought ends
+    /// with, so that answers reasoned differently still agree
+    MajorityBy(Box<dyn Fn(&str) -> String + Send + Sync>),
+    /// Answer at whichever index the judge returns
+    Judge(Judge),
+}
+
+impl Debug for Vote {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Majority => write!(f, "Majority"),
+            Self::MajorityBy(_) => write!(f, "MajorityBy(..)"),
+            Self::Judge(_) => write!(f, "Judge(..)"),
+        }
+    }
+}
+
+/// Answer picked from a set of samples
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Consensus {
+    /// Winning sample, as the model wrote it
+    pub answer: String,
+    /// How many samples agree with `answer`, itself included
+    pub votes: usize,
+    /// Every sample, in the order they were returned
+    pub samples: Vec<String>,
+}
+
+impl Vote {
+    pub fn majority_by(key: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Self::MajorityBy(Box::new(key))
+    }
+
+    pub fn judge(judge: impl Fn(&[String]) -> usize + Send + Sync + 'static) -> Self {
+        Self::Judge(Box::new(judge))
+    }
+
+    fn key(&self, sample: &str) -> String {
+        match self {
+            Self::MajorityBy(key) => key(sample),
+            Self::Majority | Self::Judge(_) => sample.trim().to_string(),
+        }
+    }
+
+    /// Picks an answer out of `samples`. Ties go to whichever answer was sampled first. `None` if
+    /// there are no samples or the judge picked an index out of range
+    pub fn decide(&self, samples: Vec<String>) -> Option<Consensus> {
+        let keys: Vec<String> = samples.iter().map(|s| self.key(s)).collect();
+        let votes_for = |index: usize| keys.iter().filter(|k| **k == keys[index]).count();
+        let winner = match self {
+            Self::Judge(judge) => Some(judge(&samples)).filter(|i| *i < samples.len())?,
+            Self::Majority | Self::MajorityBy(_) => {
+                // `max_by_key` keeps the last of equal maximums, so walk the samples backwards
+                (0..samples.len()).rev().max_by_key(|i| votes_for(*i))?
+            }
+        };
+        Some(Consensus {
+            answer: samples[winner].clone(),
+            votes: votes_for(winner),
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(answers: &[&str]) -> Vec<String> {
+        answers.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn majority_picks_most_common_answer() {
+        let consensus = Vote::Majority
+            .decide(samples(&["4", " 5\n", "5", "4 ", "5"]))
+            .unwrap();
+        assert_eq!(consensus.answer, " 5\n");
+        assert_eq!(consensus.votes, 3);
+        assert_eq!(consensus.samples.len(), 5);
+
+        let tie = Vote::Majority
+            .decide(samples(&["a", "b", "b", "a"]))
+            .unwrap();
+        assert_eq!(tie.answer, "a");
+        assert!(Vote::Majority.decide(vec![]).is_none());
+    }
+
+    #[test]
+    fn majority_by_compares_keys() {
+        let last_word = Vote::majority_by(|s| s.split_whitespace().last().unwrap_or("").into());
+        let consensus = last_word
+            .decide(samples(&[
+                "2 + 2 is 4",
+                "two and two make 5",
+                "adding them gives 4",
+            ]))
+            .unwrap();
+        assert_eq!(consensus.answer, "2 + 2 is 4");
+        assert_eq!(consensus.votes, 2);
+    }
+
+    #[test]
+    fn judge_picks_answer() {
+        let longest = Vote::judge(|samples| {
+            (0..samples.len())
+                .max_by_key(|i| samples[*i].len())
+                .unwrap_or_default()
+        });
+        let consensus = longest.decide(samples(&["a", "abc", "ab"])).unwrap();
+        assert_eq!(consensus.answer, "abc");
+        assert_eq!(consensus.votes, 1);
+
+        let out_of_range = Vote::judge(|samples| samples.len());
+        assert!(out_of_range.decide(samples(&["a"])).is_none());
+    }
+}
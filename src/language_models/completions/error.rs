@@ -1,7 +1,13 @@
 use tracing::warn;
 
+use super::streaming::StreamError;
 use crate::errors::error_chain_fmt;
-use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use reqwest::{header::HeaderMap, Response, StatusCode};
+use serde_json::Value;
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
 
 pub type CompletionResult<T> = Result<T, CompletionError>;
 
@@ -11,16 +17,134 @@ pub enum CompletionError {
     Undefined(#[from] anyhow::Error),
     Json(#[from] serde_json::Error),
     Request(#[from] reqwest::Error),
+    /// Stream failed before its first token
+    Stream(#[from] StreamError),
+    /// Provider returned an error this crate doesn't recognize, holds the raw body
     Provider(String),
+    /// Too many requests or tokens. `retry_after` is how long the provider asked to wait, if it said
+    RateLimited {
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    /// Api key is missing, invalid or not allowed to use the model
+    Authentication {
+        body: String,
+    },
+    /// Prompt plus `max_tokens` don't fit in the model's context window
+    ContextLengthExceeded {
+        body: String,
+    },
+    /// Provider rejected the request as malformed
+    InvalidRequest {
+        body: String,
+    },
+    /// Provider is temporarily out of capacity
+    Overloaded {
+        body: String,
+    },
+    /// Provider failed with a 5xx status
+    Server {
+        status: u16,
+        body: String,
+    },
     FunctionNotImplemented,
     StreamTimeout,
     CouldNotCoerce,
 }
 
+const CONTEXT_LENGTH_HINTS: [&str; 5] = [
+    "context_length_exceeded",
+    "maximum context length",
+    "prompt is too long",
+    "exceeds the maximum number of tokens",
+    "context window",
+];
+
+impl CompletionError {
+    /// Classify an error response by its HTTP status, falling back to the error type named in the
+    /// body when there is no status, like an error sent in the middle of a stream
+    pub fn from_provider(
+        status: Option<StatusCode>,
+        retry_after: Option<Duration>,
+        body: String,
+    ) -> Self {
+        let lower = body.to_lowercase();
+        let mentions = |hints: &[&str]| hints.iter().any(|h| lower.contains(h));
+        let is_context_length = mentions(&CONTEXT_LENGTH_HINTS);
+        match status.map(|s| s.as_u16()) {
+            Some(401 | 403) => Self::Authentication { body },
+            Some(429) => Self::RateLimited { retry_after, body },
+            Some(503 | 529) => Self::Overloaded { body },
+            Some(status) if status >= 500 => Self::Server { status, body },
+            Some(_) if is_context_length => Self::ContextLengthExceeded { body },
+            Some(_) => Self::InvalidRequest { body },
+            None if is_context_length => Self::ContextLengthExceeded { body },
+            None if mentions(&["rate_limit", "resource_exhausted"]) => {
+                Self::RateLimited { retry_after, body }
+            }
+            None if mentions(&["overloaded", "unavailable"]) => Self::Overloaded { body },
+            None if mentions(&["authentication", "invalid_api_key", "permission"]) => {
+                Self::Authentication { body }
+            }
+            None if mentions(&["invalid_request", "invalid_argument"]) => {
+                Self::InvalidRequest { body }
+            }
+            None => Self::Provider(body),
+        }
+    }
+
+    /// Raw body of the provider's error response
+    pub fn body(&self) -> Option<&str> {
+        match self {
+            Self::Provider(body)
+            | Self::RateLimited { body, .. }
+            | Self::Authentication { body }
+            | Self::ContextLengthExceeded { body }
+            | Self::InvalidRequest { body }
+            | Self::Overloaded { body }
+            | Self::Server { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+}
+
+/// How long the provider asked us to wait before retrying, from either `retry-after-ms` or
+/// `retry-after` given in seconds
+pub(crate) fn retry_after_from_headers(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+    header("retry-after-ms")
+        .map(|ms| ms / 1000.0)
+        .or_else(|| header("retry-after"))
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Passes successful responses through, everything else is read and turned into a typed error
+pub(crate) async fn error_for_status(response: Response) -> CompletionResult<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = retry_after_from_headers(response.headers());
+    let body = response.text().await?;
+    warn!("Provider responded with {}: {}", status, body);
+    Err(CompletionError::from_provider(
+        Some(status),
+        retry_after,
+        body,
+    ))
+}
+
+/// Error object a provider sent in place of a completion
 pub trait ProviderResponseError: Debug {
-    fn into_error(&self) -> CompletionError {
+    /// HTTP status the provider reports alongside the error, for providers that repeat it in the body
+    fn status(&self) -> Option<StatusCode> {
+        None
+    }
+    /// `body` is the raw response the error was parsed from
+    fn into_error(&self, body: &Value) -> CompletionError {
         warn!("Coercing to completion error: {:?}", self);
-        CompletionError::Provider(format!("Provider error: {:?}", self))
+        CompletionError::from_provider(self.status(), None, body.to_string())
     }
 }
 impl Debug for CompletionError {
@@ -35,11 +159,95 @@ impl Display for CompletionError {
             Self::Json(err) => err.to_string(),
             Self::Undefined(err) => err.to_string(),
             Self::Request(err) => err.to_string(),
+            Self::Stream(err) => err.to_string(),
             Self::StreamTimeout => "Stream Timeout".to_string(),
-            Self::Provider(err) => err.to_string(),
+            Self::Provider(body) => format!("Provider error: {}", body),
+            Self::RateLimited { retry_after, body } => match retry_after {
+                Some(wait) => format!("Rate limited, retry after {:?}: {}", wait, body),
+                None => format!("Rate limited: {}", body),
+            },
+            Self::Authentication { body } => format!("Authentication failed: {}", body),
+            Self::ContextLengthExceeded { body } => {
+                format!("Context length exceeded: {}", body)
+            }
+            Self::InvalidRequest { body } => format!("Invalid request: {}", body),
+            Self::Overloaded { body } => format!("Provider overloaded: {}", body),
+            Self::Server { status, body } => format!("Provider server error {}: {}", status, body),
             Self::CouldNotCoerce => "Could Not Coerce".to_string(),
             Self::FunctionNotImplemented => "Function Not Implemented".to_string(),
         };
         write!(f, "{}", display)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_classified_by_status_and_body() {
+        let classify = |status: Option<u16>, body: &str| {
+            CompletionError::from_provider(
+                status.map(|s| StatusCode::from_u16(s).unwrap()),
+                None,
+                body.to_owned(),
+            )
+        };
+        assert!(matches!(
+            classify(Some(401), r#"{"error":{"type":"authentication_error"}}"#),
+            CompletionError::Authentication { .. }
+        ));
+        assert!(matches!(
+            classify(Some(429), "{}"),
+            CompletionError::RateLimited { .. }
+        ));
+        assert!(matches!(
+            classify(
+                Some(529),
+                r#"{"type":"error","error":{"type":"overloaded_error"}}"#
+            ),
+            CompletionError::Overloaded { .. }
+        ));
+        assert!(matches!(
+            classify(Some(502), "Bad Gateway"),
+            CompletionError::Server { status: 502, .. }
+        ));
+        assert!(matches!(
+            classify(
+                Some(400),
+                r#"{"error":{"code":"context_length_exceeded","message":"This model's maximum context length is 16385 tokens"}}"#
+            ),
+            CompletionError::ContextLengthExceeded { .. }
+        ));
+        assert!(matches!(
+            classify(Some(400), r#"{"error":{"type":"invalid_request_error"}}"#),
+            CompletionError::InvalidRequest { .. }
+        ));
+        assert!(matches!(
+            classify(
+                None,
+                r#"{"type":"error","error":{"type":"overloaded_error"}}"#
+            ),
+            CompletionError::Overloaded { .. }
+        ));
+        let err = classify(None, r#"{"error":"model 'llama9' not found"}"#);
+        assert_eq!(err.body(), Some(r#"{"error":"model 'llama9' not found"}"#));
+        assert!(matches!(err, CompletionError::Provider(_)));
+    }
+
+    #[test]
+    fn retry_after_read_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after_from_headers(&headers), None);
+        headers.insert("retry-after", "20".parse().unwrap());
+        assert_eq!(
+            retry_after_from_headers(&headers),
+            Some(Duration::from_secs(20))
+        );
+        headers.insert("retry-after-ms", "1500".parse().unwrap());
+        assert_eq!(
+            retry_after_from_headers(&headers),
+            Some(Duration::from_millis(1500))
+        );
+    }
+}
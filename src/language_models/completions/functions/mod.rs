@@ -4,6 +4,8 @@ use self::{
     parser::Parser,
 };
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 use tracing_log::log::info;
 
@@ -12,8 +14,13 @@ mod lexer;
 mod parser;
 mod tests;
 mod tokens;
+pub mod typed;
 
-#[derive(Debug, PartialEq, Eq)]
+#[cfg(feature = "derive")]
+pub use espionox_derive::EspxFunction;
+pub use typed::{FromArgument, TypedFunction};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Function {
     pub name: String,
     pub description: String,
@@ -32,7 +39,46 @@ impl TryFrom<&str> for Function {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Function {
+    /// JSON schema of the function's parameters, shared by every provider which supports functions
+    pub(crate) fn params_json_schema(&self) -> Value {
+        let mut all_params = Map::new();
+        let mut req = vec![];
+        for (name, param) in self.params.iter() {
+            let mut current_param = Map::new();
+            match &param.typ {
+                ParamType::String => {
+                    current_param.insert("type".to_owned(), "string".to_owned().into());
+                }
+                ParamType::Bool => {
+                    current_param.insert("type".to_owned(), "boolean".to_owned().into());
+                }
+                ParamType::Integer => {
+                    current_param.insert("type".to_owned(), "integer".to_owned().into());
+                }
+                ParamType::Enum(variants) => {
+                    current_param.insert("type".to_owned(), "string".to_owned().into());
+                    current_param.insert("enum".to_owned(), json!(variants));
+                }
+            }
+
+            if let Some(desc) = &param.description {
+                current_param.insert("description".to_owned(), desc.to_owned().into());
+            }
+            all_params.insert(name.to_owned(), json!(current_param));
+            if param.required {
+                req.push(name);
+            }
+        }
+        json!({
+            "type": "object",
+            "properties": all_params,
+            "required": json!(req),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FunctionParam {
     pub description: Option<String>,
     pub typ: ParamType,
@@ -70,7 +116,7 @@ impl FunctionParam {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParamType {
     String,
     Integer,
@@ -89,3 +135,33 @@ impl TryFrom<&str> for ParamType {
         }
     }
 }
+
+/// Controls whether & how a model may call the functions it is given
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ToolChoice {
+    /// Model may answer normally or call any number of functions
+    #[default]
+    Auto,
+    /// Model is given the functions, but may not call any
+    None,
+    /// Model must call at least one function
+    Required,
+    /// Model must call the function with the given name
+    Function(String),
+}
+
+/// A call a model made to one of the functions it was given. `id` is assigned by the provider and
+/// is needed to match a call to its result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// Response to a completion in which the model was free to either answer or call a function
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolCompletion {
+    Message(String),
+    Calls(Vec<ToolCall>),
+}
@@ -74,3 +74,42 @@ fn parses_to_function_correctly() {
         }
     }
 }
+
+#[test]
+fn correctly_serialize_params() {
+    Lazy::force(&TRACING);
+    let function = all_test_cases().remove(0).expected_function;
+    let expected = serde_json::json!({
+            "type": "object",
+            "properties": {
+              "location": {
+                "type": "string",
+                "description": "the city and state, e.g. san francisco, ca"
+              },
+                "num_days": {
+                "type": "integer",
+                "description": "the number of days to forcast",
+                },
+              "format": {
+                "type": "string",
+                "enum": ["celcius", "farenheight"],
+                "description": "the temperature unit to use. infer this from the users location."
+              }
+            },
+            "required": ["num_days", "format"]}
+    );
+
+    let serialized = function.params_json_schema();
+
+    for (k, v) in expected["properties"].as_object().unwrap().into_iter() {
+        assert_eq!(v, &serialized["properties"][k])
+    }
+    for r in expected["required"].as_array().unwrap() {
+        assert!(serialized["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| *v == r)
+            .is_some())
+    }
+}
@@ -0,0 +1,154 @@
+use super::{Function, ParamType};
+use serde::de::{Error as _, Unexpected};
+use serde_json::{Error, Value};
+
+/// Type a model can be asked to fill in with `Agent::typed_completion`. Its fields are described
+/// by the parameters of `function` and read back out of the model's arguments by `from_arguments`.
+/// `#[derive(EspxFunction)]` implements both, types which already derive `Deserialize` can
+/// implement `from_arguments` with `serde_json::from_value`
+pub trait TypedFunction: Sized {
+    fn function() -> Function;
+    fn from_arguments(arguments: Value) -> Result<Self, Error>;
+}
+
+/// Type a single argument of a function can be read as. Fields of a struct deriving
+/// `EspxFunction` must implement it, enums deriving `EspxFunction` do
+pub trait FromArgument: Sized {
+    /// Whether the model has to give the argument, only `Option`s don't
+    const REQUIRED: bool = true;
+    fn param_type() -> ParamType;
+    fn from_argument(value: &Value) -> Result<Self, Error>;
+}
+
+/// Reads the argument `name` out of the object of `arguments`, naming it in any error
+pub fn argument<T: FromArgument>(arguments: &Value, name: &str) -> Result<T, Error> {
+    if !arguments.is_object() {
+        return Err(Error::invalid_type(
+            unexpected(arguments),
+            &"an object of arguments",
+        ));
+    }
+    let value = arguments.get(name).unwrap_or(&Value::Null);
+    if value.is_null() && T::REQUIRED {
+        return Err(Error::custom(format!("missing field `{}`", name)));
+    }
+    T::from_argument(value).map_err(|err| Error::custom(format!("field `{}`: {}", name, err)))
+}
+
+/// Error for an argument which isn't one of an enum's `variants`
+pub fn unknown_variant(value: &Value, variants: &'static [&'static str]) -> Error {
+    match value.as_str() {
+        Some(variant) => Error::unknown_variant(variant, variants),
+        None => Error::invalid_type(unexpected(value), &"a string"),
+    }
+}
+
+fn unexpected(value: &Value) -> Unexpected<'_> {
+    match value {
+        Value::Null => Unexpected::Unit,
+        Value::Bool(b) => Unexpected::Bool(*b),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Unexpected::Signed(i),
+            (None, Some(f)) => Unexpected::Float(f),
+            (None, None) => Unexpected::Other("number"),
+        },
+        Value::String(s) => Unexpected::Str(s),
+        Value::Array(_) => Unexpected::Seq,
+        Value::Object(_) => Unexpected::Map,
+    }
+}
+
+impl FromArgument for String {
+    fn param_type() -> ParamType {
+        ParamType::String
+    }
+    fn from_argument(value: &Value) -> Result<Self, Error> {
+        value
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| Error::invalid_type(unexpected(value), &"a string"))
+    }
+}
+
+impl FromArgument for bool {
+    fn param_type() -> ParamType {
+        ParamType::Bool
+    }
+    fn from_argument(value: &Value) -> Result<Self, Error> {
+        value
+            .as_bool()
+            .ok_or_else(|| Error::invalid_type(unexpected(value), &"a boolean"))
+    }
+}
+
+macro_rules! integer_argument {
+    ($as_int:ident => $($int:ty),*) => {
+        $(
+            impl FromArgument for $int {
+                fn param_type() -> ParamType {
+                    ParamType::Integer
+                }
+                fn from_argument(value: &Value) -> Result<Self, Error> {
+                    let int = value
+                        .$as_int()
+                        .ok_or_else(|| Error::invalid_type(unexpected(value), &"an integer"))?;
+                    <$int>::try_from(int).map_err(|_| {
+                        Error::invalid_value(unexpected(value), &stringify!($int))
+                    })
+                }
+            }
+        )*
+    };
+}
+
+integer_argument!(as_i64 => i8, i16, i32, i64, isize);
+integer_argument!(as_u64 => u8, u16, u32, u64, usize);
+
+impl<T: FromArgument> FromArgument for Option<T> {
+    const REQUIRED: bool = false;
+    fn param_type() -> ParamType {
+        T::param_type()
+    }
+    fn from_argument(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_argument(value).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn arguments_read_by_type() {
+        let args = json!({"name": "Ann", "age": 30, "admin": false, "nickname": null});
+        assert_eq!(argument::<String>(&args, "name").unwrap(), "Ann");
+        assert_eq!(argument::<u8>(&args, "age").unwrap(), 30);
+        assert!(!argument::<bool>(&args, "admin").unwrap());
+        assert_eq!(argument::<Option<String>>(&args, "nickname").unwrap(), None);
+        assert_eq!(argument::<Option<String>>(&args, "email").unwrap(), None);
+        assert_eq!(argument::<Option<i32>>(&args, "age").unwrap(), Some(30));
+    }
+
+    #[test]
+    fn invalid_arguments_named_in_errors() {
+        let args = json!({"name": 7, "age": -1});
+        let err = argument::<String>(&args, "name").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "field `name`: invalid type: integer `7`, expected a string"
+        );
+        let err = argument::<u32>(&args, "age").unwrap_err().to_string();
+        assert!(err.starts_with("field `age`"), "{}", err);
+        let err = argument::<String>(&args, "email").unwrap_err().to_string();
+        assert_eq!(err, "missing field `email`");
+        assert!(argument::<String>(&json!("Ann"), "name").is_err());
+        assert_eq!(
+            unknown_variant(&json!("kelvin"), &["celsius", "fahrenheit"]).to_string(),
+            "unknown variant `kelvin`, expected `celsius` or `fahrenheit`"
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use super::requests::{GeminiIoRequest, GeminiResponse};
+use crate::agents::memory::{ImageSource, Message, MessageRole, MessageStack};
+use crate::language_models::completions::{
+    error::{CompletionResult, ProviderResponseError},
+    functions::{Function, ToolCall, ToolChoice, ToolCompletion},
+    inference::{CompletionRequest, CompletionRequestBuilder},
+    metadata::CompletionMetadata,
+    ModelParameters,
+};
+use reqwest::header::HeaderMap;
+use serde::{de::Error, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum GeminiCompletionModel {
+    #[default]
+    Flash,
+    Pro,
+}
+
+const FLASH_MODEL_STR: &str = "gemini-1.5-flash";
+const PRO_MODEL_STR: &str = "gemini-1.5-pro";
+
+const GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+
+impl GeminiCompletionModel {
+    /// Gemini role a message is sent as, along with its parts. Gemini only knows `user` and
+    /// `model`, tool results are sent back as `functionResponse` parts from the user
+    fn message_parts(message: &Message) -> (&'static str, Vec<Value>) {
+        if let MessageRole::Tool { name, .. } = &message.role {
+            let part = json!({
+                "functionResponse": {
+                    "name": name,
+                    "response": {"content": message.content},
+                }
+            });
+            return ("user", vec![part]);
+        }
+        let mut parts = vec![];
+        for image in message.images.iter() {
+            let part = match &image.source {
+                ImageSource::Base64(data) => json!({
+                    "inline_data": {"mime_type": image.media_type, "data": data}
+                }),
+                ImageSource::Url(url) => json!({
+                    "file_data": {"mime_type": image.media_type, "file_uri": url}
+                }),
+            };
+            parts.push(part);
+        }
+        if !message.content.is_empty() {
+            parts.push(json!({"text": message.content}));
+        }
+        for call in message.tool_calls.iter() {
+            parts.push(json!({
+                "functionCall": {"name": call.name, "args": call.arguments}
+            }));
+        }
+        let role = match message.role.actual() {
+            MessageRole::Assistant => "model",
+            _ => "user",
+        };
+        (role, parts)
+    }
+}
+
+impl CompletionRequestBuilder for GeminiCompletionModel {
+    fn model_str(&self) -> &str {
+        match self {
+            Self::Flash => FLASH_MODEL_STR,
+            Self::Pro => PRO_MODEL_STR,
+        }
+    }
+
+    fn url_str(&self) -> String {
+        format!("{}/{}:generateContent", GEMINI_BASE_URL, self.model_str())
+    }
+
+    fn stream_url_str(&self) -> String {
+        format!(
+            "{}/{}:streamGenerateContent",
+            GEMINI_BASE_URL,
+            self.model_str()
+        )
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("x-goog-api-key", api_key.parse().unwrap());
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        // Like Anthropic, Gemini requires that turns alternate between user and model, so
+        // adjacent messages from the same role are concatenated into one
+        let mut val_vec: Vec<Value> = vec![];
+        let mut last_message: Option<(&str, Vec<Value>)> = None;
+        for message in stack.as_ref().iter() {
+            let (role, parts) = Self::message_parts(message);
+            match last_message.as_mut() {
+                Some((last_role, last_parts)) if *last_role == role => {
+                    last_parts.extend(parts);
+                }
+                _ => {
+                    if let Some((role, parts)) = last_message.replace((role, parts)) {
+                        val_vec.push(json!({"role": role, "parts": parts}));
+                    }
+                }
+            }
+        }
+        if let Some((role, parts)) = last_message {
+            val_vec.push(json!({"role": role, "parts": parts}));
+        }
+        val_vec.into()
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(GeminiIoRequest::new(stack, params, *self, false)))
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(GeminiIoRequest::new(stack, params, *self, true)))
+    }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        let declarations: Vec<Value> = functions
+            .iter()
+            .map(|f| {
+                let mut declaration = json!({"name": f.name, "description": f.description});
+                // Gemini rejects an object schema without properties
+                if !f.params.is_empty() {
+                    declaration["parameters"] = f.params_json_schema();
+                }
+                declaration
+            })
+            .collect();
+        let config = match choice {
+            ToolChoice::Auto => json!({"mode": "AUTO"}),
+            ToolChoice::None => json!({"mode": "NONE"}),
+            ToolChoice::Required => json!({"mode": "ANY"}),
+            ToolChoice::Function(name) => json!({"mode": "ANY", "allowedFunctionNames": [name]}),
+        };
+        let mut req = GeminiIoRequest::new(stack, params, *self, false).as_json()?;
+        req["tools"] = json!([{ "functionDeclarations": declarations }]);
+        req["toolConfig"] = json!({ "functionCallingConfig": config });
+        Ok(req)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        let success = match GeminiResponse::deserialize(&response_json)? {
+            GeminiResponse::Success(suc) => suc,
+            GeminiResponse::Err { error } => return Err(error.into_error(&response_json)),
+        };
+        let parts = success
+            .candidates
+            .first()
+            .and_then(|c| c.content.as_ref())
+            .map(|c| c.parts.to_owned())
+            .unwrap_or_default();
+
+        let mut calls = vec![];
+        for part in parts.iter() {
+            if let Some(call) = part.get("functionCall") {
+                let name = call
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .ok_or(serde_json::Error::missing_field("name"))?
+                    .to_owned();
+                let arguments = call.get("args").cloned().unwrap_or(json!({}));
+                // Gemini doesn't give function calls ids
+                calls.push(ToolCall {
+                    id: format!("call_{}", uuid::Uuid::new_v4()),
+                    name,
+                    arguments,
+                });
+            }
+        }
+
+        if !calls.is_empty() {
+            tracing::info!("Tool calls: {:?}", calls);
+            return Ok(ToolCompletion::Calls(calls));
+        }
+        Ok(ToolCompletion::Message(success.text()))
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        match serde_json::from_value::<GeminiResponse>(response_json.to_owned()) {
+            Ok(GeminiResponse::Success(suc)) => suc.metadata(),
+            _ => CompletionMetadata::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::memory::OtherRoleTo;
+
+    #[test]
+    fn messages_alternate_between_user_and_model() {
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_user("USER"));
+        stack.push(Message::new_user("USER1"));
+        stack.push(Message::new_assistant("ASS"));
+        stack.push(Message::new_other(
+            "some_other",
+            "ASS1",
+            OtherRoleTo::Assistant,
+        ));
+        stack.push(Message::new_user("USER2"));
+
+        let vals = GeminiCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "user", "parts": [{"text": "USER"}, {"text": "USER1"}]},
+            {"role": "model", "parts": [{"text": "ASS"}, {"text": "ASS1"}]},
+            {"role": "user", "parts": [{"text": "USER2"}]},
+        ]);
+        assert_eq!(vals, expected);
+    }
+
+    #[test]
+    fn system_prompt_sent_as_system_instruction() {
+        let mut stack = MessageStack::new("SYSTEM");
+        stack.push(Message::new_user("USER"));
+        let req = GeminiIoRequest::new(
+            &stack,
+            &ModelParameters::default(),
+            GeminiCompletionModel::default(),
+            false,
+        )
+        .as_json()
+        .unwrap();
+        assert_eq!(
+            req["systemInstruction"],
+            json!({"parts": [{"text": "SYSTEM"}]})
+        );
+        assert_eq!(
+            req["contents"],
+            json!([{"role": "user", "parts": [{"text": "USER"}]}])
+        );
+    }
+
+    #[test]
+    fn tool_messages_serialized_as_function_parts() {
+        let call = ToolCall {
+            id: "call_abc".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        };
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        stack.push(Message::new_tool_calls("", vec![call.clone()]));
+        stack.push(Message::new_tool_result(&call, "sunny"));
+
+        let vals = GeminiCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "user", "parts": [{"text": "What's the weather in Detroit?"}]},
+            {"role": "model", "parts": [{
+                "functionCall": {"name": "get_current_weather", "args": {"location": "Detroit, MI"}}
+            }]},
+            {"role": "user", "parts": [{
+                "functionResponse": {"name": "get_current_weather", "response": {"content": "sunny"}}
+            }]},
+        ]);
+        assert_eq!(vals, expected);
+    }
+
+    #[test]
+    fn parameterless_function_declared_without_parameters() {
+        let functions = [
+            Function {
+                name: "get_time".to_owned(),
+                description: "Current time".to_owned(),
+                params: Default::default(),
+            },
+            Function::try_from(
+                r#"get_weather(location!: string)
+                i = 'Current weather'
+                "#,
+            )
+            .unwrap(),
+        ];
+        let req = GeminiCompletionModel::default()
+            .serialize_tools(
+                &MessageStack::init(),
+                &ModelParameters::default(),
+                &functions,
+                &ToolChoice::Auto,
+            )
+            .unwrap();
+
+        let declarations = &req["tools"][0]["functionDeclarations"];
+        assert_eq!(
+            declarations[0],
+            json!({"name": "get_time", "description": "Current time"})
+        );
+        assert_eq!(
+            declarations[1]["parameters"],
+            functions[1].params_json_schema()
+        );
+    }
+
+    #[test]
+    fn tools_response_processed_correctly() {
+        let model = GeminiCompletionModel::default();
+        let response = json!({
+            "candidates": [{
+                "content": {
+                    "parts": [{
+                        "functionCall": {
+                            "name": "get_current_weather",
+                            "args": {"location": "Detroit, MI"}
+                        }
+                    }],
+                    "role": "model"
+                },
+                "finishReason": "STOP"
+            }]
+        });
+        match model.process_tools_response(response).unwrap() {
+            ToolCompletion::Calls(calls) => {
+                assert_eq!(calls.len(), 1);
+                assert_eq!(calls[0].name, "get_current_weather");
+                assert_eq!(calls[0].arguments, json!({"location": "Detroit, MI"}));
+            }
+            ToolCompletion::Message(m) => panic!("Expected tool calls, got message: {m}"),
+        }
+    }
+}
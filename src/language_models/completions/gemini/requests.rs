@@ -0,0 +1,352 @@
+use super::{builder::GeminiCompletionModel, streaming::GeminiStreamResponse};
+use crate::{
+    agents::memory::{MessageRole, MessageStack},
+    language_models::completions::{
+        error::{CompletionResult, ProviderResponseError},
+        inference::{
+            CompletionRequest, CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn,
+        },
+        metadata::{Completion, CompletionMetadata, FinishReason, TokenUsage},
+        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
+        ModelParameters, ResponseFormat,
+    },
+};
+use futures::TryStreamExt;
+use reqwest::{Response, StatusCode};
+use reqwest_streams::JsonStreamResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiIoRequest {
+    pub contents: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Value>,
+    pub generation_config: GeminiGenerationConfig,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+    /// Gemini decides whether to stream by endpoint rather than by a field in the body
+    #[serde(skip)]
+    pub stream: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    // No `candidateCount`, each of `n` choices is its own request as only the first candidate of
+    // a response is read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+}
+
+impl GeminiIoRequest {
+    pub fn new(
+        stack: &MessageStack,
+        params: &ModelParameters,
+        typ: GeminiCompletionModel,
+        stream: bool,
+    ) -> Self {
+        let system_stack: MessageStack = stack.ref_filter_by(&MessageRole::System, true).into();
+        let sans_system_stack: MessageStack =
+            stack.ref_filter_by(&MessageRole::System, false).into();
+        let system_parts: Vec<Value> = system_stack
+            .as_ref()
+            .iter()
+            .map(|m| json!({"text": m.content}))
+            .collect();
+        let system_instruction = match system_parts.is_empty() {
+            true => None,
+            false => Some(json!({ "parts": system_parts })),
+        };
+        let response_mime_type = match &params.response_format {
+            Some(ResponseFormat::JsonObject) | Some(ResponseFormat::JsonSchema { .. }) => {
+                Some("application/json".to_owned())
+            }
+            Some(ResponseFormat::Text) | None => None,
+        };
+        Self {
+            contents: typ.serialize_messages(&sans_system_stack),
+            system_instruction,
+            generation_config: GeminiGenerationConfig {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                top_k: params.top_k,
+                frequency_penalty: params.frequency_penalty,
+                presence_penalty: params.presence_penalty,
+                max_output_tokens: params.max_tokens,
+                stop_sequences: params.stop.clone(),
+                seed: params.seed,
+                response_mime_type,
+                response_schema: params
+                    .response_format
+                    .as_ref()
+                    .and_then(|f| f.schema())
+                    .map(gemini_schema),
+            },
+            extra: params.extra.clone(),
+            stream,
+        }
+    }
+}
+
+impl CompletionRequest for GeminiIoRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn process_response(&self, response: Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            match self.stream {
+                false => {
+                    let json = response.json().await?;
+                    tracing::debug!("Got response: {json:#?}");
+                    let response = GeminiResponse::deserialize(&json)?;
+                    match response {
+                        GeminiResponse::Success(suc) => Ok(CompletionResponse::from(
+                            Completion::new(suc.text(), suc.metadata()),
+                        )),
+                        GeminiResponse::Err { error } => Err(error.into_error(&json)),
+                    }
+                }
+                true => {
+                    let response_stream: CompletionStream = Box::new(
+                        response
+                            .json_array_stream::<Value>(1024 * 64)
+                            .map_err(|err| err.into()),
+                    );
+                    let handler: ProviderStreamHandler =
+                        StreamedCompletionHandler::<GeminiStreamResponse>::from(response_stream)
+                            .into();
+                    Ok(handler.into())
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum GeminiResponse {
+    Success(GeminiSuccess),
+    Err { error: GeminiError },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiSuccess {
+    pub candidates: Vec<GeminiCandidate>,
+    pub usage_metadata: Option<GeminiUsage>,
+    pub model_version: Option<String>,
+    pub response_id: Option<String>,
+}
+
+impl GeminiSuccess {
+    pub(super) fn metadata(&self) -> CompletionMetadata {
+        let usage = self.usage_metadata.as_ref().map(|u| {
+            TokenUsage::new(
+                u.prompt_token_count.unwrap_or_default(),
+                u.candidates_token_count.unwrap_or_default(),
+            )
+        });
+        CompletionMetadata {
+            model: self.model_version.to_owned(),
+            id: self.response_id.to_owned(),
+            request_id: None,
+            usage,
+            finish_reason: self
+                .candidates
+                .first()
+                .and_then(|c| c.finish_reason.as_deref())
+                .map(FinishReason::from),
+            fallback_index: None,
+        }
+    }
+
+    /// Text of the first candidate's parts
+    pub(super) fn text(&self) -> String {
+        self.candidates
+            .first()
+            .and_then(|c| c.content.as_ref())
+            .map(|c| {
+                c.parts
+                    .iter()
+                    .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+                    .collect::<Vec<&str>>()
+                    .concat()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCandidate {
+    pub content: Option<GeminiContent>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GeminiContent {
+    #[serde(default)]
+    pub parts: Vec<Value>,
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GeminiError {
+    pub code: i32,
+    pub message: String,
+    pub status: Option<String>,
+}
+impl ProviderResponseError for GeminiError {
+    fn status(&self) -> Option<StatusCode> {
+        u16::try_from(self.code)
+            .ok()
+            .and_then(|c| StatusCode::from_u16(c).ok())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiUsage {
+    pub prompt_token_count: Option<u32>,
+    pub candidates_token_count: Option<u32>,
+    pub total_token_count: Option<u32>,
+}
+
+/// Gemini takes an OpenAPI schema, which has no `additionalProperties` and marks properties
+/// `nullable` rather than giving them a `null` type
+fn gemini_schema(schema: &Value) -> Value {
+    match schema {
+        Value::Object(map) => {
+            let mut converted = Map::new();
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("additionalProperties", _) => {}
+                    ("type", Value::Array(types)) => {
+                        let mut types = types.iter().filter(|t| t.as_str() != Some("null"));
+                        if let Some(typ) = types.next() {
+                            converted.insert(key.to_owned(), typ.clone());
+                        }
+                        converted.insert("nullable".to_owned(), true.into());
+                    }
+                    ("enum", Value::Array(variants)) => {
+                        let variants = variants.iter().filter(|v| !v.is_null()).cloned();
+                        converted.insert(key.to_owned(), Value::Array(variants.collect()));
+                    }
+                    _ => {
+                        converted.insert(key.to_owned(), gemini_schema(value));
+                    }
+                }
+            }
+            Value::Object(converted)
+        }
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gemini_response_parsed_correctly() {
+        let value = json!({
+            "candidates": [{
+                "content": {
+                    "parts": [{"text": "Hello"}, {"text": " there!"}],
+                    "role": "model"
+                },
+                "finishReason": "STOP",
+                "index": 0
+            }],
+            "usageMetadata": {
+                "promptTokenCount": 4,
+                "candidatesTokenCount": 3,
+                "totalTokenCount": 7
+            }
+        });
+        match serde_json::from_value::<GeminiResponse>(value).unwrap() {
+            GeminiResponse::Success(suc) => {
+                assert_eq!(suc.text(), "Hello there!");
+                let metadata = suc.metadata();
+                assert_eq!(metadata.usage, Some(TokenUsage::new(4, 3)));
+                assert_eq!(metadata.finish_reason, Some(FinishReason::Stop));
+            }
+            GeminiResponse::Err { error } => panic!("Expected success, got {error:?}"),
+        }
+
+        let value = json!({
+            "error": {
+                "code": 400,
+                "message": "API key not valid. Please pass a valid API key.",
+                "status": "INVALID_ARGUMENT"
+            }
+        });
+        assert!(matches!(
+            serde_json::from_value::<GeminiResponse>(value).unwrap(),
+            GeminiResponse::Err { .. }
+        ));
+    }
+
+    #[test]
+    fn choices_not_requested_as_candidates() {
+        let params = ModelParameters {
+            n: Some(3),
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let req = GeminiIoRequest::new(
+            &MessageStack::init(),
+            &params,
+            GeminiCompletionModel::default(),
+            false,
+        )
+        .as_json()
+        .unwrap();
+        assert_eq!(req["generationConfig"], json!({"temperature": 0.5}));
+    }
+
+    #[test]
+    fn strict_schema_converted_for_gemini() {
+        let strict = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "unit": {"type": ["string", "null"], "enum": ["c", "f", null]}
+            },
+            "required": ["name", "unit"],
+            "additionalProperties": false
+        });
+        assert_eq!(
+            gemini_schema(&strict),
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "unit": {"type": "string", "nullable": true, "enum": ["c", "f"]}
+                },
+                "required": ["name", "unit"]
+            })
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use super::requests::GeminiSuccess;
+use crate::language_models::completions::{
+    metadata::CompletionMetadata,
+    streaming::{CompletionStreamStatus, StreamResponse},
+};
+use serde::Deserialize;
+
+impl StreamResponse for GeminiStreamResponse {
+    fn metadata(&self) -> Option<CompletionMetadata> {
+        Some(self.0.metadata())
+    }
+}
+
+/// Each streamed chunk is a full `GenerateContentResponse` holding only the newly generated text.
+/// The chunk carrying `finishReason` may still have text, so the stream is only finished once it
+/// runs out of chunks
+#[derive(Debug, Deserialize, Clone)]
+pub struct GeminiStreamResponse(GeminiSuccess);
+
+impl From<GeminiStreamResponse> for CompletionStreamStatus {
+    fn from(value: GeminiStreamResponse) -> Self {
+        CompletionStreamStatus::Working(value.0.text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stream_chunks_parsed_correctly() {
+        let chunks = json!([
+            {
+                "candidates": [{
+                    "content": {"parts": [{"text": "Hello"}], "role": "model"},
+                    "index": 0
+                }]
+            },
+            {
+                "candidates": [{
+                    "content": {"parts": [{"text": " there"}], "role": "model"},
+                    "finishReason": "STOP",
+                    "index": 0
+                }],
+                "usageMetadata": {
+                    "promptTokenCount": 4,
+                    "candidatesTokenCount": 2,
+                    "totalTokenCount": 6
+                }
+            }
+        ]);
+        let tokens: Vec<String> = chunks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                let chunk: GeminiStreamResponse = serde_json::from_value(c.to_owned()).unwrap();
+                match CompletionStreamStatus::from(chunk) {
+                    CompletionStreamStatus::Working(t) => t,
+                    CompletionStreamStatus::Finished => panic!("Chunk should not finish stream"),
+                }
+            })
+            .collect();
+        assert_eq!(tokens.concat(), "Hello there");
+    }
+}
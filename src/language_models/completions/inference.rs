@@ -1,20 +1,30 @@
 use super::{
     error::{CompletionError, CompletionResult},
-    functions::Function,
+    functions::{Function, ToolChoice, ToolCompletion},
+    metadata::{Completion, CompletionMetadata},
     streaming::ProviderStreamHandler,
     ModelParameters,
 };
-use crate::agents::memory::MessageStack;
+use crate::{agents::memory::MessageStack, language_models::pricing::ModelPricing};
+use anyhow::anyhow;
 use futures::Future;
 use reqwest::{header::HeaderMap, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{fmt::Debug, pin::Pin};
 
+/// Describes how to talk to a completion endpoint. Every provider in this crate implements it, and
+/// downstream crates can implement it to add their own by wrapping it in
+/// `CompletionProvider::custom`. Only the methods for the kinds of completion a provider
+/// supports need to be implemented, the rest return `CompletionError::FunctionNotImplemented`
 #[allow(unused)]
-pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
+pub trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     fn model_str(&self) -> &str;
-    fn url_str(&self) -> &str;
+    fn url_str(&self) -> String;
+    /// Most providers stream from the same endpoint they complete from
+    fn stream_url_str(&self) -> String {
+        self.url_str()
+    }
     fn serialize_messages(&self, stack: &MessageStack) -> Value;
     fn headers(&self, api_key: &str) -> HeaderMap;
     fn into_io_req(
@@ -24,6 +34,16 @@ pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Err(CompletionError::FunctionNotImplemented)
     }
+    /// Whether one io request can answer with `ModelParameters::n` choices. Models which can't
+    /// are sent a request per choice instead
+    fn supports_choices(&self) -> bool {
+        false
+    }
+    /// Whether io requests honour `ResponseFormat::JsonSchema`. Typed completions from models
+    /// which don't are got by making them call a function instead
+    fn supports_json_schema(&self) -> bool {
+        false
+    }
     fn into_stream_req(
         &self,
         stack: &MessageStack,
@@ -31,17 +51,55 @@ pub(crate) trait CompletionRequestBuilder: Debug + Sync + Send + 'static {
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
         Err(CompletionError::FunctionNotImplemented)
     }
-    // currently only supports single functions, but functions can technically be added to models
-    // like messages
+    /// Streamed completion in which the model may call any of `functions`, its calls are streamed
+    /// as they're generated
+    fn into_tool_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Err(CompletionError::FunctionNotImplemented)
+    }
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        Err(CompletionError::FunctionNotImplemented)
+    }
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        Err(CompletionError::FunctionNotImplemented)
+    }
+    // Forces the model to call the given function, any provider which supports tools gets this for
+    // free
     fn serialize_function(
         &self,
         stack: &MessageStack,
+        params: &ModelParameters,
         function: Function,
     ) -> CompletionResult<Value> {
-        Err(CompletionError::FunctionNotImplemented)
+        let choice = ToolChoice::Function(function.name.to_owned());
+        self.serialize_tools(stack, params, &[function], &choice)
+    }
+    /// Usage, finish reason and ids of a function or tool completion response
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        CompletionMetadata::default()
+    }
+    /// Price used to work out what a completion cost when the `CompletionModel` doesn't override it
+    fn pricing(&self) -> Option<ModelPricing> {
+        None
     }
     fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
-        Err(CompletionError::FunctionNotImplemented)
+        match self.process_tools_response(response_json)? {
+            ToolCompletion::Calls(mut calls) if !calls.is_empty() => Ok(calls.remove(0).arguments),
+            ToolCompletion::Message(_) | ToolCompletion::Calls(_) => Err(CompletionError::from(
+                anyhow!("Model responded without calling the function"),
+            )),
+        }
     }
 }
 
@@ -57,7 +115,9 @@ pub trait CompletionRequest: Debug + Sync + Send + 'static {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompletionResponse {
     /// For IO completions
-    Io(String),
+    Io(Completion<String>),
+    /// For IO completions asked for more than one choice
+    Choices(Completion<Vec<String>>),
     /// For streamed completions
     #[serde(skip)]
     Stream(ProviderStreamHandler),
@@ -67,6 +127,12 @@ pub enum CompletionResponse {
 
 impl From<String> for CompletionResponse {
     fn from(value: String) -> Self {
+        Self::Io(value.into())
+    }
+}
+
+impl From<Completion<String>> for CompletionResponse {
+    fn from(value: Completion<String>) -> Self {
         Self::Io(value)
     }
 }
@@ -83,13 +149,29 @@ impl From<Value> for CompletionResponse {
     }
 }
 
-impl TryInto<String> for CompletionResponse {
+impl TryInto<Completion<String>> for CompletionResponse {
     type Error = CompletionError;
-    fn try_into(self) -> Result<String, Self::Error> {
-        if let Self::Io(s) = self {
-            return Ok(s);
+    /// Models with `ModelParameters::n` above 1 answer with several choices, only the first is kept
+    fn try_into(self) -> Result<Completion<String>, Self::Error> {
+        match self {
+            Self::Io(s) => Ok(s),
+            Self::Choices(mut c) if !c.content.is_empty() => {
+                let first = c.content.remove(0);
+                Ok(Completion::new(first, c.metadata))
+            }
+            _ => Err(CompletionError::CouldNotCoerce),
+        }
+    }
+}
+
+impl TryInto<Completion<Vec<String>>> for CompletionResponse {
+    type Error = CompletionError;
+    fn try_into(self) -> Result<Completion<Vec<String>>, Self::Error> {
+        match self {
+            Self::Choices(c) => Ok(c),
+            Self::Io(c) => Ok(c.map(|content| vec![content])),
+            _ => Err(CompletionError::CouldNotCoerce),
         }
-        Err(CompletionError::CouldNotCoerce)
     }
 }
 
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, AddAssign, Deref};
+
+/// Tokens a provider billed for a completion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl TokenUsage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl Add for TokenUsage {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            prompt_tokens: self.prompt_tokens + rhs.prompt_tokens,
+            completion_tokens: self.completion_tokens + rhs.completion_tokens,
+        }
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+/// Why the model stopped generating, normalized across providers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    /// Natural end of the message or a stop sequence was hit
+    Stop,
+    /// Ran into `max_tokens` or the context length
+    Length,
+    /// Stopped to call one or more functions
+    ToolCalls,
+    /// Output was withheld by the provider's filters
+    ContentFilter,
+    /// Anything else, as the provider named it
+    Other(String),
+}
+
+impl From<&str> for FinishReason {
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "stop" | "end_turn" | "stop_sequence" => Self::Stop,
+            "length" | "max_tokens" => Self::Length,
+            "tool_calls" | "tool_use" | "function_call" => Self::ToolCalls,
+            "content_filter" | "safety" | "recitation" | "blocklist" | "prohibited_content" => {
+                Self::ContentFilter
+            }
+            _ => Self::Other(value.to_owned()),
+        }
+    }
+}
+
+/// Everything a provider reports about a completion besides its content. Fields are `None` when
+/// the provider doesn't report them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletionMetadata {
+    /// Model that generated the completion, as named by the provider
+    pub model: Option<String>,
+    /// Id the provider gave the completion, e.g. `chatcmpl-...` or `msg_...`
+    pub id: Option<String>,
+    /// Id of the HTTP request, taken from the provider's response headers
+    pub request_id: Option<String>,
+    pub usage: Option<TokenUsage>,
+    pub finish_reason: Option<FinishReason>,
+    /// Which model of a fallback chain answered, 0 being the primary. Only set for models with
+    /// fallbacks
+    #[serde(default)]
+    pub fallback_index: Option<usize>,
+}
+
+impl CompletionMetadata {
+    /// Fill in whatever `other` knows. Used to build up metadata from the chunks of a stream, which
+    /// each only carry part of it
+    pub(crate) fn merge(&mut self, other: CompletionMetadata) {
+        if other.model.is_some() {
+            self.model = other.model;
+        }
+        if other.id.is_some() {
+            self.id = other.id;
+        }
+        if other.request_id.is_some() {
+            self.request_id = other.request_id;
+        }
+        if other.finish_reason.is_some() {
+            self.finish_reason = other.finish_reason;
+        }
+        if other.fallback_index.is_some() {
+            self.fallback_index = other.fallback_index;
+        }
+        if let Some(usage) = other.usage {
+            let current = self.usage.get_or_insert_with(TokenUsage::default);
+            if usage.prompt_tokens > 0 {
+                current.prompt_tokens = usage.prompt_tokens;
+            }
+            if usage.completion_tokens > 0 {
+                current.completion_tokens = usage.completion_tokens;
+            }
+        }
+    }
+}
+
+/// Content of a completion along with its metadata. Derefs to the content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion<T> {
+    pub content: T,
+    pub metadata: CompletionMetadata,
+}
+
+impl<T> Completion<T> {
+    pub fn new(content: T, metadata: CompletionMetadata) -> Self {
+        Self { content, metadata }
+    }
+
+    pub fn into_content(self) -> T {
+        self.content
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Completion<U> {
+        Completion {
+            content: f(self.content),
+            metadata: self.metadata,
+        }
+    }
+}
+
+impl<T> Deref for Completion<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.content
+    }
+}
+
+impl From<String> for Completion<String> {
+    fn from(value: String) -> Self {
+        Self::new(value, CompletionMetadata::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_metadata_merged_correctly() {
+        let mut metadata = CompletionMetadata::default();
+        metadata.merge(CompletionMetadata {
+            model: Some("claude-3-haiku-20240307".to_owned()),
+            id: Some("msg_01".to_owned()),
+            usage: Some(TokenUsage::new(25, 1)),
+            ..Default::default()
+        });
+        metadata.merge(CompletionMetadata {
+            usage: Some(TokenUsage::new(0, 15)),
+            finish_reason: Some(FinishReason::from("end_turn")),
+            ..Default::default()
+        });
+        let expected = CompletionMetadata {
+            model: Some("claude-3-haiku-20240307".to_owned()),
+            id: Some("msg_01".to_owned()),
+            request_id: None,
+            usage: Some(TokenUsage::new(25, 15)),
+            finish_reason: Some(FinishReason::Stop),
+            fallback_index: None,
+        };
+        assert_eq!(metadata, expected);
+    }
+}
@@ -1,29 +1,72 @@
 pub mod anthropic;
+pub mod consistency;
 pub mod error;
 pub mod functions;
+pub mod gemini;
 #[cfg(feature = "bert")]
 pub mod huggingface;
-mod inference;
+pub mod inference;
+pub mod metadata;
+pub mod ollama;
 pub mod openai;
+pub mod retry;
 pub mod streaming;
 use self::{
-    anthropic::builder::AnthropicCompletionModel, error::CompletionResult, functions::Function,
-    inference::CompletionRequestBuilder, openai::builder::OpenAiCompletionModel,
+    anthropic::builder::AnthropicCompletionModel,
+    error::{error_for_status, CompletionError, CompletionResult},
+    functions::{Function, ToolChoice, ToolCompletion},
+    gemini::builder::GeminiCompletionModel,
+    inference::{CompletionRequestBuilder, CompletionResponse},
+    metadata::{Completion, CompletionMetadata, TokenUsage},
+    ollama::builder::OllamaCompletionModel,
+    openai::builder::{AzureOpenAiModel, OpenAiCompatibleModel, OpenAiCompletionModel},
+    retry::{is_transient, RetryPolicy},
     streaming::ProviderStreamHandler,
 };
 
-use crate::agents::memory::MessageStack;
-use anyhow::anyhow;
-use reqwest::Client;
+use crate::{
+    agents::memory::MessageStack,
+    language_models::{
+        key_pool::ApiKeyPool,
+        pricing::ModelPricing,
+        rate_limit::{estimate_tokens, RateLimiter},
+    },
+};
+use futures::Future;
+use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::fmt::Debug;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use tracing::{info, warn};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompletionProvider {
     OpenAi(OpenAiCompletionModel),
     Anthropic(AnthropicCompletionModel),
+    OpenAiCompatible(OpenAiCompatibleModel),
+    Ollama(OllamaCompletionModel),
+    Gemini(GeminiCompletionModel),
+    AzureOpenAi(AzureOpenAiModel),
+    /// Provider defined outside of this crate. Cannot be serialized
+    #[serde(skip)]
+    Custom(Arc<dyn CompletionRequestBuilder>),
+}
+
+impl Eq for CompletionProvider {}
+
+impl PartialEq for CompletionProvider {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::OpenAi(a), Self::OpenAi(b)) => a == b,
+            (Self::Anthropic(a), Self::Anthropic(b)) => a == b,
+            (Self::OpenAiCompatible(a), Self::OpenAiCompatible(b)) => a == b,
+            (Self::Ollama(a), Self::Ollama(b)) => a == b,
+            (Self::Gemini(a), Self::Gemini(b)) => a == b,
+            (Self::AzureOpenAi(a), Self::AzureOpenAi(b)) => a == b,
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl From<OpenAiCompletionModel> for CompletionProvider {
@@ -38,11 +81,45 @@ impl From<AnthropicCompletionModel> for CompletionProvider {
     }
 }
 
+impl From<OpenAiCompatibleModel> for CompletionProvider {
+    fn from(value: OpenAiCompatibleModel) -> Self {
+        Self::OpenAiCompatible(value)
+    }
+}
+
+impl From<OllamaCompletionModel> for CompletionProvider {
+    fn from(value: OllamaCompletionModel) -> Self {
+        Self::Ollama(value)
+    }
+}
+
+impl From<GeminiCompletionModel> for CompletionProvider {
+    fn from(value: GeminiCompletionModel) -> Self {
+        Self::Gemini(value)
+    }
+}
+
+impl From<AzureOpenAiModel> for CompletionProvider {
+    fn from(value: AzureOpenAiModel) -> Self {
+        Self::AzureOpenAi(value)
+    }
+}
+
 impl CompletionProvider {
+    /// Wrap a user defined `CompletionRequestBuilder` so it can be used by a `CompletionModel`
+    pub fn custom(builder: impl CompletionRequestBuilder) -> Self {
+        Self::Custom(Arc::new(builder))
+    }
+
     fn inner_builder(&self) -> Box<&dyn CompletionRequestBuilder> {
         match &self {
-            Self::OpenAi(b) => return Box::new(b),
-            Self::Anthropic(b) => return Box::new(b),
+            Self::OpenAi(b) => Box::new(b),
+            Self::Anthropic(b) => Box::new(b),
+            Self::OpenAiCompatible(b) => Box::new(b),
+            Self::Ollama(b) => Box::new(b),
+            Self::Gemini(b) => Box::new(b),
+            Self::AzureOpenAi(b) => Box::new(b),
+            Self::Custom(b) => Box::new(b.as_ref()),
         }
     }
 }
@@ -51,6 +128,22 @@ impl CompletionProvider {
 pub struct CompletionModel {
     pub provider: CompletionProvider,
     pub params: ModelParameters,
+    /// Overrides the provider's default price, see `CompletionModel::pricing`
+    #[serde(default)]
+    pub pricing: Option<ModelPricing>,
+    /// How failed requests are retried, see `RetryPolicy::default`
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// Shared limit on requests and tokens per minute, see `RateLimiter`
+    #[serde(skip)]
+    pub rate_limiter: Option<RateLimiter>,
+    /// Tried in order when this model is down, rate limiting or timing out, see
+    /// `CompletionModel::with_fallback`
+    #[serde(default)]
+    pub fallbacks: Vec<CompletionModel>,
+    /// Used instead of `api_key` when set, see `ApiKeyPool`
+    #[serde(skip)]
+    pub key_pool: Option<ApiKeyPool>,
     pub api_key: String,
     #[serde(skip)]
     client: Client,
@@ -62,23 +155,34 @@ impl PartialEq for CompletionModel {
     fn eq(&self, other: &Self) -> bool {
         self.provider == other.provider
             && self.params == other.params
+            && self.pricing == other.pricing
+            && self.retry_policy == other.retry_policy
+            && self.fallbacks == other.fallbacks
             && self.api_key == other.api_key
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ModelParameters {
     /// Total token usage count of the model
     pub total_token_count: u32,
     /// What sampling temperature to use, between 0 and 2.
     /// Higher values like 0.8 will make the output more random,
     /// while lower values like 0.2 will make it more focused and deterministic.
-    /// Input as a value between 0 and 200. Will be turned into float.
-    pub temperature: Option<u8>,
+    pub temperature: Option<f32>,
+    /// Nucleus sampling, only tokens comprising the top `top_p` probability mass are considered.
+    /// Generally alter this or temperature, not both.
+    pub top_p: Option<f32>,
+    /// Only sample from the top K options for each subsequent token.
+    /// Not supported by OpenAi.
+    pub top_k: Option<u32>,
     /// Number between -2.0 and 2.0.
     /// Positive values penalize new tokens based on their existing frequency in the text so far,
     /// decreasing the model's likelihood to repeat the same line verbatim.
-    pub frequency_penalty: Option<i8>,
+    pub frequency_penalty: Option<f32>,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
+    /// increasing the model's likelihood to talk about new topics.
+    pub presence_penalty: Option<f32>,
     /// The maximum number of tokens that can be generated in the chat completion.
     /// The total length of input tokens and generated tokens is limited by the model's context length.
     pub max_tokens: Option<u32>,
@@ -86,28 +190,95 @@ pub struct ModelParameters {
     /// Note that you will be charged based on the number of generated tokens across all of the choices.
     /// Keep n as 1 to minimize costs.
     pub n: Option<u32>,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
-    /// increasing the model's likelihood to talk about new topics.
-    pub presence_penalty: Option<i8>,
+    /// Sequences where the model will stop generating further tokens.
+    pub stop: Option<Vec<String>>,
+    /// Providers that support it make a best effort to sample deterministically for requests
+    /// with the same seed and parameters.
+    pub seed: Option<i64>,
+    /// Maps token ids to a bias between -100 and 100 which is added to their logits before sampling.
+    /// Only supported by OpenAi.
+    pub logit_bias: Option<HashMap<u32, i8>>,
+    /// Format the model must output.
+    pub response_format: Option<ResponseFormat>,
+    /// Provider specific keys that are added to the top level of every request body as is,
+    /// overwriting anything already there.
+    #[serde(default)]
+    pub extra: Map<String, Value>,
 }
 
 impl Default for ModelParameters {
     fn default() -> Self {
         Self {
             total_token_count: 0,
-            temperature: Some(70),
+            temperature: Some(0.7),
+            top_p: None,
+            top_k: None,
             frequency_penalty: None,
+            presence_penalty: None,
             max_tokens: None,
             n: Some(1),
-            presence_penalty: None,
+            stop: None,
+            seed: None,
+            logit_bias: None,
+            response_format: None,
+            extra: Map::new(),
         }
     }
 }
 
-impl ModelParameters {
-    /// Temperature is computed to a number between 0 and 1 by dividing this value by 100
-    fn temperature(&self) -> Result<f32, anyhow::Error> {
-        Ok((self.temperature.ok_or(anyhow!("No temperature"))? / 100) as f32)
+/// Output format a model is constrained to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// Any valid JSON object
+    JsonObject,
+    /// JSON matching the given schema, `json_schema` is an object with `name`, `schema` and
+    /// optionally `strict`, as OpenAi expects it
+    JsonSchema {
+        json_schema: Value,
+    },
+}
+
+impl ResponseFormat {
+    /// JSON matching the parameters of `function`, named and described after it. The schema is
+    /// strict, so that OpenAi enforces it rather than taking it as a hint: no other properties are
+    /// allowed, and since every property has to be required, optional ones are made nullable
+    pub fn json_schema(function: &Function) -> Self {
+        let mut schema = function.params_json_schema();
+        let required = schema["required"].as_array().cloned().unwrap_or_default();
+        let mut names = vec![];
+        if let Some(properties) = schema["properties"].as_object_mut() {
+            for (name, property) in properties.iter_mut() {
+                names.push(name.to_owned());
+                if required.contains(&json!(name)) {
+                    continue;
+                }
+                property["type"] = json!([property["type"], "null"]);
+                if let Some(variants) = property.get_mut("enum").and_then(|e| e.as_array_mut()) {
+                    variants.push(Value::Null);
+                }
+            }
+        }
+        schema["required"] = json!(names);
+        schema["additionalProperties"] = false.into();
+        let mut json_schema = json!({
+            "name": function.name,
+            "strict": true,
+            "schema": schema,
+        });
+        if !function.description.is_empty() {
+            json_schema["description"] = function.description.clone().into();
+        }
+        Self::JsonSchema { json_schema }
+    }
+
+    /// The `schema` of a `JsonSchema` format
+    pub(crate) fn schema(&self) -> Option<&Value> {
+        match self {
+            Self::JsonSchema { json_schema } => json_schema.get("schema"),
+            _ => None,
+        }
     }
 }
 
@@ -121,6 +292,11 @@ impl CompletionModel {
         Self {
             provider: m.into(),
             params,
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
             client,
             api_key: api_key.to_owned(),
         }
@@ -133,6 +309,11 @@ impl CompletionModel {
         CompletionModel {
             provider,
             params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
             api_key: api_key.to_owned(),
             client,
         }
@@ -145,26 +326,423 @@ impl CompletionModel {
         CompletionModel {
             provider,
             params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
             api_key: api_key.to_owned(),
             client,
         }
     }
 
-    #[tracing::instrument(name = "io completion", skip_all)]
+    ///  gemini Flash handler with 0.7 temp
+    pub fn default_gemini(api_key: &str) -> CompletionModel {
+        let provider = CompletionProvider::Gemini(GeminiCompletionModel::default());
+        let client = reqwest::Client::new();
+        CompletionModel {
+            provider,
+            params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
+            api_key: api_key.to_owned(),
+            client,
+        }
+    }
+
+    /// Model served by anything that implements OpenAi's chat completions API, such as a local
+    /// vLLM or llama.cpp server. Pass `None` as `api_key` if the server doesn't require auth
+    pub fn openai_compatible(
+        base_url: &str,
+        model: &str,
+        api_key: Option<&str>,
+    ) -> CompletionModel {
+        let provider =
+            CompletionProvider::OpenAiCompatible(OpenAiCompatibleModel::new(base_url, model));
+        let client = reqwest::Client::new();
+        CompletionModel {
+            provider,
+            params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
+            api_key: api_key.unwrap_or_default().to_owned(),
+            client,
+        }
+    }
+
+    /// OpenAi model deployed to an Azure OpenAi resource, e.g.
+    /// `azure_openai("my-resource", "gpt-4o-prod", "2024-06-01", api_key)`
+    pub fn azure_openai(
+        resource: &str,
+        deployment: &str,
+        api_version: &str,
+        api_key: &str,
+    ) -> CompletionModel {
+        let provider = CompletionProvider::AzureOpenAi(AzureOpenAiModel::new(
+            resource,
+            deployment,
+            api_version,
+        ));
+        let client = reqwest::Client::new();
+        CompletionModel {
+            provider,
+            params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
+            api_key: api_key.to_owned(),
+            client,
+        }
+    }
+
+    /// Model served by a local Ollama instance on its default port, e.g. `ollama("llama3")`.
+    /// Use `CompletionModel::new` with an `OllamaCompletionModel` to point at another host
+    pub fn ollama(model: &str) -> CompletionModel {
+        let provider = CompletionProvider::Ollama(OllamaCompletionModel::new(
+            ollama::builder::DEFAULT_OLLAMA_URL,
+            model,
+        ));
+        let client = reqwest::Client::new();
+        CompletionModel {
+            provider,
+            params: ModelParameters::default(),
+            pricing: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            fallbacks: vec![],
+            key_pool: None,
+            api_key: String::new(),
+            client,
+        }
+    }
+
+    /// Price completions from this model at `pricing` instead of the provider's default. Needed
+    /// to keep track of cost for any model this crate doesn't know the price of
+    pub fn with_pricing(mut self, pricing: ModelPricing) -> Self {
+        self.pricing = Some(pricing);
+        self
+    }
+
+    /// Retry failed requests according to `policy`, `RetryPolicy::none()` turns retries off
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Queue requests behind `limiter`. Give the same limiter, or clones of it, to every model
+    /// sharing an api key
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Send requests with keys from `pool` in turn instead of `api_key`
+    pub fn with_key_pool(mut self, pool: ApiKeyPool) -> Self {
+        self.key_pool = Some(pool);
+        self
+    }
+
+    /// Add `model` to the end of this model's fallback chain, e.g.
+    /// `opus.with_fallback(gpt4).with_fallback(haiku)`. Once this model has used up its retries on
+    /// an outage, rate limit or timeout, each fallback is tried in turn with its own retry policy.
+    /// Which one answered is recorded in `CompletionMetadata::fallback_index`
+    pub fn with_fallback(mut self, model: CompletionModel) -> Self {
+        self.fallbacks.push(model);
+        self
+    }
+
+    /// Model of the fallback chain that `index` refers to, this model for `None` or 0
+    pub fn fallback(&self, index: Option<usize>) -> &CompletionModel {
+        match index {
+            Some(i) if i > 0 => self.fallbacks.get(i - 1).unwrap_or(self),
+            _ => self,
+        }
+    }
+
+    /// Price of this model, `None` if it was never set and the provider doesn't know it
+    pub fn pricing(&self) -> Option<ModelPricing> {
+        self.pricing
+            .or_else(|| self.provider.inner_builder().pricing())
+    }
+
+    /// Dollar cost of `usage` on this model. Free if the price isn't known
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        self.pricing().map(|p| p.cost(usage)).unwrap_or_default()
+    }
+
+    /// Runs `attempt` against this model, then each of its fallbacks in turn for as long as they
+    /// fail with transient errors. Returns the index of the model that answered, if there are any
+    /// fallbacks
+    async fn with_fallbacks<'a, T, F, Fut>(
+        &'a self,
+        mut attempt: F,
+    ) -> CompletionResult<(Option<usize>, T)>
+    where
+        F: FnMut(&'a CompletionModel) -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        let chain: Vec<&CompletionModel> = std::iter::once(self).chain(&self.fallbacks).collect();
+        let has_fallbacks = chain.len() > 1;
+        for (index, model) in chain.iter().enumerate() {
+            match attempt(model).await {
+                Ok(res) => return Ok((has_fallbacks.then_some(index), res)),
+                Err(err) if index + 1 < chain.len() && is_transient(&err) => {
+                    warn!("Model {} failed, falling back to the next: {}", index, err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!("fallback chain always has at least one model")
+    }
+
     pub(crate) async fn get_io_completion(
         &self,
         messages: &MessageStack,
-    ) -> CompletionResult<String> {
+    ) -> CompletionResult<Completion<String>> {
+        let (fallback_index, mut completion) = self
+            .with_fallbacks(|model| {
+                model.retry_policy.run(move || {
+                    model.with_api_key(move |key| async move {
+                        model.try_io_completion(messages, &key).await
+                    })
+                })
+            })
+            .await?;
+        completion.metadata.fallback_index = fallback_index;
+        Ok(completion)
+    }
+
+    /// Gets `n` answers to `messages`. Models which support it are asked for every choice in one
+    /// request, whatever choices are still missing after that, e.g. because the provider has no
+    /// `n` or the answering fallback ignored it, are each requested separately and at the same time
+    pub(crate) async fn get_io_choices(
+        &self,
+        messages: &MessageStack,
+        n: usize,
+    ) -> CompletionResult<Completion<Vec<String>>> {
+        let mut choices = Completion::new(vec![], CompletionMetadata::default());
+        if n > 1 && self.provider.inner_builder().supports_choices() {
+            let (fallback_index, completion) = self
+                .with_fallbacks(|model| {
+                    model.retry_policy.run(move || {
+                        model.with_api_key(move |key| async move {
+                            let params = ModelParameters {
+                                n: Some(n as u32),
+                                ..model.params.clone()
+                            };
+                            model.try_io_request(messages, &params, &key).await
+                        })
+                    })
+                })
+                .await?;
+            choices = completion;
+            choices.content.truncate(n);
+            choices.metadata.fallback_index = fallback_index;
+        }
+
+        let missing = n - choices.content.len();
+        let completions =
+            futures::future::try_join_all((0..missing).map(|_| self.get_io_completion(messages)))
+                .await?;
+        for completion in completions {
+            if choices.content.is_empty() {
+                choices.metadata = completion.metadata;
+            } else if let Some(usage) = completion.metadata.usage {
+                let total = choices.metadata.usage.unwrap_or_default() + usage;
+                choices.metadata.usage = Some(total);
+            }
+            choices.content.push(completion.content);
+        }
+        Ok(choices)
+    }
+
+    /// Gets JSON matching the parameters of `function`, as the text the model wrote. Models which
+    /// honour `ResponseFormat::JsonSchema` are asked for it directly, the rest are made to call
+    /// `function`. Each model of the fallback chain is asked whichever way suits it
+    pub(crate) async fn get_structured_completion(
+        &self,
+        messages: &MessageStack,
+        function: &Function,
+    ) -> CompletionResult<Completion<String>> {
+        let (fallback_index, mut completion) = self
+            .with_fallbacks(|model| {
+                model.retry_policy.run(move || {
+                    model.with_api_key(move |key| async move {
+                        if !model.provider.inner_builder().supports_json_schema() {
+                            let completion = model
+                                .try_fn_completion(messages, function.clone(), &key)
+                                .await?;
+                            return Ok(completion.map(|args| args.to_string()));
+                        }
+                        let params = ModelParameters {
+                            response_format: Some(ResponseFormat::json_schema(function)),
+                            ..model.params.clone()
+                        };
+                        model.try_io_request(messages, &params, &key).await
+                    })
+                })
+            })
+            .await?;
+        completion.metadata.fallback_index = fallback_index;
+        Ok(completion)
+    }
+
+    /// Gets the stream and waits for its first chunk, so that a stream which fails before its
+    /// first token is retried or falls back as well. The model may call any of `tools`' functions
+    pub(crate) async fn get_stream_completion(
+        &self,
+        messages: &MessageStack,
+        tools: Option<(&[Function], &ToolChoice)>,
+    ) -> CompletionResult<ProviderStreamHandler> {
+        let (fallback_index, handler) = self
+            .with_fallbacks(|model| {
+                model.retry_policy.run(move || {
+                    model.with_api_key(move |key| async move {
+                        let mut handler =
+                            model.try_stream_completion(messages, tools, &key).await?;
+                        handler.check_first_chunk().await?;
+                        Ok(handler)
+                    })
+                })
+            })
+            .await?;
+        handler.merge_metadata(CompletionMetadata {
+            fallback_index,
+            ..Default::default()
+        });
+        Ok(handler)
+    }
+
+    pub(crate) async fn get_fn_completion(
+        &self,
+        messages: &MessageStack,
+        function: Function,
+    ) -> CompletionResult<Completion<Value>> {
+        let function = &function;
+        let (fallback_index, mut completion) = self
+            .with_fallbacks(|model| {
+                model.retry_policy.run(move || {
+                    model.with_api_key(move |key| async move {
+                        model
+                            .try_fn_completion(messages, function.clone(), &key)
+                            .await
+                    })
+                })
+            })
+            .await?;
+        completion.metadata.fallback_index = fallback_index;
+        Ok(completion)
+    }
+
+    pub(crate) async fn get_tool_completion(
+        &self,
+        messages: &MessageStack,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Completion<ToolCompletion>> {
+        let (fallback_index, mut completion) = self
+            .with_fallbacks(|model| {
+                model.retry_policy.run(move || {
+                    model.with_api_key(move |key| async move {
+                        model
+                            .try_tool_completion(messages, functions, choice, &key)
+                            .await
+                    })
+                })
+            })
+            .await?;
+        completion.metadata.fallback_index = fallback_index;
+        Ok(completion)
+    }
+
+    /// Runs `attempt` with this model's api key, or with the next key of its pool. A pooled key
+    /// that is rejected or rate limited is set aside and the next one tried straight away
+    async fn with_api_key<T, F, Fut>(&self, mut attempt: F) -> CompletionResult<T>
+    where
+        T: ReportsUsage,
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        let Some(pool) = self.key_pool.as_ref().filter(|p| !p.is_empty()) else {
+            return attempt(self.api_key.clone()).await;
+        };
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let (index, key) = pool.next_key();
+            match attempt(key).await {
+                Ok(res) => {
+                    pool.record_success(index, res.usage());
+                    return Ok(res);
+                }
+                Err(err) => {
+                    let set_aside = pool.record_error(index, &err);
+                    if !set_aside || tries >= pool.len() || !pool.has_available() {
+                        return Err(err);
+                    }
+                    warn!("Trying the next key of the pool after: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Waits for room in the rate limiter, if there is one. Returns what the request was
+    /// estimated to use, counting the whole of the `max_tokens` it was sent with as providers do
+    async fn wait_for_rate_limit(&self, req: &Value, params: &ModelParameters) -> u32 {
+        let Some(limiter) = &self.rate_limiter else {
+            return 0;
+        };
+        let estimated_tokens =
+            estimate_tokens(&req.to_string()) + params.max_tokens.unwrap_or_default();
+        limiter.acquire(estimated_tokens).await;
+        estimated_tokens
+    }
+
+    async fn reconcile_rate_limit(&self, estimated_tokens: u32, metadata: &CompletionMetadata) {
+        if let (Some(limiter), Some(usage)) = (&self.rate_limiter, metadata.usage) {
+            limiter.reconcile(estimated_tokens, usage.total_tokens());
+        }
+    }
+
+    async fn try_io_completion(
+        &self,
+        messages: &MessageStack,
+        api_key: &str,
+    ) -> CompletionResult<Completion<String>> {
+        self.try_io_request(messages, &self.params, api_key).await
+    }
+
+    /// Io request sent with `params` rather than the model's own, so that the number of choices
+    /// can be changed
+    #[tracing::instrument(name = "io completion", skip_all)]
+    async fn try_io_request<T>(
+        &self,
+        messages: &MessageStack,
+        params: &ModelParameters,
+        api_key: &str,
+    ) -> CompletionResult<Completion<T>>
+    where
+        CompletionResponse: TryInto<Completion<T>, Error = CompletionError>,
+    {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
+        let headers = builder.headers(api_key);
         let url = builder.url_str();
-        let req = builder.into_io_req(messages, &self.params)?;
+        let req = builder.into_io_req(messages, params)?;
         let json_req = req.as_json()?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
             json_req, url, headers
         );
 
+        let estimated_tokens = self.wait_for_rate_limit(&json_req, params).await;
         let response = self
             .client
             .post(url)
@@ -172,9 +750,17 @@ impl CompletionModel {
             .json(&json_req)
             .send()
             .await?;
+        let request_id = request_id_from_headers(response.headers());
+        let response = error_for_status(response).await?;
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<String>::try_into(r)?),
+            Ok(r) => {
+                let mut completion: Completion<T> = r.try_into()?;
+                completion.metadata.request_id = request_id;
+                self.reconcile_rate_limit(estimated_tokens, &completion.metadata)
+                    .await;
+                Ok(completion)
+            }
             Err(err) => {
                 warn!("Error getting Io completion: {:?}", err);
                 Err(err)
@@ -183,20 +769,28 @@ impl CompletionModel {
     }
 
     #[tracing::instrument(name = "streamed completion", skip_all)]
-    pub(crate) async fn get_stream_completion(
+    async fn try_stream_completion(
         &self,
         messages: &MessageStack,
+        tools: Option<(&[Function], &ToolChoice)>,
+        api_key: &str,
     ) -> CompletionResult<ProviderStreamHandler> {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
-        let url = builder.url_str();
-        let req = builder.into_stream_req(messages, &self.params)?;
+        let headers = builder.headers(api_key);
+        let url = builder.stream_url_str();
+        let req = match tools {
+            Some((functions, choice)) => {
+                builder.into_tool_stream_req(messages, &self.params, functions, choice)?
+            }
+            None => builder.into_stream_req(messages, &self.params)?,
+        };
         let json_req = req.as_json()?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
             json_req, url, headers
         );
 
+        let estimated_tokens = self.wait_for_rate_limit(&json_req, &self.params).await;
         let response = self
             .client
             .post(url)
@@ -204,31 +798,46 @@ impl CompletionModel {
             .json(&json_req)
             .send()
             .await?;
+        let request_id = request_id_from_headers(response.headers());
+        let response = error_for_status(response).await?;
 
         match req.process_response(response).await {
-            Ok(r) => return Ok(TryInto::<ProviderStreamHandler>::try_into(r)?),
+            Ok(r) => {
+                let mut handler: ProviderStreamHandler = r.try_into()?;
+                handler.merge_metadata(CompletionMetadata {
+                    request_id,
+                    ..Default::default()
+                });
+                // Streams only report usage once they finish
+                if let Some(limiter) = &self.rate_limiter {
+                    handler.set_rate_limit(limiter.clone(), estimated_tokens);
+                }
+                Ok(handler)
+            }
             Err(err) => {
                 warn!("Error getting streamed Io completion: {:?}", err);
-                Err(err.into())
+                Err(err)
             }
         }
     }
 
     #[tracing::instrument(name = "function completion", skip_all)]
-    pub(crate) async fn get_fn_completion(
+    async fn try_fn_completion(
         &self,
         messages: &MessageStack,
         function: Function,
-    ) -> CompletionResult<Value> {
+        api_key: &str,
+    ) -> CompletionResult<Completion<Value>> {
         let builder = self.provider.inner_builder();
-        let headers = builder.headers(&self.api_key);
+        let headers = builder.headers(api_key);
         let url = builder.url_str();
-        let req = builder.serialize_function(messages, function)?;
+        let req = builder.serialize_function(messages, &self.params, function)?;
         info!(
             "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
             req, url, headers
         );
 
+        let estimated_tokens = self.wait_for_rate_limit(&req, &self.params).await;
         let response = self
             .client
             .post(url)
@@ -236,14 +845,86 @@ impl CompletionModel {
             .json(&req)
             .send()
             .await?;
+        let request_id = request_id_from_headers(response.headers());
+        let response = error_for_status(response).await?;
         let json = response.json().await?;
         info!("Got response: {json:#?}");
+        let mut metadata = builder.process_metadata(&json);
+        metadata.request_id = request_id;
+        self.reconcile_rate_limit(estimated_tokens, &metadata).await;
         match builder.process_function_response(json) {
-            Ok(r) => return Ok(r),
+            Ok(r) => Ok(Completion::new(r, metadata)),
             Err(err) => {
                 warn!("Error getting function completion: {:?}", err);
-                Err(err.into())
+                Err(err)
             }
         }
     }
+
+    #[tracing::instrument(name = "tool completion", skip_all)]
+    async fn try_tool_completion(
+        &self,
+        messages: &MessageStack,
+        functions: &[Function],
+        choice: &ToolChoice,
+        api_key: &str,
+    ) -> CompletionResult<Completion<ToolCompletion>> {
+        let builder = self.provider.inner_builder();
+        let headers = builder.headers(api_key);
+        let url = builder.url_str();
+        let req = builder.serialize_tools(messages, &self.params, functions, choice)?;
+        info!(
+            "\nSending request:\n{:?}\nto: {}\nwith headers: {:?}\n",
+            req, url, headers
+        );
+
+        let estimated_tokens = self.wait_for_rate_limit(&req, &self.params).await;
+        let response = self
+            .client
+            .post(url)
+            .headers(headers)
+            .json(&req)
+            .send()
+            .await?;
+        let request_id = request_id_from_headers(response.headers());
+        let response = error_for_status(response).await?;
+        let json = response.json().await?;
+        info!("Got response: {json:#?}");
+        let mut metadata = builder.process_metadata(&json);
+        metadata.request_id = request_id;
+        self.reconcile_rate_limit(estimated_tokens, &metadata).await;
+        match builder.process_tools_response(json) {
+            Ok(r) => Ok(Completion::new(r, metadata)),
+            Err(err) => {
+                warn!("Error getting tool completion: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Usage a successful attempt reports, so it can be counted against the key it was made with
+trait ReportsUsage {
+    fn usage(&self) -> Option<TokenUsage>;
+}
+
+impl<T> ReportsUsage for Completion<T> {
+    fn usage(&self) -> Option<TokenUsage> {
+        self.metadata.usage
+    }
+}
+
+impl ReportsUsage for ProviderStreamHandler {
+    fn usage(&self) -> Option<TokenUsage> {
+        None
+    }
+}
+
+/// OpenAi and Azure send `x-request-id`, Anthropic sends `request-id`
+fn request_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    ["x-request-id", "request-id"]
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
 }
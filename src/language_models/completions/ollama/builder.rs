@@ -0,0 +1,278 @@
+use super::requests::{OllamaIoRequest, OllamaResponse};
+use crate::agents::memory::{ImageSource, Message, MessageRole, MessageStack};
+use crate::language_models::completions::{
+    error::{CompletionError, CompletionResult},
+    functions::{Function, ToolCall, ToolChoice, ToolCompletion},
+    inference::{CompletionRequest, CompletionRequestBuilder},
+    metadata::CompletionMetadata,
+    ModelParameters,
+};
+use reqwest::header::HeaderMap;
+use serde::{de::Error, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+/// Where Ollama listens when run locally with its default configuration
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Model served through Ollama's native API. `base_url` is everything before `/api/chat`, e.g.
+/// `http://localhost:11434`. `model` is the name of any model that has been pulled, e.g. `llama3`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OllamaCompletionModel {
+    pub base_url: String,
+    pub model: String,
+}
+
+impl Default for OllamaCompletionModel {
+    fn default() -> Self {
+        Self::new(DEFAULT_OLLAMA_URL, "llama3")
+    }
+}
+
+impl OllamaCompletionModel {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            model: model.to_owned(),
+        }
+    }
+}
+
+fn serialize_message(message: &Message) -> Value {
+    let content = message.content.to_owned();
+    if let MessageRole::Tool { .. } = &message.role {
+        return json!({"role": "tool", "content": content});
+    }
+    let mut json = json!({"role": message.role.actual().to_string(), "content": content});
+    if !message.images.is_empty() {
+        // Ollama only accepts images as base64 encoded strings
+        let images: Vec<&str> = message
+            .images
+            .iter()
+            .filter_map(|image| match &image.source {
+                ImageSource::Base64(data) => Some(data.as_str()),
+                ImageSource::Url(url) => {
+                    warn!("Ollama does not support image urls, skipping: {}", url);
+                    None
+                }
+            })
+            .collect();
+        json["images"] = json!(images);
+    }
+    if !message.tool_calls.is_empty() {
+        let calls: Vec<Value> = message
+            .tool_calls
+            .iter()
+            .map(|call| json!({"function": {"name": call.name, "arguments": call.arguments}}))
+            .collect();
+        json["tool_calls"] = calls.into();
+    }
+    json
+}
+
+pub(super) fn serialize_ollama_messages(stack: &MessageStack) -> Value {
+    stack
+        .as_ref()
+        .iter()
+        .map(serialize_message)
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+impl CompletionRequestBuilder for OllamaCompletionModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+
+    fn url_str(&self) -> String {
+        format!("{}/api/chat", self.base_url)
+    }
+
+    fn headers(&self, _api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        serialize_ollama_messages(stack)
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OllamaIoRequest::new(
+            stack,
+            params,
+            self.model_str(),
+            false,
+        )))
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(OllamaIoRequest::new(
+            stack,
+            params,
+            self.model_str(),
+            true,
+        )))
+    }
+
+    /// Ollama has no `tool_choice`, so only `ToolChoice::None` changes the request, by leaving
+    /// the tools out entirely
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        let mut req = OllamaIoRequest::new(stack, params, self.model_str(), false).as_json()?;
+        if choice != &ToolChoice::None {
+            let tools: Vec<Value> = functions
+                .iter()
+                .map(|f| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": f.name,
+                            "description": f.description,
+                            "parameters": f.params_json_schema(),
+                        }
+                    })
+                })
+                .collect();
+            req["tools"] = tools.into();
+        }
+        Ok(req)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        let message = match OllamaResponse::deserialize(&response_json)? {
+            OllamaResponse::Success(suc) => suc.message,
+            OllamaResponse::Err { .. } => {
+                return Err(CompletionError::from_provider(
+                    None,
+                    None,
+                    response_json.to_string(),
+                ))
+            }
+        };
+        match message.tool_calls {
+            Some(Value::Array(tool_calls)) if !tool_calls.is_empty() => {
+                let mut calls = vec![];
+                for call in tool_calls.iter() {
+                    let function = call
+                        .get("function")
+                        .ok_or(serde_json::Error::missing_field("function"))?;
+                    let name = function
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .ok_or(serde_json::Error::missing_field("name"))?
+                        .to_owned();
+                    let arguments = function
+                        .get("arguments")
+                        .ok_or(serde_json::Error::missing_field("arguments"))?
+                        .to_owned();
+                    // Ollama doesn't give tool calls ids
+                    calls.push(ToolCall {
+                        id: format!("call_{}", uuid::Uuid::new_v4()),
+                        name,
+                        arguments,
+                    });
+                }
+                Ok(ToolCompletion::Calls(calls))
+            }
+            _ => Ok(ToolCompletion::Message(message.content)),
+        }
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        match serde_json::from_value::<OllamaResponse>(response_json.to_owned()) {
+            Ok(OllamaResponse::Success(suc)) => suc.metadata(),
+            _ => CompletionMetadata::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OllamaCompletionModel;
+    use crate::agents::memory::{Message, MessageImage, MessageStack};
+    use crate::language_models::completions::{
+        functions::{ToolCall, ToolCompletion},
+        inference::CompletionRequestBuilder,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn messages_serialized_correctly() {
+        let call = ToolCall {
+            id: "call_abc".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        };
+        let mut stack = MessageStack::new("You are a weather bot");
+        stack.push(Message::new_user_with_images(
+            "What is this?",
+            vec![MessageImage::from_bytes(b"png", "image/png")],
+        ));
+        stack.push(Message::new_tool_calls("", vec![call.clone()]));
+        stack.push(Message::new_tool_result(&call, "sunny"));
+
+        let vals = OllamaCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "system", "content": "You are a weather bot"},
+            {"role": "user", "content": "What is this?", "images": ["cG5n"]},
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "function": {
+                        "name": "get_current_weather",
+                        "arguments": {"location": "Detroit, MI"}
+                    }
+                }]
+            },
+            {"role": "tool", "content": "sunny"}
+        ]);
+        assert_eq!(vals, expected);
+    }
+
+    #[test]
+    fn tools_response_processed_correctly() {
+        let model = OllamaCompletionModel::default();
+        let response = json!({
+            "model": "llama3.1",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "function": {
+                        "name": "get_current_weather",
+                        "arguments": {"location": "Detroit, MI"}
+                    }
+                }]
+            },
+            "done": true
+        });
+        match model.process_tools_response(response).unwrap() {
+            ToolCompletion::Calls(calls) => {
+                assert_eq!(calls.len(), 1);
+                assert_eq!(calls[0].name, "get_current_weather");
+                assert_eq!(calls[0].arguments, json!({"location": "Detroit, MI"}));
+            }
+            ToolCompletion::Message(m) => panic!("Expected tool calls, got message: {m}"),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use super::{builder::serialize_ollama_messages, streaming::OllamaStreamResponse};
+use crate::{
+    agents::memory::MessageStack,
+    language_models::completions::{
+        error::{CompletionError, CompletionResult},
+        inference::{CompletionRequest, CompletionResponse, ProcessResponseReturn},
+        metadata::{Completion, CompletionMetadata, FinishReason, TokenUsage},
+        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
+        ModelParameters, ResponseFormat,
+    },
+};
+use futures::TryStreamExt;
+use reqwest::Response;
+use reqwest_streams::JsonStreamResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct OllamaIoRequest {
+    pub model: String,
+    pub messages: Value,
+    pub stream: bool,
+    pub options: OllamaOptions,
+    /// Either `"json"` or a JSON schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Ollama takes sampling parameters in a separate `options` object
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl OllamaIoRequest {
+    pub fn new(stack: &MessageStack, params: &ModelParameters, model: &str, stream: bool) -> Self {
+        let format = match &params.response_format {
+            Some(ResponseFormat::JsonObject) => Some(json!("json")),
+            Some(format @ ResponseFormat::JsonSchema { .. }) => format.schema().cloned(),
+            Some(ResponseFormat::Text) | None => None,
+        };
+        OllamaIoRequest {
+            model: model.to_string(),
+            messages: serialize_ollama_messages(stack),
+            stream,
+            options: OllamaOptions {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                top_k: params.top_k,
+                frequency_penalty: params.frequency_penalty,
+                presence_penalty: params.presence_penalty,
+                num_predict: params.max_tokens,
+                stop: params.stop.clone(),
+                seed: params.seed,
+            },
+            format,
+            extra: params.extra.clone(),
+        }
+    }
+}
+
+impl CompletionRequest for OllamaIoRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn process_response(&self, response: Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            match self.stream {
+                false => {
+                    let json: Value = response.json().await?;
+                    tracing::debug!("Got response: {json:#?}");
+                    let response = OllamaResponse::deserialize(&json)?;
+                    match response {
+                        OllamaResponse::Success(suc) => {
+                            let metadata = suc.metadata();
+                            Ok(CompletionResponse::from(Completion::new(
+                                suc.message.content,
+                                metadata,
+                            )))
+                        }
+                        OllamaResponse::Err { .. } => {
+                            Err(CompletionError::from_provider(None, None, json.to_string()))
+                        }
+                    }
+                }
+                true => {
+                    let response_stream: CompletionStream = Box::new(
+                        response
+                            .json_nl_stream::<Value>(1024 * 64)
+                            .map_err(|err| err.into()),
+                    );
+                    let handler: ProviderStreamHandler =
+                        StreamedCompletionHandler::<OllamaStreamResponse>::from(response_stream)
+                            .into();
+                    Ok(handler.into())
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum OllamaResponse {
+    Success(OllamaSuccess),
+    Err { error: String },
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OllamaSuccess {
+    pub model: String,
+    pub message: OllamaMessage,
+    pub done: bool,
+    pub done_reason: Option<String>,
+    pub prompt_eval_count: Option<u32>,
+    pub eval_count: Option<u32>,
+}
+
+impl OllamaSuccess {
+    pub(super) fn metadata(&self) -> CompletionMetadata {
+        ollama_metadata(
+            Some(&self.model),
+            self.done_reason.as_deref(),
+            self.prompt_eval_count,
+            self.eval_count,
+        )
+    }
+}
+
+/// Ollama doesn't give completions ids
+pub(super) fn ollama_metadata(
+    model: Option<&str>,
+    done_reason: Option<&str>,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+) -> CompletionMetadata {
+    let usage = match (prompt_eval_count, eval_count) {
+        (None, None) => None,
+        (prompt, completion) => Some(TokenUsage::new(
+            prompt.unwrap_or_default(),
+            completion.unwrap_or_default(),
+        )),
+    };
+    CompletionMetadata {
+        model: model.map(String::from),
+        id: None,
+        request_id: None,
+        usage,
+        finish_reason: done_reason.map(FinishReason::from),
+        fallback_index: None,
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct OllamaMessage {
+    pub role: String,
+    pub content: String,
+    pub tool_calls: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ollama_response_parsed_correctly() {
+        let value = json!({
+            "model": "llama3",
+            "created_at": "2024-05-01T18:39:00.000Z",
+            "message": {"role": "assistant", "content": "Hello there!"},
+            "done": true,
+            "done_reason": "stop",
+            "total_duration": 5191566416u64,
+            "prompt_eval_count": 26,
+            "eval_count": 4
+        });
+        let res: OllamaResponse = serde_json::from_value(value).unwrap();
+        let expected = OllamaResponse::Success(OllamaSuccess {
+            model: "llama3".to_owned(),
+            message: OllamaMessage {
+                role: "assistant".to_owned(),
+                content: "Hello there!".to_owned(),
+                tool_calls: None,
+            },
+            done: true,
+            done_reason: Some("stop".to_owned()),
+            prompt_eval_count: Some(26),
+            eval_count: Some(4),
+        });
+        assert_eq!(res, expected);
+
+        let value = json!({"error": "model 'llama9' not found, try pulling it first"});
+        let res: OllamaResponse = serde_json::from_value(value).unwrap();
+        let expected = OllamaResponse::Err {
+            error: "model 'llama9' not found, try pulling it first".to_owned(),
+        };
+        assert_eq!(res, expected);
+    }
+}
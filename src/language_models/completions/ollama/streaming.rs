@@ -0,0 +1,47 @@
+use super::requests::ollama_metadata;
+use crate::language_models::completions::{
+    metadata::CompletionMetadata,
+    streaming::{CompletionStreamStatus, StreamResponse},
+};
+use serde::Deserialize;
+
+impl StreamResponse for OllamaStreamResponse {
+    fn metadata(&self) -> Option<CompletionMetadata> {
+        self.done.then(|| {
+            ollama_metadata(
+                self.model.as_deref(),
+                self.done_reason.as_deref(),
+                self.prompt_eval_count,
+                self.eval_count,
+            )
+        })
+    }
+}
+
+/// Ollama streams one JSON object per line rather than using server sent events. The last one has
+/// `done` set to true
+#[derive(Debug, Deserialize, Clone)]
+pub struct OllamaStreamResponse {
+    pub model: Option<String>,
+    pub message: OllamaStreamMessage,
+    pub done: bool,
+    /// The rest are only sent with the last chunk
+    pub done_reason: Option<String>,
+    pub prompt_eval_count: Option<u32>,
+    pub eval_count: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OllamaStreamMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl From<OllamaStreamResponse> for CompletionStreamStatus {
+    fn from(value: OllamaStreamResponse) -> Self {
+        match value.done {
+            true => CompletionStreamStatus::Finished,
+            false => CompletionStreamStatus::Working(value.message.content),
+        }
+    }
+}
@@ -1,15 +1,17 @@
-use std::collections::HashMap;
-
 use super::{
     super::inference::{CompletionRequest, CompletionRequestBuilder},
-    requests::OpenAiIoRequest,
+    requests::{openai_response_metadata, OpenAiIoRequest},
+    streaming::OpenAiStreamResponse,
 };
+use crate::agents::memory::{Message, MessageRole, MessageStack};
 use crate::language_models::completions::{
-    error::{CompletionError, CompletionResult},
-    functions::{FunctionParam, ParamType},
+    error::CompletionResult,
+    functions::{Function, ToolCall, ToolChoice, ToolCompletion},
+    metadata::CompletionMetadata,
+    streaming::sse::SseCompletionRequest,
     ModelParameters,
 };
-use anyhow::anyhow;
+use crate::language_models::pricing::{default_pricing, ModelPricing};
 use reqwest::header::HeaderMap;
 use serde::{de::Error, Deserialize, Serialize};
 use serde_json::{json, Map, Value};
@@ -25,41 +27,177 @@ pub enum OpenAiCompletionModel {
 const GPT3_MODEL_STR: &str = "gpt-3.5-turbo-0125";
 const GPT4_MODEL_STR: &str = "gpt-4-0125-preview";
 
-impl OpenAiCompletionModel {
-    fn serialize_function_params(params: HashMap<String, FunctionParam>) -> Value {
-        let mut all_params = Map::new();
-        let mut req = vec![];
-        for (name, param) in params.iter() {
-            let mut current_param = Map::new();
-            match &param.typ {
-                ParamType::String => {
-                    current_param.insert("type".to_owned(), "string".to_owned().into());
-                }
-                ParamType::Bool => {
-                    current_param.insert("type".to_owned(), "boolean".to_owned().into());
-                }
-                ParamType::Integer => {
-                    current_param.insert("type".to_owned(), "integer".to_owned().into());
-                }
-                ParamType::Enum(variants) => {
-                    current_param.insert("type".to_owned(), "string".to_owned().into());
-                    current_param.insert("enum".to_owned(), json!(variants));
-                }
-            }
+fn serialize_function_def(function: &Function) -> Value {
+    let mut func_map = Map::new();
+    let params = function.params_json_schema();
+    func_map.insert("name".to_owned(), function.name.clone().into());
+    func_map.insert(
+        "description".to_owned(),
+        function.description.clone().into(),
+    );
+    func_map.insert("parameters".to_owned(), json!(params));
+    let func: Value = func_map.into();
+    info!("function serialized: {:?}", func);
+    func
+}
 
-            if let Some(desc) = &param.description {
-                current_param.insert("description".to_owned(), desc.to_owned().into());
-            }
-            all_params.insert(name.to_owned(), json!(current_param));
-            if param.required {
-                req.push(name);
-            }
+fn serialize_message(message: Message) -> Value {
+    if let MessageRole::Tool { tool_call_id, .. } = &message.role {
+        return json!({
+            "role": "tool",
+            "tool_call_id": tool_call_id,
+            "content": message.content,
+        });
+    }
+    if !message.images.is_empty() {
+        let mut content = vec![];
+        if !message.content.is_empty() {
+            content.push(json!({"type": "text", "text": message.content}));
+        }
+        for image in message.images.iter() {
+            content.push(json!({"type": "image_url", "image_url": {"url": image.url()}}));
         }
-        json!({
-            "type": "object",
-            "properties": all_params,
-            "required": json!(req),
+        return json!({"role": message.role.actual().to_string(), "content": content});
+    }
+    if message.tool_calls.is_empty() {
+        return message.into();
+    }
+    let tool_calls: Vec<Value> = message
+        .tool_calls
+        .iter()
+        .map(|call| {
+            json!({
+                "id": call.id,
+                "type": "function",
+                "function": {"name": call.name, "arguments": call.arguments.to_string()},
+            })
         })
+        .collect();
+    let content = match message.content.is_empty() {
+        true => Value::Null,
+        false => message.content.into(),
+    };
+    json!({"role": "assistant", "content": content, "tool_calls": tool_calls})
+}
+
+// The following are shared by every builder which speaks OpenAi's chat completions API
+
+pub(super) fn serialize_openai_messages(stack: &MessageStack) -> Value {
+    stack
+        .as_ref()
+        .to_owned()
+        .into_iter()
+        .map(serialize_message)
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+pub(crate) fn bearer_auth_headers(api_key: &str) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    if !api_key.is_empty() {
+        map.insert(
+            "Authorization",
+            format!("Bearer {}", api_key).parse().unwrap(),
+        );
+    }
+    map.insert("Content-Type", "application/json".parse().unwrap());
+    map
+}
+
+fn openai_request(
+    stack: &MessageStack,
+    params: &ModelParameters,
+    model: &str,
+    stream: bool,
+) -> CompletionResult<Box<dyn CompletionRequest>> {
+    Ok(Box::new(OpenAiIoRequest::new(stack, params, model, stream)))
+}
+
+fn serialize_openai_tools(
+    stack: &MessageStack,
+    params: &ModelParameters,
+    model: &str,
+    functions: &[Function],
+    choice: &ToolChoice,
+    stream: bool,
+) -> CompletionResult<Value> {
+    let tools: Vec<Value> = functions
+        .iter()
+        .map(|f| json!({"type": "function", "function": serialize_function_def(f)}))
+        .collect();
+    let tool_choice = match choice {
+        ToolChoice::Auto => json!("auto"),
+        ToolChoice::None => json!("none"),
+        ToolChoice::Required => json!("required"),
+        ToolChoice::Function(name) => json!({"type": "function", "function": {"name": name}}),
+    };
+    let mut req = OpenAiIoRequest::new(stack, params, model, stream).as_json()?;
+    req["tools"] = tools.into();
+    req["tool_choice"] = tool_choice;
+    Ok(req)
+}
+
+fn openai_tool_stream_request(
+    stack: &MessageStack,
+    params: &ModelParameters,
+    model: &str,
+    functions: &[Function],
+    choice: &ToolChoice,
+) -> CompletionResult<Box<dyn CompletionRequest>> {
+    let json = serialize_openai_tools(stack, params, model, functions, choice, true)?;
+    Ok(Box::new(SseCompletionRequest::<OpenAiStreamResponse>::new(
+        json,
+    )))
+}
+
+pub(super) fn process_openai_tools_response(
+    response_json: Value,
+) -> CompletionResult<ToolCompletion> {
+    let message = response_json
+        .get("choices")
+        .ok_or(serde_json::Error::missing_field("choices"))?[0]
+        .get("message")
+        .ok_or(serde_json::Error::missing_field("message"))?;
+
+    match message.get("tool_calls").and_then(|c| c.as_array()) {
+        Some(tool_calls) if !tool_calls.is_empty() => {
+            let mut calls = vec![];
+            for call in tool_calls {
+                let id = call
+                    .get("id")
+                    .and_then(|i| i.as_str())
+                    .ok_or(serde_json::Error::missing_field("id"))?
+                    .to_owned();
+                let function = call
+                    .get("function")
+                    .ok_or(serde_json::Error::missing_field("function"))?;
+                let name = function
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .ok_or(serde_json::Error::missing_field("name"))?
+                    .to_owned();
+                let arguments = serde_json::from_str::<Value>(
+                    function
+                        .get("arguments")
+                        .and_then(|a| a.as_str())
+                        .ok_or(serde_json::Error::missing_field("arguments"))?,
+                )?;
+                calls.push(ToolCall {
+                    id,
+                    name,
+                    arguments,
+                });
+            }
+            tracing::info!("Tool calls: {:?}", calls);
+            Ok(ToolCompletion::Calls(calls))
+        }
+        _ => {
+            let content = message
+                .get("content")
+                .and_then(|c| c.as_str())
+                .ok_or(serde_json::Error::missing_field("content"))?;
+            Ok(ToolCompletion::Message(content.to_owned()))
+        }
     }
 }
 
@@ -71,172 +209,422 @@ impl CompletionRequestBuilder for OpenAiCompletionModel {
         }
     }
 
-    fn url_str(&self) -> &str {
-        "https://api.openai.com/v1/chat/completions"
+    fn pricing(&self) -> Option<ModelPricing> {
+        default_pricing(self.model_str())
+    }
+    fn url_str(&self) -> String {
+        "https://api.openai.com/v1/chat/completions".to_owned()
     }
 
     fn headers(&self, api_key: &str) -> HeaderMap {
-        let mut map = HeaderMap::new();
-        map.insert(
-            "Authorization",
-            format!("Bearer {}", api_key).parse().unwrap(),
-        );
-        map.insert("Content-Type", "application/json".parse().unwrap());
-        map
+        bearer_auth_headers(api_key)
     }
 
-    fn serialize_messages(&self, stack: &crate::agents::memory::MessageStack) -> Value {
-        stack
-            .as_ref()
-            .to_owned()
-            .into_iter()
-            .map(|m| m.into())
-            .collect::<Vec<Value>>()
-            .into()
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        serialize_openai_messages(stack)
     }
 
     fn into_io_req(
         &self,
-        stack: &crate::agents::memory::MessageStack,
+        stack: &MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
-        Ok(Box::new(OpenAiIoRequest::new(stack, params, *self, false)))
+        openai_request(stack, params, self.model_str(), false)
     }
+
+    fn supports_choices(&self) -> bool {
+        true
+    }
+
     fn into_stream_req(
         &self,
-        stack: &crate::agents::memory::MessageStack,
+        stack: &MessageStack,
         params: &ModelParameters,
     ) -> CompletionResult<Box<dyn CompletionRequest>> {
-        Ok(Box::new(OpenAiIoRequest::new(stack, params, *self, true)))
+        openai_request(stack, params, self.model_str(), true)
     }
-    fn serialize_function(
+
+    fn serialize_tools(
         &self,
-        stack: &crate::prelude::MessageStack,
-        function: crate::language_models::completions::functions::Function,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
     ) -> CompletionResult<Value> {
-        let mut func_map = Map::new();
-        let params = Self::serialize_function_params(function.params);
-        func_map.insert("name".to_owned(), function.name.clone().into());
-        func_map.insert("description".to_owned(), function.description.into());
-        func_map.insert("parameters".to_owned(), json!(params));
-        let func: Value = func_map.into();
-        info!("function serialized: {:?}", func);
-
-        Ok(json!({
-            "model": self.model_str(),
-            "messages": self.serialize_messages(stack),
-            "functions": [func],
-            "function_call": {"name": function.name}
-        }))
-    }
-
-    fn process_function_response(&self, response_json: Value) -> CompletionResult<Value> {
-        let fn_call = response_json
-            .get("choices")
-            .ok_or(serde_json::Error::missing_field("choices"))?[0]
-            .get("message")
-            .ok_or(serde_json::Error::missing_field("message"))?
-            .get("function_call")
-            .ok_or(serde_json::Error::missing_field("function_call"))?;
-
-        let args_json = serde_json::from_str::<Value>(
-            fn_call
-                .get("arguments")
-                .ok_or(serde_json::Error::missing_field("arguments"))?
-                .as_str()
-                .expect("why did args fail to be coerced to str?"),
-        )?;
-
-        tracing::info!("Args json: {:?}", args_json);
-        let mut args_output: Value = json!({});
-        if let Some(arguments) = args_json.as_object() {
-            for (key, value) in arguments.iter() {
-                args_output
-                    .as_object_mut()
-                    .ok_or(CompletionError::from(anyhow!(
-                        "failed to get args output as mutable array"
-                    )))?
-                    .insert(key.to_string(), value.clone());
-            }
+        serialize_openai_tools(stack, params, self.model_str(), functions, choice, false)
+    }
+
+    fn into_tool_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_tool_stream_request(stack, params, self.model_str(), functions, choice)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        process_openai_tools_response(response_json)
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        openai_response_metadata(response_json)
+    }
+}
+
+/// Any server that implements OpenAi's chat completions API, such as vLLM, llama.cpp server or
+/// LM Studio. `base_url` is everything before `/chat/completions`, e.g. `http://localhost:8000/v1`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OpenAiCompatibleModel {
+    pub base_url: String,
+    pub model: String,
+    /// Whether the server enforces `ResponseFormat::JsonSchema`, typed completions are made
+    /// with forced function calls otherwise
+    #[serde(default)]
+    pub structured_outputs: bool,
+}
+
+impl OpenAiCompatibleModel {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            model: model.to_owned(),
+            structured_outputs: false,
         }
-        tracing::info!("Args output: {:?}", args_output);
-        Ok(args_output)
+    }
+
+    /// Mark the server as supporting OpenAi's structured outputs
+    pub fn with_structured_outputs(mut self) -> Self {
+        self.structured_outputs = true;
+        self
     }
 }
 
-mod tests {
-    use std::collections::HashMap;
+impl CompletionRequestBuilder for OpenAiCompatibleModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
 
-    use once_cell::sync::Lazy;
+    fn url_str(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    /// Authorization header is only sent if `api_key` isn't empty
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        bearer_auth_headers(api_key)
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        serialize_openai_messages(stack)
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_request(stack, params, self.model_str(), false)
+    }
+
+    fn supports_choices(&self) -> bool {
+        true
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        self.structured_outputs
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_request(stack, params, self.model_str(), true)
+    }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        serialize_openai_tools(stack, params, self.model_str(), functions, choice, false)
+    }
+
+    fn into_tool_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_tool_stream_request(stack, params, self.model_str(), functions, choice)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        process_openai_tools_response(response_json)
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        openai_response_metadata(response_json)
+    }
+}
+
+/// Deployment of an OpenAi model on Azure. Requests go to
+/// `https://{resource}.openai.azure.com/openai/deployments/{deployment}` and authenticate with an
+/// `api-key` header rather than a bearer token
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AzureOpenAiModel {
+    pub resource: String,
+    pub deployment: String,
+    pub api_version: String,
+    /// Whether the deployment enforces `ResponseFormat::JsonSchema`, typed completions are made
+    /// with forced function calls otherwise
+    #[serde(default)]
+    pub structured_outputs: bool,
+}
+
+impl AzureOpenAiModel {
+    pub fn new(resource: &str, deployment: &str, api_version: &str) -> Self {
+        Self {
+            resource: resource.to_owned(),
+            deployment: deployment.to_owned(),
+            api_version: api_version.to_owned(),
+            structured_outputs: false,
+        }
+    }
+
+    /// Mark the deployment as supporting OpenAi's structured outputs
+    pub fn with_structured_outputs(mut self) -> Self {
+        self.structured_outputs = true;
+        self
+    }
+}
+
+pub(crate) fn azure_deployment_url(
+    resource: &str,
+    deployment: &str,
+    api_version: &str,
+    endpoint: &str,
+) -> String {
+    format!(
+        "https://{}.openai.azure.com/openai/deployments/{}/{}?api-version={}",
+        resource, deployment, endpoint, api_version
+    )
+}
+
+pub(crate) fn azure_auth_headers(api_key: &str) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    map.insert("api-key", api_key.parse().unwrap());
+    map.insert("Content-Type", "application/json".parse().unwrap());
+    map
+}
+
+impl CompletionRequestBuilder for AzureOpenAiModel {
+    /// Azure picks the model by deployment, so the deployment name stands in for it
+    fn model_str(&self) -> &str {
+        &self.deployment
+    }
+
+    fn url_str(&self) -> String {
+        azure_deployment_url(
+            &self.resource,
+            &self.deployment,
+            &self.api_version,
+            "chat/completions",
+        )
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        azure_auth_headers(api_key)
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        serialize_openai_messages(stack)
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_request(stack, params, self.model_str(), false)
+    }
+
+    fn supports_choices(&self) -> bool {
+        true
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        self.structured_outputs
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_request(stack, params, self.model_str(), true)
+    }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        serialize_openai_tools(stack, params, self.model_str(), functions, choice, false)
+    }
+
+    fn into_tool_stream_req(
+        &self,
+        stack: &MessageStack,
+        params: &ModelParameters,
+        functions: &[Function],
+        choice: &ToolChoice,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        openai_tool_stream_request(stack, params, self.model_str(), functions, choice)
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        process_openai_tools_response(response_json)
+    }
+
+    fn process_metadata(&self, response_json: &Value) -> CompletionMetadata {
+        openai_response_metadata(response_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use serde_json::json;
 
-    use crate::{
-        language_models::completions::{
-            functions::{FunctionParam, ParamType},
-            openai::builder::OpenAiCompletionModel,
-        },
-        telemetry::{get_subscriber, init_subscriber},
+    use crate::agents::memory::{Message, MessageImage, MessageStack};
+    use crate::language_models::completions::{
+        functions::{ToolCall, ToolCompletion},
+        inference::CompletionRequestBuilder,
+        openai::builder::{AzureOpenAiModel, OpenAiCompletionModel},
     };
 
     #[test]
-    fn correctly_serialize_params() {
-        let mut params = HashMap::new();
-        params.insert(
-            String::from("location"),
-            FunctionParam {
-                required: false,
-                typ: ParamType::String,
-                description: Some("the city and state, e.g. san francisco, ca".to_owned()),
+    fn tools_response_processed_correctly() {
+        let model = OpenAiCompletionModel::default();
+        let call = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_abc",
+                            "type": "function",
+                            "function": {
+                                "name": "get_current_weather",
+                                "arguments": "{\"location\": \"Detroit, MI\"}"
+                            }
+                        },
+                        {
+                            "id": "call_def",
+                            "type": "function",
+                            "function": {
+                                "name": "get_current_weather",
+                                "arguments": "{\"location\": \"Chicago, IL\"}"
+                            }
+                        }
+                    ]
+                }
+            }]
+        });
+        let expected = ToolCompletion::Calls(vec![
+            ToolCall {
+                id: "call_abc".to_owned(),
+                name: "get_current_weather".to_owned(),
+                arguments: json!({"location": "Detroit, MI"}),
             },
-        );
-        params.insert(
-            String::from("format"),
-            FunctionParam {
-                required: true,
-                typ: ParamType::Enum(vec![String::from("celcius"), String::from("fahrenheight")]),
-                description: None,
+            ToolCall {
+                id: "call_def".to_owned(),
+                name: "get_current_weather".to_owned(),
+                arguments: json!({"location": "Chicago, IL"}),
             },
-        );
-        params.insert(
-            String::from("num_days"),
-            FunctionParam {
-                required: true,
-                typ: ParamType::Integer,
-                description: Some("the number of days to forcast".to_owned()),
+        ]);
+        assert_eq!(model.process_tools_response(call).unwrap(), expected);
+
+        let message = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "It's sunny in Detroit"
+                }
+            }]
+        });
+        let expected = ToolCompletion::Message("It's sunny in Detroit".to_owned());
+        assert_eq!(model.process_tools_response(message).unwrap(), expected);
+    }
+
+    #[test]
+    fn tool_messages_serialized_correctly() {
+        let call = ToolCall {
+            id: "call_abc".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        };
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_tool_calls("", vec![call.clone()]));
+        stack.push(Message::new_tool_result(&call, "sunny"));
+
+        let vals = OpenAiCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_abc",
+                    "type": "function",
+                    "function": {
+                        "name": "get_current_weather",
+                        "arguments": "{\"location\":\"Detroit, MI\"}"
+                    }
+                }]
             },
-        );
+            {"role": "tool", "tool_call_id": "call_abc", "content": "sunny"},
+        ]);
+        assert_eq!(vals, expected);
+    }
 
-        let expected = json!({
-                "type": "object",
-                "properties": {
-                  "location": {
-                    "type": "string",
-                    "description": "the city and state, e.g. san francisco, ca"
-                  },
-                    "num_days": {
-                    "type": "integer",
-                    "description": "the number of days to forcast",
-                    },
-                  "format": {
-                    "type": "string",
-                    "enum": ["celcius", "fahrenheight"]
-                  }
-                },
-                "required": ["num_days", "format"]}
-        );
+    #[test]
+    fn image_messages_serialized_as_image_url_parts() {
+        let mut stack = MessageStack::init();
+        stack.push(Message::new_user_with_images(
+            "What are these images?",
+            vec![
+                MessageImage::from_bytes(b"not really a png", "image/png"),
+                MessageImage::from_url("https://example.com/image.jpg", "image/jpeg"),
+            ],
+        ));
 
-        let serialized = OpenAiCompletionModel::serialize_function_params(params);
+        let vals = OpenAiCompletionModel::default().serialize_messages(&stack);
+        let expected = json!([
+            {"role": "user", "content": [
+                {"type": "text", "text": "What are these images?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,bm90IHJlYWxseSBhIHBuZw=="}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/image.jpg"}},
+            ]},
+        ]);
+        assert_eq!(vals, expected);
+    }
 
-        for (k, v) in expected["properties"].as_object().unwrap().into_iter() {
-            assert_eq!(v, &serialized["properties"][k])
-        }
-        for r in expected["required"].as_array().unwrap() {
-            assert!(serialized["required"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .find(|v| *v == r)
-                .is_some())
-        }
+    #[test]
+    fn azure_requests_go_to_deployment() {
+        let model = AzureOpenAiModel::new("my-resource", "gpt-4o-prod", "2024-06-01");
+        assert_eq!(
+            model.url_str(),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-06-01"
+        );
+        let headers = model.headers("azure-key");
+        assert_eq!(headers.get("api-key").unwrap(), "azure-key");
+        assert!(headers.get("Authorization").is_none());
     }
 }
@@ -1,53 +1,72 @@
 use super::{
-    super::inference::CompletionRequest, builder::OpenAiCompletionModel,
+    super::inference::CompletionRequest, builder::serialize_openai_messages,
     streaming::OpenAiStreamResponse,
 };
 use crate::{
     agents::memory::MessageStack,
     language_models::completions::{
         error::{CompletionError, CompletionResult, ProviderResponseError},
-        inference::{CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn},
-        streaming::{CompletionStream, ProviderStreamHandler, StreamedCompletionHandler},
-        ModelParameters,
+        inference::{CompletionResponse, ProcessResponseReturn},
+        metadata::{Completion, CompletionMetadata, FinishReason, TokenUsage},
+        streaming::{sse::sse_json_stream, ProviderStreamHandler, StreamedCompletionHandler},
+        ModelParameters, ResponseFormat,
     },
 };
 use anyhow::anyhow;
-use futures::TryStreamExt;
 use reqwest::Response;
-use reqwest_streams::JsonStreamResponse;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::time::Duration;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use tracing::info;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct OpenAiIoRequest {
     pub model: String,
     pub messages: Value,
-    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
     pub max_tokens: u32,
     pub stream: bool,
     pub n: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// Asks for usage to be sent in a final chunk when streaming
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl OpenAiIoRequest {
-    pub fn new(
-        stack: &MessageStack,
-        params: &ModelParameters,
-        typ: OpenAiCompletionModel,
-        stream: bool,
-    ) -> Self {
-        let temperature = match params.temperature().ok() {
-            Some(t) => t,
-            None => 0.7,
-        };
+    pub fn new(stack: &MessageStack, params: &ModelParameters, model: &str, stream: bool) -> Self {
         OpenAiIoRequest {
-            model: typ.model_str().to_string(),
-            messages: CompletionRequestBuilder::serialize_messages(&typ, stack),
-            temperature,
+            model: model.to_string(),
+            messages: serialize_openai_messages(stack),
+            temperature: params.temperature,
+            top_p: params.top_p,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
             stream,
             max_tokens: params.max_tokens.unwrap_or(1000),
             n: params.n.unwrap_or(1),
+            stop: params.stop.clone(),
+            seed: params.seed,
+            logit_bias: params.logit_bias.clone(),
+            response_format: params.response_format.clone(),
+            stream_options: stream.then(|| json!({"include_usage": true})),
+            extra: params.extra.clone(),
         }
     }
 }
@@ -63,27 +82,33 @@ impl CompletionRequest for OpenAiIoRequest {
                 false => {
                     let json = response.json().await?;
                     tracing::warn!("got response:  {json:#?}");
-                    let response: OpenAiResponse = serde_json::from_value(json)?;
+                    let metadata = openai_response_metadata(&json);
+                    let response = OpenAiResponse::deserialize(&json)?;
                     return match response {
+                        OpenAiResponse::Success(suc) if self.n > 1 => {
+                            let choices: Vec<String> = suc
+                                .choices
+                                .into_iter()
+                                .filter_map(|c| c.message.content)
+                                .collect();
+                            if choices.is_empty() {
+                                return Err(anyhow!("No content in any choice").into());
+                            }
+                            Ok(CompletionResponse::Choices(Completion::new(
+                                choices, metadata,
+                            )))
+                        }
                         OpenAiResponse::Success(mut suc) => {
                             let content = suc.choices.remove(0).message.content.ok_or(
                                 CompletionError::from(anyhow!("No content in success message")),
                             )?;
-                            Ok(CompletionResponse::from(content))
+                            Ok(CompletionResponse::from(Completion::new(content, metadata)))
                         }
-                        OpenAiResponse::Err { error } => Err(error.into_error()),
+                        OpenAiResponse::Err { error } => Err(error.into_error(&json)),
                     };
                 }
                 true => {
-                    let response_stream: CompletionStream = Box::new(
-                        tokio::time::timeout(Duration::from_secs(10), async {
-                            response
-                                .json_array_stream::<Value>(1024)
-                                .map_err(|err| err.into())
-                        })
-                        .await
-                        .map_err(|_| CompletionError::StreamTimeout)?,
-                    );
+                    let response_stream = sse_json_stream(response.bytes_stream());
                     let handler: ProviderStreamHandler =
                         StreamedCompletionHandler::<OpenAiStreamResponse>::from(response_stream)
                             .into();
@@ -94,6 +119,29 @@ impl CompletionRequest for OpenAiIoRequest {
     }
 }
 
+/// Shared by every model which speaks OpenAi's chat completions API
+pub(crate) fn openai_response_metadata(json: &Value) -> CompletionMetadata {
+    let str_field = |v: &Value, key: &str| v.get(key).and_then(|f| f.as_str()).map(String::from);
+    let usage = json
+        .get("usage")
+        .and_then(|u| serde_json::from_value::<OpenAiUsage>(u.to_owned()).ok())
+        .map(TokenUsage::from);
+    let finish_reason = json
+        .get("choices")
+        .and_then(|c| c.get(0))
+        .and_then(|c| c.get("finish_reason"))
+        .and_then(|f| f.as_str())
+        .map(FinishReason::from);
+    CompletionMetadata {
+        model: str_field(json, "model"),
+        id: str_field(json, "id"),
+        request_id: None,
+        usage,
+        finish_reason,
+        fallback_index: None,
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum OpenAiResponse {
@@ -123,7 +171,7 @@ pub struct Choice {
 pub struct GptMessage {
     pub role: String,
     pub content: Option<String>,
-    pub function_call: Option<Value>,
+    pub tool_calls: Option<Value>,
 }
 
 #[derive(Debug, serde::Deserialize, Clone, PartialEq, Eq)]
@@ -133,9 +181,20 @@ pub struct OpenAiUsage {
     pub total_tokens: i32,
 }
 
+impl From<OpenAiUsage> for TokenUsage {
+    fn from(value: OpenAiUsage) -> Self {
+        TokenUsage::new(
+            value.prompt_tokens.max(0) as u32,
+            value.completion_tokens.unwrap_or(0).max(0) as u32,
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::language_models::completions::functions::{Function, FunctionParam, ParamType};
+
     #[test]
     fn openai_response_parsed_correctly() {
         let value = json!(
@@ -162,6 +221,17 @@ mod tests {
             ]
         });
 
+        let metadata = openai_response_metadata(&value);
+        let expected_metadata = CompletionMetadata {
+            model: Some("gpt-3.5-turbo-0613".to_owned()),
+            id: Some("chatcmpl-abc123".to_owned()),
+            request_id: None,
+            usage: Some(TokenUsage::new(13, 7)),
+            finish_reason: Some(FinishReason::Stop),
+            fallback_index: None,
+        };
+        assert_eq!(metadata, expected_metadata);
+
         let res: OpenAiResponse = serde_json::from_value(value).unwrap();
         let expected = OpenAiResponse::Success(OpenAiSuccess {
             usage: OpenAiUsage {
@@ -174,11 +244,99 @@ mod tests {
                     message: GptMessage {
                         role: "assistant".to_string(),
                         content: Some("\n\nThis is a test!".to_string()),
-                        function_call: None,
+                        tool_calls: None,
                     },
                 }
             }],
         });
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn model_parameters_mapped_into_request() {
+        let mut params = ModelParameters {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            top_k: Some(40),
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-0.5),
+            stop: Some(vec!["\n\n".to_owned()]),
+            seed: Some(42),
+            logit_bias: Some([(50256, -100)].into_iter().collect()),
+            response_format: Some(ResponseFormat::JsonObject),
+            ..Default::default()
+        };
+        params.extra.insert("user".to_owned(), json!("user-1234"));
+        let stack = MessageStack::new("SYSTEM");
+        let req = OpenAiIoRequest::new(&stack, &params, "gpt-4o", false)
+            .as_json()
+            .unwrap();
+
+        assert_eq!(req["temperature"], json!(0.7f32));
+        assert_eq!(req["top_p"], json!(0.9f32));
+        assert_eq!(req["frequency_penalty"], json!(0.5));
+        assert_eq!(req["presence_penalty"], json!(-0.5));
+        assert_eq!(req["stop"], json!(["\n\n"]));
+        assert_eq!(req["seed"], json!(42));
+        assert_eq!(req["logit_bias"], json!({"50256": -100}));
+        assert_eq!(req["response_format"], json!({"type": "json_object"}));
+        assert_eq!(req["user"], json!("user-1234"));
+        assert!(req.get("top_k").is_none());
+    }
+
+    #[test]
+    fn json_schema_format_built_from_function() {
+        let function = Function {
+            name: "person".to_owned(),
+            description: "A person mentioned in the text".to_owned(),
+            params: [
+                (
+                    "name".to_owned(),
+                    FunctionParam {
+                        description: Some("Their full name".to_owned()),
+                        typ: ParamType::String,
+                        required: true,
+                    },
+                ),
+                (
+                    "pronouns".to_owned(),
+                    FunctionParam {
+                        description: None,
+                        typ: ParamType::Enum(vec!["she".to_owned(), "they".to_owned()]),
+                        required: false,
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let params = ModelParameters {
+            response_format: Some(ResponseFormat::json_schema(&function)),
+            ..Default::default()
+        };
+        let req = OpenAiIoRequest::new(&MessageStack::init(), &params, "gpt-4o", false)
+            .as_json()
+            .unwrap();
+
+        let format = &req["response_format"];
+        assert_eq!(format["type"], json!("json_schema"));
+        assert_eq!(format["json_schema"]["name"], json!("person"));
+        assert_eq!(
+            format["json_schema"]["description"],
+            json!("A person mentioned in the text")
+        );
+        assert_eq!(format["json_schema"]["strict"], json!(true));
+        assert_eq!(
+            format["json_schema"]["schema"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string", "description": "Their full name"},
+                    "pronouns": {"type": ["string", "null"], "enum": ["she", "they", null]}
+                },
+                "required": ["name", "pronouns"],
+                "additionalProperties": false
+            })
+        );
+    }
 }
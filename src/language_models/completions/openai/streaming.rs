@@ -1,34 +1,182 @@
-use super::super::streaming::{CompletionStreamStatus, StreamResponse};
+use super::{
+    super::{
+        metadata::{CompletionMetadata, FinishReason, TokenUsage},
+        streaming::{CompletionStreamStatus, StreamResponse, ToolCallChunk},
+    },
+    requests::OpenAiUsage,
+};
 use serde::Deserialize;
 
-impl StreamResponse for OpenAiStreamResponse {}
+impl StreamResponse for OpenAiStreamResponse {
+    fn metadata(&self) -> Option<CompletionMetadata> {
+        Some(CompletionMetadata {
+            model: self.model.to_owned(),
+            id: self.id.to_owned(),
+            request_id: None,
+            usage: self.usage.to_owned().map(TokenUsage::from),
+            finish_reason: self
+                .choices
+                .first()
+                .and_then(|c| c.finish_reason.as_deref())
+                .map(FinishReason::from),
+            fallback_index: None,
+        })
+    }
+
+    /// A call's first chunk carries its id and name, the rest only pieces of its arguments
+    fn tool_call_chunks(&self) -> Vec<ToolCallChunk> {
+        let calls = self
+            .choices
+            .first()
+            .and_then(|c| c.delta.tool_calls.as_deref())
+            .unwrap_or_default();
+        let mut chunks = vec![];
+        for call in calls {
+            if let (Some(id), Some(name)) = (&call.id, &call.function.name) {
+                chunks.push(ToolCallChunk::Start {
+                    index: call.index,
+                    id: id.to_owned(),
+                    name: name.to_owned(),
+                });
+            }
+            match call.function.arguments.as_deref() {
+                Some(fragment) if !fragment.is_empty() => chunks.push(ToolCallChunk::Arguments {
+                    index: call.index,
+                    fragment: fragment.to_owned(),
+                }),
+                _ => {}
+            }
+        }
+        chunks
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct OpenAiStreamResponse {
+    pub id: Option<String>,
+    pub model: Option<String>,
     pub choices: Vec<StreamChoice>,
+    /// Only present on the last chunk, when `stream_options.include_usage` is set
+    pub usage: Option<OpenAiUsage>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct StreamChoice {
     pub delta: StreamDelta,
+    pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct StreamDelta {
     pub role: Option<String>,
     pub content: Option<String>,
+    pub tool_calls: Option<Vec<StreamToolCall>>,
 }
 
-impl Into<CompletionStreamStatus> for OpenAiStreamResponse {
-    fn into(self) -> CompletionStreamStatus {
-        match self.choices[0].delta.content.to_owned() {
+#[derive(Debug, Deserialize, Clone)]
+struct StreamToolCall {
+    index: usize,
+    id: Option<String>,
+    #[serde(default)]
+    function: StreamFunction,
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+struct StreamFunction {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+impl From<OpenAiStreamResponse> for CompletionStreamStatus {
+    fn from(value: OpenAiStreamResponse) -> Self {
+        // The usage chunk comes after the one with `finish_reason` and has no choices. Servers
+        // that don't send it simply end the stream, which also finishes it
+        let choice = match value.choices.first() {
+            Some(choice) => choice,
+            None => return CompletionStreamStatus::Finished,
+        };
+        match choice.delta.content.to_owned() {
             Some(response) => CompletionStreamStatus::Working(
                 response
                     .trim_start_matches('"')
                     .trim_end_matches('"')
                     .to_string(),
             ),
-            None => CompletionStreamStatus::Finished,
+            None => CompletionStreamStatus::Working(String::new()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn usage_chunk_finishes_stream_with_metadata() {
+        let finish_chunk = json!({
+            "id": "chatcmpl-abc123",
+            "model": "gpt-4o-mini",
+            "choices": [{"index": 0, "delta": {}, "finish_reason": "length"}],
+            "usage": null
+        });
+        let chunk: OpenAiStreamResponse = serde_json::from_value(finish_chunk).unwrap();
+        let metadata = chunk.metadata().unwrap();
+        assert_eq!(metadata.finish_reason, Some(FinishReason::Length));
+        assert!(matches!(
+            CompletionStreamStatus::from(chunk),
+            CompletionStreamStatus::Working(t) if t.is_empty()
+        ));
+
+        let usage_chunk = json!({
+            "id": "chatcmpl-abc123",
+            "model": "gpt-4o-mini",
+            "choices": [],
+            "usage": {"prompt_tokens": 9, "completion_tokens": 12, "total_tokens": 21}
+        });
+        let chunk: OpenAiStreamResponse = serde_json::from_value(usage_chunk).unwrap();
+        assert_eq!(
+            chunk.metadata().unwrap().usage,
+            Some(TokenUsage::new(9, 12))
+        );
+        assert!(matches!(
+            CompletionStreamStatus::from(chunk),
+            CompletionStreamStatus::Finished
+        ));
+    }
+
+    #[test]
+    fn tool_call_deltas_become_chunks() {
+        let first = json!({
+            "choices": [{"index": 0, "delta": {"role": "assistant", "content": null, "tool_calls": [
+                {"index": 0, "id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": ""}}
+            ]}, "finish_reason": null}]
+        });
+        let next = json!({
+            "choices": [{"index": 0, "delta": {"tool_calls": [
+                {"index": 0, "function": {"arguments": "{\"loc"}}
+            ]}, "finish_reason": null}]
+        });
+        let chunks: Vec<_> = [first, next]
+            .into_iter()
+            .flat_map(|c| {
+                let chunk: OpenAiStreamResponse = serde_json::from_value(c).unwrap();
+                chunk.tool_call_chunks()
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                ToolCallChunk::Start {
+                    index: 0,
+                    id: "call_1".to_owned(),
+                    name: "get_weather".to_owned()
+                },
+                ToolCallChunk::Arguments {
+                    index: 0,
+                    fragment: "{\"loc".to_owned()
+                },
+            ]
+        );
+    }
+}
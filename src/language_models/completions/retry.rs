@@ -0,0 +1,302 @@
+use super::{
+    error::{CompletionError, CompletionResult},
+    streaming::StreamError,
+};
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Kinds of `CompletionError` worth trying again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetryableError {
+    /// `CompletionError::RateLimited`
+    RateLimited,
+    /// `CompletionError::Overloaded`
+    Overloaded,
+    /// `CompletionError::Server`
+    Server,
+    /// Request timed out or couldn't connect, or a stream broke before its first token
+    Connection,
+}
+
+impl RetryableError {
+    const ALL: [Self; 4] = [
+        Self::RateLimited,
+        Self::Overloaded,
+        Self::Server,
+        Self::Connection,
+    ];
+
+    fn matches(&self, err: &CompletionError) -> bool {
+        match (self, err) {
+            (Self::RateLimited, CompletionError::RateLimited { .. })
+            | (Self::Overloaded, CompletionError::Overloaded { .. })
+            | (Self::Server, CompletionError::Server { .. })
+            | (Self::Connection, CompletionError::StreamTimeout) => true,
+            (Self::Connection, CompletionError::Request(err)) => {
+                err.is_timeout() || err.is_connect()
+            }
+            (_, CompletionError::Stream(err)) => self.matches_stream(err),
+            _ => false,
+        }
+    }
+
+    fn matches_stream(&self, err: &StreamError) -> bool {
+        match err {
+            StreamError::StreamBody(_) | StreamError::IdleTimeout(_) | StreamError::Truncated => {
+                *self == Self::Connection
+            }
+            // An error the provider sent in place of the stream's first chunk
+            StreamError::StreamRecievedErr(json) => self.matches(&CompletionError::from_provider(
+                None,
+                None,
+                json.to_string(),
+            )),
+            StreamError::Provider(err) => self.matches(err),
+            StreamError::RetryError { source, .. } => self.matches_stream(source),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `err` is any kind of `RetryableError`, meaning the provider is down, limiting us or
+/// couldn't be reached
+pub(crate) fn is_transient(err: &CompletionError) -> bool {
+    RetryableError::ALL.iter().any(|kind| kind.matches(err))
+}
+
+/// How a `CompletionModel` retries failed requests. Waits `initial_backoff`, multiplied by
+/// `multiplier` after every attempt up to `max_backoff`, unless the provider said how long to wait
+/// with a `retry-after` header. A `retry-after` longer than `max_backoff` isn't waited out, the
+/// `RateLimited` error is returned instead so a fallback can take over
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts including the first, so 1 never retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of each backoff, between 0 and 1, that is randomly taken off so clients that failed
+    /// together don't all retry together
+    pub jitter: f64,
+    pub retry_on: Vec<RetryableError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_on: RetryableError::ALL.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn retry_on(mut self, kinds: &[RetryableError]) -> Self {
+        self.retry_on = kinds.to_vec();
+        self
+    }
+
+    /// Backoff before the attempt after `attempt`, not counting jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        // `mul_f64` panics on overflow, which enough attempts will get to
+        Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait before trying again after `attempt` failed with `err`, `None` if it
+    /// shouldn't be retried
+    fn delay(&self, err: &CompletionError, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.retry_on.iter().any(|kind| kind.matches(err)) {
+            return None;
+        }
+        if let CompletionError::RateLimited {
+            retry_after: Some(wait),
+            ..
+        } = err
+        {
+            return (*wait <= self.max_backoff).then_some(*wait);
+        }
+        let backoff = self.backoff(attempt);
+        Some(backoff.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random_fraction()))
+    }
+
+    /// Run `attempt` until it succeeds, fails with an error that shouldn't be retried, or
+    /// `max_attempts` is used up. A stream that keeps failing before its first token ends in
+    /// `StreamError::RetryError`
+    pub(crate) async fn run<T, F, Fut>(&self, mut attempt: F) -> CompletionResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = CompletionResult<T>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match attempt().await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+            match self.delay(&err, attempts) {
+                Some(wait) => {
+                    warn!(
+                        "Attempt {} of {} failed, retrying in {:?}: {}",
+                        attempts, self.max_attempts, wait, err
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                None => {
+                    return Err(match err {
+                        CompletionError::Stream(source) if attempts > 1 => {
+                            StreamError::RetryError {
+                                attempts,
+                                source: Box::new(source),
+                            }
+                            .into()
+                        }
+                        err => err,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Random number between 0 and 1, good enough for jitter
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn overloaded() -> CompletionError {
+        CompletionError::Overloaded {
+            body: "overloaded_error".to_owned(),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(350))
+            .with_max_attempts(10)
+            .with_jitter(0.0);
+        let delays: Vec<_> = (1..5).map(|a| policy.delay(&overloaded(), a)).collect();
+        let expected = [100, 200, 350, 350].map(|ms| Some(Duration::from_millis(ms)));
+        assert_eq!(delays, expected);
+
+        let policy = policy.with_max_attempts(u32::MAX);
+        for attempt in [70, 2000, u32::MAX - 1] {
+            assert_eq!(
+                policy.delay(&overloaded(), attempt),
+                Some(Duration::from_millis(350))
+            );
+        }
+
+        let jittered = RetryPolicy::default().delay(&overloaded(), 1).unwrap();
+        assert!(jittered >= Duration::from_millis(250) && jittered <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn retry_after_honoured_and_kinds_respected() {
+        let policy = RetryPolicy::default();
+        let limited = CompletionError::RateLimited {
+            retry_after: Some(Duration::from_secs(7)),
+            body: String::new(),
+        };
+        assert_eq!(policy.delay(&limited, 1), Some(Duration::from_secs(7)));
+        assert_eq!(policy.delay(&limited, 3), None);
+        let limited_for_an_hour = CompletionError::RateLimited {
+            retry_after: Some(Duration::from_secs(3600)),
+            body: String::new(),
+        };
+        assert_eq!(policy.delay(&limited_for_an_hour, 1), None);
+
+        let auth = CompletionError::Authentication {
+            body: String::new(),
+        };
+        assert_eq!(policy.delay(&auth, 1), None);
+
+        let stream_err = CompletionError::Stream(StreamError::StreamRecievedErr(
+            serde_json::json!({"type": "error", "error": {"type": "overloaded_error"}}),
+        ));
+        assert!(policy.delay(&stream_err, 1).is_some());
+        let policy = policy.retry_on(&[RetryableError::RateLimited]);
+        assert_eq!(policy.delay(&stream_err, 1), None);
+    }
+
+    #[tokio::test]
+    async fn run_retries_until_success() {
+        let policy = RetryPolicy::default().with_backoff(Duration::ZERO, Duration::ZERO);
+        let calls = AtomicU32::new(0);
+        let res = policy
+            .run(|| async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(overloaded()),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = AtomicU32::new(0);
+        let res: CompletionResult<()> = policy
+            .run(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(CompletionError::Stream(StreamError::StreamRecievedErr(
+                    serde_json::json!({"error": "server overloaded"}),
+                )))
+            })
+            .await;
+        assert!(matches!(
+            res,
+            Err(CompletionError::Stream(StreamError::RetryError {
+                attempts: 3,
+                ..
+            }))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,7 +1,9 @@
 use reqwest_streams::error::StreamBodyError;
 
 use crate::errors::error_chain_fmt;
+use crate::language_models::completions::error::CompletionError;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::time::Duration;
 
 pub type StreamResult<T> = Result<T, StreamError>;
 #[derive(thiserror::Error)]
@@ -11,8 +13,23 @@ pub enum StreamError {
     Json(#[from] serde_json::Error),
     StreamBody(#[from] StreamBodyError),
     StreamRecievedErr(serde_json::Value),
-    ReceiverTimeout,
-    RetryError,
+    /// Error event the provider sent partway through the stream
+    Provider(Box<CompletionError>),
+    /// Streamed json went wrong at the `offset`th character
+    InvalidJson {
+        offset: usize,
+        found: char,
+    },
+    /// Nothing arrived from the provider for this long
+    IdleTimeout(Duration),
+    /// Stream ended before the provider said the completion was finished, e.g. the connection
+    /// dropped
+    Truncated,
+    /// Stream failed before its first token on every one of `attempts`, `source` is the last failure
+    RetryError {
+        attempts: u32,
+        source: Box<StreamError>,
+    },
 }
 
 impl Debug for StreamError {
@@ -34,8 +51,16 @@ impl Display for StreamError {
             Self::Undefined(err) => err.to_string(),
             Self::StreamBody(err) => err.to_string(),
             Self::StreamRecievedErr(err) => err.to_string(),
-            Self::RetryError => "Retry Error".to_string(),
-            Self::ReceiverTimeout => "Receiver Timeout".to_string(),
+            Self::Provider(err) => err.to_string(),
+            Self::RetryError { attempts, source } => format!(
+                "Stream failed before its first token after {} attempts: {}",
+                attempts, source
+            ),
+            Self::InvalidJson { offset, found } => {
+                format!("Invalid json, found {:?} at character {}", found, offset)
+            }
+            Self::IdleTimeout(timeout) => format!("Stream sent nothing for {:?}", timeout),
+            Self::Truncated => "Stream ended before the completion finished".to_owned(),
         };
         write!(f, "{}", display)
     }
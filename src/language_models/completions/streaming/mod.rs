@@ -1,48 +1,153 @@
 use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time::Duration;
 use tracing::warn;
-use tracing_log::log::info;
 pub mod error;
+pub mod partial_json;
+pub mod sse;
 use crate::agents::memory::Message;
 use crate::agents::Agent;
-use anyhow::anyhow;
+use crate::language_models::rate_limit::RateLimiter;
 pub use error::*;
-use futures::Stream;
+use futures::{Future, Stream};
 use futures_util::StreamExt;
+use partial_json::PartialJson;
 use serde::Deserialize;
 
 use super::{
-    anthropic::streaming::AnthropicStreamResponse, openai::streaming::OpenAiStreamResponse,
+    anthropic::streaming::AnthropicStreamResponse, functions::ToolCall,
+    gemini::streaming::GeminiStreamResponse, metadata::CompletionMetadata,
+    ollama::streaming::OllamaStreamResponse, openai::streaming::OpenAiStreamResponse,
 };
 
-pub(in crate::language_models) type CompletionStream =
-    Box<dyn Stream<Item = StreamResult<Value>> + Send + Unpin>;
+pub type CompletionStream = Box<dyn Stream<Item = StreamResult<Value>> + Send + Unpin>;
 
-pub(in crate::language_models) type CompletionStreamReceiver =
-    tokio::sync::mpsc::Receiver<Result<CompletionStreamStatus, StreamError>>;
-pub(in crate::language_models) type CompletionStreamSender =
-    tokio::sync::mpsc::Sender<Result<CompletionStreamStatus, StreamError>>;
+/// How long a stream may go without sending anything before it's given up on, unless changed
+/// with `ProviderStreamHandler::with_idle_timeout`
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub trait StreamResponse:
     for<'de> Deserialize<'de> + Debug + Into<CompletionStreamStatus> + Clone + Send + Sync + 'static
 {
+    /// Whatever part of the completion's metadata this chunk carries. Chunks are merged in order,
+    /// so later chunks overwrite earlier ones
+    fn metadata(&self) -> Option<CompletionMetadata> {
+        None
+    }
+
+    /// Pieces of tool calls this chunk carries, in order
+    fn tool_call_chunks(&self) -> Vec<ToolCallChunk> {
+        vec![]
+    }
 }
 
-#[derive(Debug)]
-struct CompletionStreamingThread;
+/// Piece of a tool call as a provider streams it. Calls are told apart by `index`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolCallChunk {
+    Start {
+        index: usize,
+        id: String,
+        name: String,
+    },
+    /// Next piece of the call's json arguments
+    Arguments { index: usize, fragment: String },
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CompletionStreamStatus {
     Working(String),
     Finished,
 }
 
+/// Item of a `ProviderStreamHandler` used as a `Stream`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Next piece of the message, never empty
+    Token(String),
+    /// Model started calling a function
+    ToolCallStart {
+        index: usize,
+        id: String,
+        name: String,
+    },
+    /// Next piece of a call's json arguments, as generated
+    ToolCallArguments { index: usize, fragment: String },
+    /// Call is complete, with its arguments parsed. Sent for every call once the stream ends,
+    /// right before `Finished`
+    ToolCall(ToolCall),
+    /// Stream is over, with everything it reported about the completion. Nothing follows it
+    Finished(CompletionMetadata),
+}
+
 #[derive(Debug)]
 pub enum ProviderStreamHandler {
     OpenAi(StreamedCompletionHandler<OpenAiStreamResponse>),
     Anthropic(StreamedCompletionHandler<AnthropicStreamResponse>),
+    Ollama(StreamedCompletionHandler<OllamaStreamResponse>),
+    Gemini(StreamedCompletionHandler<GeminiStreamResponse>),
+    /// Stream of a user defined provider
+    Custom(CustomStreamHandler),
+}
+
+/// Handler of a user defined provider's stream, whatever its `StreamResponse` is
+#[derive(Debug)]
+pub struct CustomStreamHandler(Box<dyn ErasedStreamHandler>);
+
+/// What a `ProviderStreamHandler` needs of a `StreamedCompletionHandler`, without its type
+/// parameter
+trait ErasedStreamHandler: Stream<Item = StreamResult<StreamEvent>> + Debug + Send + Unpin {
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>);
+    fn metadata(&self) -> CompletionMetadata;
+    fn merge_metadata(&self, metadata: CompletionMetadata);
+    fn set_rate_limit(&mut self, limiter: RateLimiter, estimated_tokens: u32);
+    fn message_content(&self) -> &str;
+    fn tool_calls(&self) -> &[ToolCall];
+    fn partial_json(&self) -> Option<Value>;
+    fn partial_tool_arguments(&self, index: usize) -> Option<Value>;
+    fn is_finished(&self) -> bool;
+    fn commit(&mut self, agent: &mut Agent) -> bool;
+    fn check_first_chunk(&mut self) -> Pin<Box<dyn Future<Output = StreamResult<()>> + Send + '_>>;
+}
+
+impl<T: StreamResponse> ErasedStreamHandler for StreamedCompletionHandler<T> {
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        StreamedCompletionHandler::set_idle_timeout(self, timeout)
+    }
+    fn metadata(&self) -> CompletionMetadata {
+        StreamedCompletionHandler::metadata(self)
+    }
+    fn merge_metadata(&self, metadata: CompletionMetadata) {
+        StreamedCompletionHandler::merge_metadata(self, metadata)
+    }
+    fn set_rate_limit(&mut self, limiter: RateLimiter, estimated_tokens: u32) {
+        self.rate_limit = Some((limiter, estimated_tokens));
+    }
+    fn message_content(&self) -> &str {
+        &self.message_content
+    }
+    fn tool_calls(&self) -> &[ToolCall] {
+        &self.tool_calls
+    }
+    fn partial_json(&self) -> Option<Value> {
+        StreamedCompletionHandler::partial_json(self)
+    }
+    fn partial_tool_arguments(&self, index: usize) -> Option<Value> {
+        StreamedCompletionHandler::partial_tool_arguments(self, index)
+    }
+    fn is_finished(&self) -> bool {
+        StreamedCompletionHandler::is_finished(self)
+    }
+    fn commit(&mut self, agent: &mut Agent) -> bool {
+        StreamedCompletionHandler::commit(self, agent)
+    }
+    fn check_first_chunk(&mut self) -> Pin<Box<dyn Future<Output = StreamResult<()>> + Send + '_>> {
+        Box::pin(StreamedCompletionHandler::check_first_chunk(self))
+    }
 }
 
 impl From<StreamedCompletionHandler<OpenAiStreamResponse>> for ProviderStreamHandler {
@@ -57,11 +162,40 @@ impl From<StreamedCompletionHandler<AnthropicStreamResponse>> for ProviderStream
     }
 }
 
+impl From<StreamedCompletionHandler<OllamaStreamResponse>> for ProviderStreamHandler {
+    fn from(value: StreamedCompletionHandler<OllamaStreamResponse>) -> Self {
+        Self::Ollama(value)
+    }
+}
+
+impl From<StreamedCompletionHandler<GeminiStreamResponse>> for ProviderStreamHandler {
+    fn from(value: StreamedCompletionHandler<GeminiStreamResponse>) -> Self {
+        Self::Gemini(value)
+    }
+}
+
+/// Reads chunks straight off the provider's response as it's polled, so dropping the handler
+/// drops the connection and cancels the completion
 pub struct StreamedCompletionHandler<T> {
-    phantom: PhantomData<T>,
-    stream: Option<CompletionStream>,
-    sender: Option<CompletionStreamSender>,
-    receiver: CompletionStreamReceiver,
+    phantom: PhantomData<fn() -> T>,
+    stream: CompletionStream,
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Events waiting to be returned, a chunk can carry more than one
+    pending: VecDeque<StreamResult<StreamEvent>>,
+    /// Calls still being streamed and their arguments so far, by index
+    partial_calls: BTreeMap<usize, ToolCall>,
+    call_arguments: BTreeMap<usize, String>,
+    /// Snapshots of the message and of each call's arguments, for json streamed as it's written
+    content_json: Box<PartialJson>,
+    arguments_json: BTreeMap<usize, PartialJson>,
+    tool_calls: Vec<ToolCall>,
+    finished: bool,
+    committed: bool,
+    metadata: Arc<Mutex<CompletionMetadata>>,
+    /// Limiter the request was counted against and its estimate, corrected once the stream
+    /// reports its usage
+    rate_limit: Option<(RateLimiter, u32)>,
     pub message_content: String,
 }
 
@@ -69,40 +203,257 @@ impl<T> std::fmt::Debug for StreamedCompletionHandler<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("StreamedCompletionHandler")
             .field("stream", &"<<skipped>>")
-            .field("sender", &self.sender)
-            .field("phantom", &self.phantom)
-            .field("receiver", &self.receiver)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("tool_calls", &self.tool_calls)
+            .field("finished", &self.finished)
+            .field("committed", &self.committed)
+            .field("metadata", &self.metadata)
+            .field("message_content", &self.message_content)
             .finish()
     }
 }
 
 impl<T> From<CompletionStream> for StreamedCompletionHandler<T> {
     fn from(stream: CompletionStream) -> Self {
-        let (tx, rx): (CompletionStreamSender, CompletionStreamReceiver) =
-            tokio::sync::mpsc::channel(50);
+        Self::new(stream, Arc::new(Mutex::new(CompletionMetadata::default())))
+    }
+}
+
+impl<T> StreamedCompletionHandler<T> {
+    fn new(stream: CompletionStream, metadata: Arc<Mutex<CompletionMetadata>>) -> Self {
         Self {
-            phantom: PhantomData::default(),
-            stream: Some(stream),
-            sender: Some(tx),
-            receiver: rx,
+            phantom: PhantomData,
+            stream,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            idle: None,
+            pending: VecDeque::new(),
+            partial_calls: BTreeMap::new(),
+            call_arguments: BTreeMap::new(),
+            content_json: Box::default(),
+            arguments_json: BTreeMap::new(),
+            tool_calls: vec![],
+            finished: false,
+            committed: false,
+            metadata,
+            rate_limit: None,
             message_content: String::new(),
         }
     }
+
+    /// Metadata reported by the stream so far. Usage and finish reason are usually only known
+    /// once the stream has finished
+    pub fn metadata(&self) -> CompletionMetadata {
+        self.metadata.lock().unwrap().clone()
+    }
+
+    fn merge_metadata(&self, metadata: CompletionMetadata) {
+        self.metadata.lock().unwrap().merge(metadata)
+    }
+
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+        self.idle = None;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn partial_json(&self) -> Option<Value> {
+        valid_snapshot(&self.content_json)
+    }
+
+    fn partial_tool_arguments(&self, index: usize) -> Option<Value> {
+        self.arguments_json.get(&index).and_then(valid_snapshot)
+    }
+
+    fn commit(&mut self, agent: &mut Agent) -> bool {
+        if self.committed {
+            return false;
+        }
+        self.committed = true;
+        agent.record_usage(&self.metadata());
+        let message = match self.tool_calls.is_empty() {
+            true => Message::new_assistant(&self.message_content),
+            false => Message::new_tool_calls(&self.message_content, self.tool_calls.clone()),
+        };
+        agent.cache.push(message);
+        true
+    }
 }
 
 impl ProviderStreamHandler {
+    /// Handler for the stream of a user defined provider. Each item of `stream` is parsed as a
+    /// `T`, items which don't parse are treated as errors sent by the provider
+    pub fn custom<T: StreamResponse>(
+        stream: impl Stream<Item = StreamResult<Value>> + Send + Unpin + 'static,
+    ) -> Self {
+        let stream: CompletionStream = Box::new(stream);
+        let handler = StreamedCompletionHandler::<T>::from(stream);
+        Self::Custom(CustomStreamHandler(Box::new(handler)))
+    }
+
+    /// How long to wait for the next chunk before failing with `StreamError::IdleTimeout`, `None`
+    /// waits forever. Defaults to `DEFAULT_IDLE_TIMEOUT`
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        match &mut self {
+            Self::OpenAi(inner) => inner.set_idle_timeout(timeout),
+            Self::Anthropic(inner) => inner.set_idle_timeout(timeout),
+            Self::Ollama(inner) => inner.set_idle_timeout(timeout),
+            Self::Gemini(inner) => inner.set_idle_timeout(timeout),
+            Self::Custom(inner) => inner.0.set_idle_timeout(timeout),
+        }
+        self
+    }
+
+    /// Metadata reported by the stream so far. Usage and finish reason are usually only known
+    /// once the stream has finished
+    pub fn metadata(&self) -> CompletionMetadata {
+        match self {
+            Self::OpenAi(inner) => inner.metadata(),
+            Self::Anthropic(inner) => inner.metadata(),
+            Self::Ollama(inner) => inner.metadata(),
+            Self::Gemini(inner) => inner.metadata(),
+            Self::Custom(inner) => inner.0.metadata(),
+        }
+    }
+
+    /// Everything the stream has sent so far
+    pub fn message_content(&self) -> &str {
+        match self {
+            Self::OpenAi(inner) => &inner.message_content,
+            Self::Anthropic(inner) => &inner.message_content,
+            Self::Ollama(inner) => &inner.message_content,
+            Self::Gemini(inner) => &inner.message_content,
+            Self::Custom(inner) => inner.0.message_content(),
+        }
+    }
+
+    /// Every tool call the stream made, only known once it has finished
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        match self {
+            Self::OpenAi(inner) => &inner.tool_calls,
+            Self::Anthropic(inner) => &inner.tool_calls,
+            Self::Ollama(inner) => &inner.tool_calls,
+            Self::Gemini(inner) => &inner.tool_calls,
+            Self::Custom(inner) => inner.0.tool_calls(),
+        }
+    }
+
+    /// Message so far parsed as json, with whatever is still open closed. Meant for json mode and
+    /// structured output, `None` if the message isn't json
+    pub fn partial_json(&self) -> Option<Value> {
+        match self {
+            Self::OpenAi(inner) => inner.partial_json(),
+            Self::Anthropic(inner) => inner.partial_json(),
+            Self::Ollama(inner) => inner.partial_json(),
+            Self::Gemini(inner) => inner.partial_json(),
+            Self::Custom(inner) => inner.0.partial_json(),
+        }
+    }
+
+    /// Arguments of the tool call at `index` so far, with whatever is still open closed
+    pub fn partial_tool_arguments(&self, index: usize) -> Option<Value> {
+        match self {
+            Self::OpenAi(inner) => inner.partial_tool_arguments(index),
+            Self::Anthropic(inner) => inner.partial_tool_arguments(index),
+            Self::Ollama(inner) => inner.partial_tool_arguments(index),
+            Self::Gemini(inner) => inner.partial_tool_arguments(index),
+            Self::Custom(inner) => inner.0.partial_tool_arguments(index),
+        }
+    }
+
+    /// Whether the stream has ended, either finishing or failing
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Self::OpenAi(inner) => inner.is_finished(),
+            Self::Anthropic(inner) => inner.is_finished(),
+            Self::Ollama(inner) => inner.is_finished(),
+            Self::Gemini(inner) => inner.is_finished(),
+            Self::Custom(inner) => inner.0.is_finished(),
+        }
+    }
+
+    /// Push the streamed message onto the agent's cache and record its usage. Meant for streams
+    /// consumed as a `Stream`, which don't borrow the agent. Can be called before the stream has
+    /// finished to keep a partial message. Only the first call does anything, returns whether it
+    /// was this one
+    pub fn commit(&mut self, agent: &mut Agent) -> bool {
+        match self {
+            Self::OpenAi(inner) => inner.commit(agent),
+            Self::Anthropic(inner) => inner.commit(agent),
+            Self::Ollama(inner) => inner.commit(agent),
+            Self::Gemini(inner) => inner.commit(agent),
+            Self::Custom(inner) => inner.0.commit(agent),
+        }
+    }
+
+    pub(crate) fn merge_metadata(&self, metadata: CompletionMetadata) {
+        match self {
+            Self::OpenAi(inner) => inner.merge_metadata(metadata),
+            Self::Anthropic(inner) => inner.merge_metadata(metadata),
+            Self::Ollama(inner) => inner.merge_metadata(metadata),
+            Self::Gemini(inner) => inner.merge_metadata(metadata),
+            Self::Custom(inner) => inner.0.merge_metadata(metadata),
+        }
+    }
+
+    pub(crate) fn set_rate_limit(&mut self, limiter: RateLimiter, estimated_tokens: u32) {
+        match self {
+            Self::OpenAi(inner) => inner.rate_limit = Some((limiter, estimated_tokens)),
+            Self::Anthropic(inner) => inner.rate_limit = Some((limiter, estimated_tokens)),
+            Self::Ollama(inner) => inner.rate_limit = Some((limiter, estimated_tokens)),
+            Self::Gemini(inner) => inner.rate_limit = Some((limiter, estimated_tokens)),
+            Self::Custom(inner) => inner.0.set_rate_limit(limiter, estimated_tokens),
+        }
+    }
+
+    pub(crate) async fn check_first_chunk(&mut self) -> StreamResult<()> {
+        match self {
+            Self::OpenAi(inner) => inner.check_first_chunk().await,
+            Self::Anthropic(inner) => inner.check_first_chunk().await,
+            Self::Ollama(inner) => inner.check_first_chunk().await,
+            Self::Gemini(inner) => inner.check_first_chunk().await,
+            Self::Custom(inner) => inner.0.check_first_chunk().await,
+        }
+    }
+
+    /// Returns tokens until finished. When finished the full message is committed to the agent's
+    /// cache. Best used in a while loop. Tool calls are skipped, poll the handler as a `Stream` to
+    /// follow them
     #[tracing::instrument("Receive tokens from completion stream", skip(self))]
     pub async fn receive(
         &mut self,
         agent: &mut Agent,
     ) -> StreamResult<Option<CompletionStreamStatus>> {
-        let response = match self {
-            Self::OpenAi(inner) => inner.receive(agent).await,
-            Self::Anthropic(inner) => inner.receive(agent).await,
-        };
+        while let Some(event) = self.next().await.transpose()? {
+            match event {
+                StreamEvent::Token(token) => {
+                    return Ok(Some(CompletionStreamStatus::Working(token)))
+                }
+                StreamEvent::Finished(_) => {
+                    tracing::info!("Stream finished with content: {}", self.message_content());
+                    self.commit(agent);
+                    return Ok(Some(CompletionStreamStatus::Finished));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Stream for ProviderStreamHandler {
+    type Item = StreamResult<StreamEvent>;
 
-        tracing::warn!("got stream response:  {response:#?}");
-        response
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Self::OpenAi(inner) => inner.poll_next_unpin(cx),
+            Self::Anthropic(inner) => inner.poll_next_unpin(cx),
+            Self::Ollama(inner) => inner.poll_next_unpin(cx),
+            Self::Gemini(inner) => inner.poll_next_unpin(cx),
+            Self::Custom(inner) => inner.0.poll_next_unpin(cx),
+        }
     }
 }
 
@@ -110,131 +461,457 @@ impl<T> StreamedCompletionHandler<T>
 where
     T: StreamResponse,
 {
-    /// Returns tokens until finished, when finished, sends an update cache request with the full
-    /// message. Best used in a while loop
-    #[tracing::instrument("Receive tokens from completion stream", skip(self))]
-    async fn receive(&mut self, agent: &mut Agent) -> StreamResult<Option<CompletionStreamStatus>> {
-        if self.sender.is_some() && self.stream.is_some() {
-            tracing::info!("Telling thread to run");
-            self.spawn()?;
-        }
-        if let Some(result) =
-            tokio::time::timeout(Duration::from_millis(1000), self.receiver.recv())
+    /// Waits for the first chunk so a stream which fails or stalls before sending anything can be
+    /// retried. The chunk is put back at the front of the stream
+    async fn check_first_chunk(&mut self) -> StreamResult<()> {
+        let mut stream = std::mem::replace(&mut self.stream, Box::new(futures::stream::empty()));
+        let first = match self.idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, stream.next())
                 .await
-                .map_err(|_| StreamError::ReceiverTimeout)?
+                .map_err(|_| StreamError::IdleTimeout(timeout))?,
+            None => stream.next().await,
+        };
+        let result = match &first {
+            Some(Err(_)) => {
+                return Err(first.unwrap().unwrap_err());
+            }
+            Some(Ok(json)) if serde_json::from_value::<T>(json.clone()).is_err() => {
+                Err(StreamError::from(json.clone()))
+            }
+            _ => Ok(()),
+        };
+        self.stream = Box::new(futures::stream::iter(first).chain(stream));
+        result
+    }
+
+    /// Ends the stream with `err`
+    fn fail(&mut self, err: StreamError) {
+        self.pending.push_back(Err(err));
+        self.close();
+    }
+
+    /// Ends the stream, sending every tool call it made and then `Finished`
+    fn finish(&mut self) {
+        self.content_json.finish();
+        for (index, mut call) in std::mem::take(&mut self.partial_calls) {
+            let arguments = self.call_arguments.remove(&index).unwrap_or_default();
+            call.arguments = match arguments.trim() {
+                "" => Value::Object(Default::default()),
+                arguments => match serde_json::from_str(arguments) {
+                    Ok(arguments) => arguments,
+                    Err(err) => return self.fail(err.into()),
+                },
+            };
+            self.tool_calls.push(call.clone());
+            self.pending.push_back(Ok(StreamEvent::ToolCall(call)));
+        }
+        let metadata = self.metadata();
+        if let (Some((limiter, estimated_tokens)), Some(usage)) =
+            (self.rate_limit.take(), metadata.usage)
         {
-            match result? {
-                CompletionStreamStatus::Working(token) => {
-                    self.message_content.push_str(&token);
-                    return Ok(Some(CompletionStreamStatus::Working(token.to_string())));
+            limiter.reconcile(estimated_tokens, usage.total_tokens());
+        }
+        self.pending.push_back(Ok(StreamEvent::Finished(metadata)));
+        self.close();
+    }
+
+    fn close(&mut self) {
+        self.finished = true;
+        self.idle = None;
+        // Drop the response so the provider stops generating
+        self.stream = Box::new(futures::stream::empty());
+    }
+
+    fn process_chunk(&mut self, typ: T) {
+        if let Some(chunk_metadata) = typ.metadata() {
+            self.merge_metadata(chunk_metadata);
+        }
+        let tool_chunks = typ.tool_call_chunks();
+        let status: CompletionStreamStatus = typ.into();
+        if let CompletionStreamStatus::Working(token) = &status {
+            if !token.is_empty() {
+                self.message_content.push_str(token);
+                // Most messages aren't json, which the parser gives up on straight away
+                let _ = self.content_json.push(token);
+                self.pending
+                    .push_back(Ok(StreamEvent::Token(token.to_owned())));
+            }
+        }
+        for chunk in tool_chunks {
+            let event = match chunk {
+                ToolCallChunk::Start { index, id, name } => {
+                    self.partial_calls.insert(
+                        index,
+                        ToolCall {
+                            id: id.to_owned(),
+                            name: name.to_owned(),
+                            arguments: Value::Null,
+                        },
+                    );
+                    StreamEvent::ToolCallStart { index, id, name }
                 }
-                // CompletionStreamStatus::Error(json) => {
-                //     tracing::info!("Stream recieved an error: {json:#?}");
-                //     return Err(StreamError::from(json));
-                // }
-                CompletionStreamStatus::Finished => {
-                    tracing::info!("Stream finished with content: {}", self.message_content);
-                    let message = Message::new_assistant(&self.message_content);
-                    agent.cache.push(message);
-                    return Ok(Some(CompletionStreamStatus::Finished));
+                ToolCallChunk::Arguments { index, fragment } => {
+                    self.call_arguments
+                        .entry(index)
+                        .or_default()
+                        .push_str(&fragment);
+                    let _ = self
+                        .arguments_json
+                        .entry(index)
+                        .or_default()
+                        .push(&fragment);
+                    StreamEvent::ToolCallArguments { index, fragment }
                 }
-            }
+            };
+            self.pending.push_back(Ok(event));
+        }
+        if let CompletionStreamStatus::Finished = status {
+            self.finish();
         }
-        tracing::info!("received none");
-        Ok(None)
     }
+}
 
-    #[tracing::instrument("Spawn completion stream thread", skip(self))]
-    fn spawn(&mut self) -> Result<(), StreamError> {
-        let mut stream = self.stream.take().unwrap();
-        let tx = self.sender.take().unwrap();
-        tokio::spawn(async move {
-            loop {
-                tracing::info!("Beginning of completion stream thread loop");
-                match CompletionStreamingThread::poll_stream_for_type::<T>(&mut stream).await {
-                    Ok(type_option) => {
-                        let status: CompletionStreamStatus = match type_option {
-                            Some(ret) => match ret {
-                                StreamPollReturn::Ok(typ) => <T as Clone>::clone(&(typ)).into(),
-                                StreamPollReturn::Err(json) => {
-                                    tx.send(Err(StreamError::from(json)))
-                                        .await
-                                        .expect("could not send");
-                                    break;
-                                }
-                            },
-                            None => CompletionStreamStatus::Finished,
-                        };
-                        tracing::info!("Got status: {:?}", status);
-
-                        let break_loop = match &status {
-                            &CompletionStreamStatus::Finished => true,
-                            _ => false,
-                        };
-
-                        tx.send(Ok(status)).await.map_err(|err| {
-                            StreamError::Undefined(anyhow!("Unexpected Error: {:?}", err))
-                        })?;
-
-                        if break_loop {
-                            break;
-                        }
+impl<T> Stream for StreamedCompletionHandler<T>
+where
+    T: StreamResponse,
+{
+    type Item = StreamResult<StreamEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            let json = match this.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(json))) => json,
+                Poll::Ready(Some(Err(err))) => {
+                    this.fail(err);
+                    continue;
+                }
+                // Providers which end on a chunk with a finish reason rather than one that
+                // finishes the stream are done once they close it, anything else was cut off
+                Poll::Ready(None) => {
+                    match this.metadata().finish_reason {
+                        Some(_) => this.finish(),
+                        None => this.fail(StreamError::Truncated),
                     }
-                    Err(err) => {
-                        tx.send(Err(err))
-                            .await
-                            .expect("failed to send error message back");
-                        break;
+                    continue;
+                }
+                Poll::Pending => {
+                    let Some(timeout) = this.idle_timeout else {
+                        return Poll::Pending;
+                    };
+                    let idle = this
+                        .idle
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                    match idle.as_mut().poll(cx) {
+                        Poll::Ready(()) => {
+                            this.fail(StreamError::IdleTimeout(timeout));
+                            continue;
+                        }
+                        Poll::Pending => return Poll::Pending,
                     }
-                };
+                }
+            };
+            this.idle = None;
+            match serde_json::from_value::<T>(json.clone()) {
+                Ok(typ) => this.process_chunk(typ),
+                Err(err) => {
+                    warn!("Stream chunk failed to coerce to T: {err:#?}");
+                    this.fail(StreamError::from(json));
+                }
             }
-            tracing::info!("outside of loop");
-            return Ok::<(), StreamError>(());
-        });
-
-        Ok(())
+        }
     }
 }
 
-pub enum StreamPollReturn<T> {
-    Ok(T),
-    Err(serde_json::Value),
+fn valid_snapshot(parser: &PartialJson) -> Option<Value> {
+    parser.is_valid().then(|| parser.snapshot()).flatten()
 }
 
-impl<T> From<T> for StreamPollReturn<T>
-where
-    T: StreamResponse,
-{
-    fn from(value: T) -> Self {
-        Self::Ok(value)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::CompletionModel;
+    use tokio::sync::mpsc;
+
+    /// Chunk of a made up provider, which finishes with a `null` token and can stream a call to
+    /// a single function
+    #[derive(Debug, Clone, Deserialize)]
+    struct TestChunk {
+        token: Option<String>,
+        #[serde(default)]
+        call_id: Option<String>,
+        #[serde(default)]
+        arguments: String,
     }
-}
 
-impl<T> From<serde_json::Value> for StreamPollReturn<T> {
-    fn from(value: serde_json::Value) -> Self {
-        Self::Err(value)
+    impl From<TestChunk> for CompletionStreamStatus {
+        fn from(value: TestChunk) -> Self {
+            match value.token {
+                Some(token) => Self::Working(token),
+                None => Self::Finished,
+            }
+        }
     }
-}
 
-impl CompletionStreamingThread {
-    #[tracing::instrument(name = "Get token from stream" skip(stream))]
-    async fn poll_stream_for_type<T>(
-        stream: &mut CompletionStream,
-    ) -> StreamResult<Option<StreamPollReturn<T>>>
-    where
-        T: StreamResponse,
-    {
-        while let Some(Ok(stream_response)) = stream.next().await {
-            warn!("Stream response json: {:?}", stream_response);
-            match serde_json::from_value::<T>(stream_response.clone()) {
-                Ok(val) => return Ok(Some(StreamPollReturn::from(val))),
-                Err(err) => {
-                    warn!("poll stream for type failed to coerce to T: {err:#?}");
-                    return Ok(Some(StreamPollReturn::from(stream_response)));
-                }
+    impl StreamResponse for TestChunk {
+        fn tool_call_chunks(&self) -> Vec<ToolCallChunk> {
+            let mut chunks = vec![];
+            if let Some(id) = &self.call_id {
+                chunks.push(ToolCallChunk::Start {
+                    index: 0,
+                    id: id.to_owned(),
+                    name: "lookup".to_owned(),
+                });
+            }
+            if !self.arguments.is_empty() {
+                chunks.push(ToolCallChunk::Arguments {
+                    index: 0,
+                    fragment: self.arguments.to_owned(),
+                });
             }
+            chunks
         }
+    }
 
-        Ok(None)
+    /// Handler reading whatever statuses are sent down the returned channel
+    fn channel_handler() -> (
+        mpsc::UnboundedSender<CompletionStreamStatus>,
+        ProviderStreamHandler,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel::<CompletionStreamStatus>();
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            let token = match rx.recv().await? {
+                CompletionStreamStatus::Working(token) => Some(token),
+                CompletionStreamStatus::Finished => None,
+            };
+            Some((Ok(serde_json::json!({ "token": token })), rx))
+        });
+        let handler = ProviderStreamHandler::custom::<TestChunk>(Box::pin(stream));
+        (tx, handler)
+    }
+
+    #[tokio::test]
+    async fn custom_provider_streams_tool_calls() {
+        let chunks = vec![
+            Ok(serde_json::json!({"token": "", "call_id": "call_1"})),
+            Ok(serde_json::json!({"token": "", "arguments": "{\"q\": "})),
+            Ok(serde_json::json!({"token": "", "arguments": "1}"})),
+            Ok(serde_json::json!({"token": null})),
+        ];
+        let mut handler = ProviderStreamHandler::custom::<TestChunk>(futures::stream::iter(chunks));
+
+        let events: Vec<_> = (&mut handler).map(Result::unwrap).collect().await;
+        let call = ToolCall {
+            id: "call_1".to_owned(),
+            name: "lookup".to_owned(),
+            arguments: serde_json::json!({"q": 1}),
+        };
+        assert!(matches!(
+            events[0],
+            StreamEvent::ToolCallStart { index: 0, .. }
+        ));
+        assert_eq!(events[3], StreamEvent::ToolCall(call.clone()));
+        assert_eq!(handler.tool_calls(), [call]);
+    }
+
+    #[tokio::test]
+    async fn tokens_streamed_then_committed() {
+        let (tx, mut handler) = channel_handler();
+        for token in ["Hel", "", "lo"] {
+            tx.send(CompletionStreamStatus::Working(token.to_owned()))
+                .unwrap();
+        }
+        tx.send(CompletionStreamStatus::Finished).unwrap();
+
+        let events: Vec<_> = (&mut handler).collect().await;
+        let events: Vec<_> = events.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            events,
+            [
+                StreamEvent::Token("Hel".to_owned()),
+                StreamEvent::Token("lo".to_owned()),
+                StreamEvent::Finished(CompletionMetadata::default()),
+            ]
+        );
+        assert!(handler.is_finished());
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        assert!(handler.commit(&mut agent));
+        assert!(!handler.commit(&mut agent));
+        assert_eq!(agent.cache.len(), 1);
+        assert_eq!(handler.message_content(), "Hello");
+    }
+
+    #[tokio::test]
+    async fn tool_calls_assembled_from_fragments() {
+        let chunk = |delta: serde_json::Value| {
+            Ok(
+                serde_json::json!({"choices": [{"index": 0, "delta": delta, "finish_reason": null}]}),
+            )
+        };
+        let call = |index: usize, id: Option<&str>, name: Option<&str>, arguments: &str| {
+            serde_json::json!({"tool_calls": [
+                {"index": index, "id": id, "function": {"name": name, "arguments": arguments}}
+            ]})
+        };
+        let chunks = vec![
+            chunk(serde_json::json!({"content": "Checking"})),
+            chunk(call(0, Some("call_a"), Some("get_weather"), "")),
+            chunk(call(0, None, None, "{\"location\":")),
+            chunk(call(1, Some("call_b"), Some("get_time"), "")),
+            chunk(call(0, None, None, " \"Paris\"}")),
+            Ok(
+                serde_json::json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]}),
+            ),
+        ];
+        let stream: CompletionStream = Box::new(futures::stream::iter(chunks));
+        let mut handler: ProviderStreamHandler =
+            StreamedCompletionHandler::<OpenAiStreamResponse>::from(stream).into();
+
+        let events: Vec<_> = (&mut handler).map(Result::unwrap).collect().await;
+        assert_eq!(events[0], StreamEvent::Token("Checking".to_owned()));
+        assert_eq!(
+            events[1],
+            StreamEvent::ToolCallStart {
+                index: 0,
+                id: "call_a".to_owned(),
+                name: "get_weather".to_owned()
+            }
+        );
+        let fragments: String = events
+            .iter()
+            .filter_map(|e| match e {
+                StreamEvent::ToolCallArguments { index: 0, fragment } => Some(fragment.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fragments, "{\"location\": \"Paris\"}");
+        assert_eq!(handler.partial_json(), None);
+        assert_eq!(
+            handler.partial_tool_arguments(0),
+            Some(serde_json::json!({"location": "Paris"}))
+        );
+
+        let calls = vec![
+            ToolCall {
+                id: "call_a".to_owned(),
+                name: "get_weather".to_owned(),
+                arguments: serde_json::json!({"location": "Paris"}),
+            },
+            ToolCall {
+                id: "call_b".to_owned(),
+                name: "get_time".to_owned(),
+                arguments: serde_json::json!({}),
+            },
+        ];
+        let n = events.len();
+        assert_eq!(events[n - 3], StreamEvent::ToolCall(calls[0].clone()));
+        assert_eq!(events[n - 2], StreamEvent::ToolCall(calls[1].clone()));
+        assert!(matches!(events[n - 1], StreamEvent::Finished(_)));
+        assert_eq!(handler.tool_calls(), calls);
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        handler.commit(&mut agent);
+        assert_eq!(
+            agent.cache.as_ref().last().unwrap(),
+            &Message::new_tool_calls("Checking", calls)
+        );
+    }
+
+    #[tokio::test]
+    async fn json_message_snapshotted_as_it_streams() {
+        let (tx, mut handler) = channel_handler();
+        let tokens = [
+            r#"{"answer": "fo"#,
+            r#"rty two", "confid"#,
+            r#"ence": 0.9}"#,
+        ];
+        let snapshots = [
+            serde_json::json!({"answer": "fo"}),
+            serde_json::json!({"answer": "forty two"}),
+            serde_json::json!({"answer": "forty two", "confidence": 0.9}),
+        ];
+        for (token, snapshot) in tokens.into_iter().zip(snapshots) {
+            tx.send(CompletionStreamStatus::Working(token.to_owned()))
+                .unwrap();
+            handler.next().await.unwrap().unwrap();
+            assert_eq!(handler.partial_json(), Some(snapshot));
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_cut_off_before_finishing_fails() {
+        let (tx, mut handler) = channel_handler();
+        tx.send(CompletionStreamStatus::Working("Hel".to_owned()))
+            .unwrap();
+        drop(tx);
+        assert!(matches!(
+            handler.next().await,
+            Some(Ok(StreamEvent::Token(_)))
+        ));
+        assert!(matches!(
+            handler.next().await,
+            Some(Err(StreamError::Truncated))
+        ));
+        assert!(handler.next().await.is_none());
+
+        let mut agent = Agent::new(None, CompletionModel::default_openai(""));
+        let (tx, mut handler) = channel_handler();
+        tx.send(CompletionStreamStatus::Working("Hel".to_owned()))
+            .unwrap();
+        drop(tx);
+        assert!(handler.receive(&mut agent).await.unwrap().is_some());
+        assert!(matches!(
+            handler.receive(&mut agent).await,
+            Err(StreamError::Truncated)
+        ));
+        assert_eq!(agent.cache.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_stream_times_out() {
+        let (tx, handler) = channel_handler();
+        let mut handler = handler.with_idle_timeout(Some(Duration::from_millis(100)));
+        // Chunks arriving more often than the timeout keep the stream alive
+        let sender = tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                tx.send(CompletionStreamStatus::Working("tick".to_owned()))
+                    .unwrap();
+            }
+            tx
+        });
+        for _ in 0..4 {
+            assert!(matches!(
+                handler.next().await,
+                Some(Ok(StreamEvent::Token(_)))
+            ));
+        }
+        let _tx = sender.await.unwrap();
+        assert!(matches!(
+            handler.next().await,
+            Some(Err(StreamError::IdleTimeout(_)))
+        ));
+        assert!(handler.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_handler_cancels_stream() {
+        let (tx, mut handler) = channel_handler();
+        tx.send(CompletionStreamStatus::Working("partial".to_owned()))
+            .unwrap();
+        assert!(matches!(
+            handler.next().await,
+            Some(Ok(StreamEvent::Token(_)))
+        ));
+        assert!(!tx.is_closed());
+        drop(handler);
+        assert!(tx.is_closed());
     }
 }
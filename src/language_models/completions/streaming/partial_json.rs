@@ -0,0 +1,480 @@
+use super::{StreamError, StreamResult};
+use serde_json::{Map, Number, Value};
+
+/// Parses json as it's streamed, a few characters at a time. After every fragment `snapshot`
+/// gives the value so far as valid json: open strings, arrays and objects are closed, while keys
+/// without a value yet and half written `true`, `false` and `null` are left out. Numbers are kept
+/// as far as they parse
+#[derive(Debug, Clone, Default)]
+pub struct PartialJson {
+    stack: Vec<Frame>,
+    scalar: Scalar,
+    expect: Expect,
+    root: Option<Value>,
+    /// Characters read so far, for error offsets
+    offset: usize,
+    error: Option<(usize, char)>,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    Object {
+        map: Map<String, Value>,
+        key: Option<String>,
+    },
+    Array(Vec<Value>),
+}
+
+#[derive(Debug, Clone, Default)]
+enum Scalar {
+    #[default]
+    None,
+    String {
+        decoded: String,
+        is_key: bool,
+        escape: Escape,
+    },
+    Number(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+enum Escape {
+    #[default]
+    None,
+    Backslash,
+    Unicode {
+        hex: String,
+        high: Option<u32>,
+    },
+    /// High half of a surrogate pair, waiting for the escape of its low half
+    HighSurrogate(u32),
+    HighSurrogateBackslash(u32),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Expect {
+    #[default]
+    Value,
+    /// Key or the end of an empty object
+    Key,
+    Colon,
+    CommaOrClose,
+    /// Root value is complete, only whitespace may follow
+    End,
+}
+
+impl PartialJson {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next fragment. Fails on input that can't be the start of valid json, after which
+    /// the parser is stuck and `snapshot` keeps returning what was parsed before the error
+    pub fn push(&mut self, fragment: &str) -> StreamResult<()> {
+        for c in fragment.chars() {
+            if self.error.is_none() && !self.next_char(c) {
+                self.error = Some((self.offset, c));
+            }
+            self.offset += 1;
+        }
+        match self.error {
+            Some((offset, found)) => Err(StreamError::InvalidJson { offset, found }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether everything pushed so far could be the start of valid json
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Marks the end of the input. A number at the root only ends with the input, so it isn't
+    /// complete until this is called
+    pub fn finish(&mut self) {
+        if let (true, Scalar::Number(digits)) = (self.stack.is_empty(), &self.scalar) {
+            if let Ok(number) = digits.parse::<Number>() {
+                self.scalar = Scalar::None;
+                self.value_done(Value::Number(number));
+            }
+        }
+    }
+
+    /// Whether a whole json value has been read
+    pub fn is_complete(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// Best effort value of everything pushed so far, `None` until anything worth showing has
+    /// arrived
+    pub fn snapshot(&self) -> Option<Value> {
+        if let Some(root) = &self.root {
+            return Some(root.clone());
+        }
+        let mut partial = match &self.scalar {
+            Scalar::String {
+                decoded,
+                is_key: false,
+                ..
+            } => Some(Value::String(decoded.to_owned())),
+            Scalar::Number(digits) => partial_number(digits),
+            Scalar::String { is_key: true, .. } | Scalar::Literal(_) | Scalar::None => None,
+        };
+        for frame in self.stack.iter().rev() {
+            partial = Some(match frame.clone() {
+                Frame::Array(mut values) => {
+                    values.extend(partial);
+                    Value::Array(values)
+                }
+                Frame::Object { mut map, key } => {
+                    if let (Some(key), Some(value)) = (key, partial) {
+                        map.insert(key, value);
+                    }
+                    Value::Object(map)
+                }
+            });
+        }
+        partial
+    }
+
+    /// Returns false if `c` can't come next
+    fn next_char(&mut self, c: char) -> bool {
+        match &mut self.scalar {
+            Scalar::String {
+                decoded, escape, ..
+            } => {
+                let closed = match push_string_char(decoded, escape, c) {
+                    Some(closed) => closed,
+                    None => return false,
+                };
+                if closed {
+                    self.end_string();
+                }
+                return true;
+            }
+            Scalar::Number(digits) => {
+                if c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-') {
+                    digits.push(c);
+                    return true;
+                }
+                let Ok(number) = digits.parse::<Number>() else {
+                    return false;
+                };
+                self.scalar = Scalar::None;
+                self.value_done(Value::Number(number));
+            }
+            Scalar::Literal(word) => {
+                word.push(c);
+                let value = match word.as_str() {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    "null" => Value::Null,
+                    w => return ["true", "false", "null"].iter().any(|l| l.starts_with(w)),
+                };
+                self.scalar = Scalar::None;
+                self.value_done(value);
+                return true;
+            }
+            Scalar::None => {}
+        }
+
+        if c.is_whitespace() {
+            return true;
+        }
+        match (self.expect, c) {
+            (Expect::Value, '{') => {
+                self.stack.push(Frame::Object {
+                    map: Map::new(),
+                    key: None,
+                });
+                self.expect = Expect::Key;
+            }
+            (Expect::Value, '[') => {
+                self.stack.push(Frame::Array(vec![]));
+            }
+            (Expect::Value, ']') if matches!(self.stack.last(), Some(Frame::Array(v)) if v.is_empty()) =>
+            {
+                return self.close(']');
+            }
+            (Expect::Value, '"') => self.start_string(false),
+            (Expect::Value, c) if c == '-' || c.is_ascii_digit() => {
+                self.scalar = Scalar::Number(c.to_string());
+            }
+            (Expect::Value, c @ ('t' | 'f' | 'n')) => {
+                self.scalar = Scalar::Literal(c.to_string());
+            }
+            (Expect::Key, '"') => self.start_string(true),
+            (Expect::Key, '}') => return self.close('}'),
+            (Expect::Colon, ':') => self.expect = Expect::Value,
+            (Expect::CommaOrClose, ',') => {
+                self.expect = match self.stack.last() {
+                    Some(Frame::Object { .. }) => Expect::Key,
+                    _ => Expect::Value,
+                };
+            }
+            (Expect::CommaOrClose, c @ ('}' | ']')) => return self.close(c),
+            _ => return false,
+        }
+        true
+    }
+
+    fn start_string(&mut self, is_key: bool) {
+        self.scalar = Scalar::String {
+            decoded: String::new(),
+            is_key,
+            escape: Escape::None,
+        };
+    }
+
+    fn end_string(&mut self) {
+        let Scalar::String {
+            decoded, is_key, ..
+        } = std::mem::take(&mut self.scalar)
+        else {
+            return;
+        };
+        match (is_key, self.stack.last_mut()) {
+            (true, Some(Frame::Object { key, .. })) => {
+                *key = Some(decoded);
+                self.expect = Expect::Colon;
+            }
+            _ => self.value_done(Value::String(decoded)),
+        }
+    }
+
+    fn close(&mut self, c: char) -> bool {
+        let value = match (self.stack.pop(), c) {
+            (Some(Frame::Object { map, .. }), '}') => Value::Object(map),
+            (Some(Frame::Array(values)), ']') => Value::Array(values),
+            _ => return false,
+        };
+        self.value_done(value);
+        true
+    }
+
+    fn value_done(&mut self, value: Value) {
+        self.expect = Expect::CommaOrClose;
+        match self.stack.last_mut() {
+            None => {
+                self.root = Some(value);
+                self.expect = Expect::End;
+            }
+            Some(Frame::Array(values)) => values.push(value),
+            Some(Frame::Object { map, key }) => {
+                if let Some(key) = key.take() {
+                    map.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Adds `c` to the string being read. Returns whether it closed the string, `None` if it isn't
+/// allowed there
+fn push_string_char(decoded: &mut String, escape: &mut Escape, c: char) -> Option<bool> {
+    match escape {
+        Escape::None => match c {
+            '\\' => *escape = Escape::Backslash,
+            '"' => return Some(true),
+            c if (c as u32) < 0x20 => return None,
+            c => decoded.push(c),
+        },
+        Escape::HighSurrogate(high) => {
+            if c == '\\' {
+                *escape = Escape::HighSurrogateBackslash(*high);
+                return Some(false);
+            }
+            decoded.push(char::REPLACEMENT_CHARACTER);
+            *escape = Escape::None;
+            return push_string_char(decoded, escape, c);
+        }
+        Escape::HighSurrogateBackslash(high) => {
+            if c == 'u' {
+                *escape = Escape::Unicode {
+                    hex: String::new(),
+                    high: Some(*high),
+                };
+                return Some(false);
+            }
+            decoded.push(char::REPLACEMENT_CHARACTER);
+            *escape = Escape::Backslash;
+            return push_string_char(decoded, escape, c);
+        }
+        Escape::Backslash => {
+            let unescaped = match c {
+                '"' => '"',
+                '\\' => '\\',
+                '/' => '/',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    *escape = Escape::Unicode {
+                        hex: String::new(),
+                        high: None,
+                    };
+                    return Some(false);
+                }
+                _ => return None,
+            };
+            decoded.push(unescaped);
+            *escape = Escape::None;
+        }
+        Escape::Unicode { hex, high } => {
+            if !c.is_ascii_hexdigit() {
+                return None;
+            }
+            hex.push(c);
+            if hex.len() < 4 {
+                return Some(false);
+            }
+            let code = u32::from_str_radix(hex, 16).ok()?;
+            let high = high.take();
+            *escape = Escape::None;
+            match (high, code) {
+                (Some(high), 0xDC00..=0xDFFF) => {
+                    let combined = 0x10000 + ((high - 0xD800) << 10) + (code - 0xDC00);
+                    decoded.push(char::from_u32(combined).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                (None, 0xD800..=0xDBFF) => *escape = Escape::HighSurrogate(code),
+                (high, code) => {
+                    if high.is_some() {
+                        decoded.push(char::REPLACEMENT_CHARACTER);
+                    }
+                    decoded.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+            }
+        }
+    }
+    Some(false)
+}
+
+/// Longest prefix of a number still being written that parses, e.g. `12` of `12.` or `1e`
+fn partial_number(digits: &str) -> Option<Value> {
+    let trimmed = digits.trim_end_matches(['.', 'e', 'E', '+', '-']);
+    trimmed.parse::<Number>().ok().map(Value::Number)
+}
+
+/// Best effort value of a truncated json document, see `PartialJson`
+pub fn parse_partial_json(json: &str) -> Option<Value> {
+    let mut parser = PartialJson::new();
+    // Whatever parsed before an error is still returned
+    let _ = parser.push(json);
+    parser.snapshot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn truncated_inputs_closed() {
+        let cases = [
+            (r#"{"location": "Par"#, Some(json!({"location": "Par"}))),
+            (
+                r#"{"location": "Paris", "unit"#,
+                Some(json!({"location": "Paris"})),
+            ),
+            (
+                r#"{"location": "Paris", "unit":"#,
+                Some(json!({"location": "Paris"})),
+            ),
+            (r#"{"days": [1, 2, 3"#, Some(json!({"days": [1, 2, 3]}))),
+            (r#"{"days": [1, 2, "#, Some(json!({"days": [1, 2]}))),
+            (r#"{"temp": -12."#, Some(json!({"temp": -12}))),
+            (r#"{"temp": 1.5e"#, Some(json!({"temp": 1.5}))),
+            (r#"{"temp": -"#, Some(json!({}))),
+            (r#"{"ok": tr"#, Some(json!({}))),
+            (r#"{"ok": true"#, Some(json!({"ok": true}))),
+            (
+                r#"{"a": {"b": [{"c": "d\"e\"#,
+                Some(json!({"a": {"b": [{"c": "d\"e"}]}})),
+            ),
+            (r#"{"emoji": "\ud83c"#, Some(json!({"emoji": ""}))),
+            (r#"{"emoji": "🌍 é"#, Some(json!({"emoji": "🌍 é"}))),
+            (r#"[[], {}, nul"#, Some(json!([[], {}]))),
+            (r#""just a str"#, Some(json!("just a str"))),
+            ("", None),
+            ("  ", None),
+            ("{", Some(json!({}))),
+            ("42", Some(json!(42))),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_partial_json(input), expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn every_prefix_snapshots_and_whole_matches() {
+        let whole = json!({
+            "name": "get_weather",
+            "args": {"cities": ["Paris", "Tōkyō 🗼"], "days": 3, "metric": false, "note": null},
+            "scores": [1.5, -2e3, 0],
+            "quote": "she said \"hi\"\n\ttwice \\ \u{1F600}"
+        });
+        let text = serde_json::to_string_pretty(&whole).unwrap();
+        let chars: Vec<char> = text.chars().collect();
+        for end in 0..=chars.len() {
+            let prefix: String = chars[..end].iter().collect();
+            let mut parser = PartialJson::new();
+            parser.push(&prefix).unwrap();
+            if let Some(snapshot) = parser.snapshot() {
+                // Anything shown is a real, if truncated, part of the whole
+                assert!(snapshot.is_object(), "prefix: {}", prefix);
+            }
+            assert_eq!(parser.is_complete(), end == chars.len());
+        }
+
+        // Fed a character at a time, the same as all at once
+        let mut parser = PartialJson::new();
+        let mut last_keys = 0;
+        for c in &chars {
+            parser.push(&c.to_string()).unwrap();
+            let keys = parser
+                .snapshot()
+                .map_or(0, |s| s.as_object().unwrap().len());
+            assert!(keys >= last_keys);
+            last_keys = keys;
+        }
+        assert_eq!(parser.snapshot(), Some(whole));
+    }
+
+    #[test]
+    fn root_number_complete_once_finished() {
+        let mut parser = PartialJson::new();
+        parser.push("4").unwrap();
+        parser.push("2").unwrap();
+        assert!(!parser.is_complete());
+        parser.finish();
+        assert!(parser.is_complete());
+        assert_eq!(parser.snapshot(), Some(json!(42)));
+        assert!(parser.push("1").is_err());
+
+        let mut parser = PartialJson::new();
+        parser.push("-1.").unwrap();
+        parser.finish();
+        assert!(!parser.is_complete());
+        assert_eq!(parser.snapshot(), Some(json!(-1)));
+    }
+
+    #[test]
+    fn invalid_json_rejected_and_last_snapshot_kept() {
+        let mut parser = PartialJson::new();
+        parser.push(r#"{"a": 1, "#).unwrap();
+        match parser.push(r#"]"#) {
+            Err(StreamError::InvalidJson { offset, found }) => {
+                assert_eq!((offset, found), (9, ']'));
+            }
+            other => panic!("Expected invalid json, got {:?}", other),
+        }
+        assert!(parser.push("\"b\": 2}").is_err());
+        assert_eq!(parser.snapshot(), Some(json!({"a": 1})));
+
+        for invalid in [r#"{"a" 1}"#, "[1 2]", "{'a': 1}", "[01]", "{} {}", "[tru3]"] {
+            assert!(PartialJson::new().push(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
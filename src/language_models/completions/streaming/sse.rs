@@ -0,0 +1,399 @@
+use super::{
+    CompletionStream, ProviderStreamHandler, StreamError, StreamResponse, StreamResult,
+    StreamedCompletionHandler,
+};
+use crate::language_models::completions::{
+    error::{CompletionError, CompletionResult},
+    inference::{CompletionRequest, ProcessResponseReturn},
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::Response;
+use reqwest_streams::error::{StreamBodyError, StreamBodyKind};
+use serde_json::Value;
+use std::{collections::VecDeque, fmt::Debug, marker::PhantomData};
+
+/// One event of a server-sent events stream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// Name given by an `event:` line, `None` for the default `message` event
+    pub event: Option<String>,
+    /// Every `data:` line of the event joined with newlines
+    pub data: String,
+    pub id: Option<String>,
+}
+
+/// Incremental decoder of the `text/event-stream` format. Bytes can be fed in however they were
+/// chunked on the wire, events are returned once the blank line ending them has arrived
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    pending: SseEvent,
+    started: bool,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of the body, returning every event it completed
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some((line_len, terminator_len)) = self.next_line() {
+            let line: Vec<u8> = self.buffer.drain(..line_len + terminator_len).collect();
+            if let Some(event) = self.process_line(&line[..line_len]) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Call once the body has ended. An event missing its final blank line is still returned
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let line = std::mem::take(&mut self.buffer);
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        let event = match line.is_empty() {
+            true => None,
+            false => self.process_line(line),
+        };
+        event.or_else(|| self.dispatch())
+    }
+
+    /// Length of the first complete line in the buffer and of its terminator. A trailing `\r` isn't
+    /// complete yet, since the `\n` of a `\r\n` may be in the next chunk
+    fn next_line(&self) -> Option<(usize, usize)> {
+        let pos = self
+            .buffer
+            .iter()
+            .position(|b| *b == b'\n' || *b == b'\r')?;
+        match self.buffer[pos] {
+            b'\n' => Some((pos, 1)),
+            _ => match self.buffer.get(pos + 1) {
+                Some(b'\n') => Some((pos, 2)),
+                Some(_) => Some((pos, 1)),
+                None => None,
+            },
+        }
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        let mut line = String::from_utf8_lossy(line).into_owned();
+        if !self.started {
+            self.started = true;
+            if let Some(stripped) = line.strip_prefix('\u{feff}') {
+                line = stripped.to_owned();
+            }
+        }
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+        match field {
+            "event" => self.pending.event = Some(value.to_owned()),
+            "data" => {
+                self.pending.data.push_str(value);
+                self.pending.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.pending.id = Some(value.to_owned()),
+            _ => {}
+        }
+        None
+    }
+
+    /// Events without data are dropped, as the spec says
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let mut event = std::mem::take(&mut self.pending);
+        if event.data.is_empty() {
+            return None;
+        }
+        event.data.pop();
+        Some(event)
+    }
+}
+
+impl SseEvent {
+    /// OpenAi ends its streams with a `[DONE]` event
+    pub fn is_done(&self) -> bool {
+        self.data == "[DONE]"
+    }
+
+    /// Parse the event's data, an error event becomes the `CompletionError` it describes.
+    /// Anthropic names these events `error`, OpenAi sends an object with an `error` field
+    pub fn into_json(self) -> StreamResult<Value> {
+        let json: Value = serde_json::from_str(&self.data)?;
+        let is_error = self.event.as_deref() == Some("error")
+            || json.get("type").and_then(|t| t.as_str()) == Some("error")
+            || json.get("error").is_some_and(|e| !e.is_null());
+        match is_error {
+            true => Err(StreamError::Provider(Box::new(
+                CompletionError::from_provider(None, None, self.data),
+            ))),
+            false => Ok(json),
+        }
+    }
+}
+
+/// Json payloads of a server-sent events body, ending at a `[DONE]` event or the end of the body
+pub fn sse_json_stream<S>(body: S) -> CompletionStream
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+{
+    struct State<S> {
+        body: S,
+        decoder: SseDecoder,
+        events: VecDeque<SseEvent>,
+        done: bool,
+    }
+    let state = State {
+        body,
+        decoder: SseDecoder::new(),
+        events: VecDeque::new(),
+        done: false,
+    };
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+            if let Some(event) = state.events.pop_front() {
+                if event.is_done() {
+                    return None;
+                }
+                let item = event.into_json();
+                state.done = item.is_err();
+                return Some((item, state));
+            }
+            match state.body.next().await {
+                Some(Ok(chunk)) => state.events.extend(state.decoder.feed(&chunk)),
+                Some(Err(err)) => {
+                    state.done = true;
+                    let err = StreamBodyError::new(
+                        StreamBodyKind::InputOutputError,
+                        Some(Box::new(err)),
+                        None,
+                    );
+                    return Some((Err(err.into()), state));
+                }
+                // An event missing its final blank line is the last one
+                None => {
+                    state.done = true;
+                    return match state.decoder.finish() {
+                        Some(event) if !event.is_done() => Some((event.into_json(), state)),
+                        _ => None,
+                    };
+                }
+            }
+        }
+    });
+    Box::new(Box::pin(stream))
+}
+
+/// Request whose body is already serialized and whose response is a server-sent events stream of
+/// `T`s. Used for requests such as tool streams, which are built from a provider's json
+pub(crate) struct SseCompletionRequest<T> {
+    json: Value,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> SseCompletionRequest<T> {
+    pub(crate) fn new(json: Value) -> Self {
+        Self {
+            json,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Debug for SseCompletionRequest<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SseCompletionRequest")
+            .field(&self.json)
+            .finish()
+    }
+}
+
+impl<T> CompletionRequest for SseCompletionRequest<T>
+where
+    T: StreamResponse,
+    StreamedCompletionHandler<T>: Into<ProviderStreamHandler>,
+{
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(self.json.clone())
+    }
+
+    fn process_response<'r>(&'r self, response: Response) -> ProcessResponseReturn<'r> {
+        Box::pin(async move {
+            let stream = sse_json_stream(response.bytes_stream());
+            let handler: ProviderStreamHandler =
+                StreamedCompletionHandler::<T>::from(stream).into();
+            Ok(handler.into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_models::completions::{
+        anthropic::streaming::AnthropicStreamResponse,
+        openai::streaming::OpenAiStreamResponse,
+        streaming::{CompletionStreamStatus, StreamResponse},
+    };
+
+    const OPENAI_BODY: &str = concat!(
+        ": keep-alive\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Héllo\"},\"finish_reason\":null}]}\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" wörld 🌍\"},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":3,\"total_tokens\":8}}\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    const ANTHROPIC_BODY: &str = concat!(
+        "event: message_start\r\n",
+        "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"type\":\"message\",\"role\":\"assistant\",\"content\":[],\"model\":\"claude-3-haiku-20240307\",\"stop_reason\":null,\"stop_sequence\":null,\"usage\":{\"input_tokens\":10,\"output_tokens\":1}}}\r\n",
+        "\r\n",
+        "event: ping\r\n",
+        "data: {\"type\": \"ping\"}\r\n",
+        "\r\n",
+        "event: content_block_delta\r\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\r\n",
+        "\r\n",
+        "event: error\r\n",
+        "data: {\"type\": \"error\", \"error\": {\"type\": \"overloaded_error\", \"message\": \"Overloaded\"}}\r\n",
+        "\r\n",
+    );
+
+    /// Every event `body` decodes to when fed in chunks of `size` bytes
+    fn decode_in_chunks(body: &[u8], size: usize) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<_> = body.chunks(size).flat_map(|c| decoder.feed(c)).collect();
+        events.extend(decoder.finish());
+        events
+    }
+
+    #[test]
+    fn events_survive_any_chunking() {
+        for body in [OPENAI_BODY, ANTHROPIC_BODY] {
+            let whole = decode_in_chunks(body.as_bytes(), body.len());
+            for size in 1..body.len() {
+                assert_eq!(decode_in_chunks(body.as_bytes(), size), whole);
+            }
+            // Split in two at every byte, including inside multibyte characters and `\r\n`
+            for split in 1..body.len() {
+                let (head, tail) = body.as_bytes().split_at(split);
+                let mut decoder = SseDecoder::new();
+                let mut events = decoder.feed(head);
+                events.extend(decoder.feed(tail));
+                events.extend(decoder.finish());
+                assert_eq!(events, whole);
+            }
+        }
+    }
+
+    #[test]
+    fn openai_events_feed_stream_responses() {
+        let events = decode_in_chunks(OPENAI_BODY.as_bytes(), 7);
+        assert_eq!(events.len(), 5);
+        assert!(events[4].is_done());
+
+        let mut tokens = String::new();
+        let mut finished = false;
+        for event in events.into_iter().take_while(|e| !e.is_done()) {
+            let chunk: OpenAiStreamResponse =
+                serde_json::from_value(event.into_json().unwrap()).unwrap();
+            match chunk.into() {
+                CompletionStreamStatus::Working(token) => tokens.push_str(&token),
+                CompletionStreamStatus::Finished => finished = true,
+            }
+        }
+        assert_eq!(tokens, "Héllo wörld 🌍");
+        assert!(finished);
+    }
+
+    #[test]
+    fn anthropic_events_named_and_errors_typed() {
+        let events = decode_in_chunks(ANTHROPIC_BODY.as_bytes(), 3);
+        let names: Vec<_> = events.iter().map(|e| e.event.as_deref()).collect();
+        assert_eq!(
+            names,
+            [
+                Some("message_start"),
+                Some("ping"),
+                Some("content_block_delta"),
+                Some("error")
+            ]
+        );
+
+        let start: AnthropicStreamResponse =
+            serde_json::from_value(events[0].clone().into_json().unwrap()).unwrap();
+        assert_eq!(start.metadata().unwrap().id.as_deref(), Some("msg_1"));
+
+        match events[3].clone().into_json() {
+            Err(StreamError::Provider(err)) => {
+                assert!(matches!(*err, CompletionError::Overloaded { .. }))
+            }
+            other => panic!("Expected a typed provider error, got {:?}", other),
+        }
+
+        let openai_error = SseEvent {
+            data: r#"{"error": {"message": "Rate limit reached", "type": "rate_limit_exceeded"}}"#
+                .to_owned(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            openai_error.into_json(),
+            Err(StreamError::Provider(err)) if matches!(*err, CompletionError::RateLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn multiline_data_and_unterminated_event() {
+        let mut decoder = SseDecoder::new();
+        let events = decoder.feed(
+            b"\xEF\xBB\xBFid: 7\ndata: {\"a\":\ndata:1}\n\nretry: 100\nevent: last\ndata: {}",
+        );
+        assert_eq!(
+            events,
+            [SseEvent {
+                event: None,
+                data: "{\"a\":\n1}".to_owned(),
+                id: Some("7".to_owned()),
+            }]
+        );
+        assert_eq!(
+            events[0].clone().into_json().unwrap(),
+            serde_json::json!({"a": 1})
+        );
+        let last = decoder.finish().unwrap();
+        assert_eq!(last.event.as_deref(), Some("last"));
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[tokio::test]
+    async fn json_stream_ends_at_done_or_error() {
+        let chunks = |body: &'static str| {
+            futures::stream::iter(
+                body.as_bytes()
+                    .chunks(11)
+                    .map(|c| Ok(Bytes::copy_from_slice(c)))
+                    .collect::<Vec<reqwest::Result<Bytes>>>(),
+            )
+        };
+        let items: Vec<_> = sse_json_stream(chunks(OPENAI_BODY)).collect().await;
+        assert_eq!(items.len(), 4);
+        assert!(items.iter().all(|i| i.is_ok()));
+
+        let items: Vec<_> = sse_json_stream(chunks(ANTHROPIC_BODY)).collect().await;
+        assert_eq!(items.len(), 4);
+        assert!(matches!(items[3], Err(StreamError::Provider(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::errors::error_chain_fmt;
+use crate::{errors::error_chain_fmt, language_models::completions::error::CompletionError};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 pub type EmbeddingResult<T> = Result<T, EmbeddingError>;
@@ -9,6 +9,8 @@ pub enum EmbeddingError {
     Undefined(#[from] anyhow::Error),
     Json(#[from] serde_json::Error),
     Request(#[from] reqwest::Error),
+    /// Provider responded with an error status, see `CompletionError` for the kinds
+    Provider(#[from] CompletionError),
 }
 
 impl Debug for EmbeddingError {
@@ -23,6 +25,7 @@ impl Display for EmbeddingError {
             Self::Json(err) => err.to_string(),
             Self::Undefined(err) => err.to_string(),
             Self::Request(err) => err.to_string(),
+            Self::Provider(err) => err.to_string(),
         };
         write!(f, "{}", display)
     }
@@ -1,15 +1,20 @@
 use super::error::EmbeddingResult;
+use crate::language_models::{completions::metadata::Completion, pricing::ModelPricing};
 use futures::Future;
 use reqwest::{header::HeaderMap, Response};
 use serde_json::Value;
 use std::{fmt::Debug, pin::Pin};
 
 pub type ProcessEmbeddingResponseReturn<'r> =
-    Pin<Box<dyn Future<Output = EmbeddingResult<Vec<f32>>> + Send + Sync + 'r>>;
+    Pin<Box<dyn Future<Output = EmbeddingResult<Completion<Vec<f32>>>> + Send + Sync + 'r>>;
 pub trait EmbeddingRequest: Debug + Sync + Send + 'static {
     fn headers(&self, api_key: &str) -> HeaderMap;
     fn model_str(&self) -> &str;
-    fn url_str(&self) -> &str;
+    fn url_str(&self) -> String;
     fn as_json(&self, text: &str) -> EmbeddingResult<Value>;
     fn process_response<'r>(&'r self, response: Response) -> ProcessEmbeddingResponseReturn;
+    /// Price used to work out what an embedding cost when the `EmbeddingModel` doesn't override it
+    fn pricing(&self) -> Option<ModelPricing> {
+        None
+    }
 }
@@ -1,20 +1,64 @@
-use self::{error::EmbeddingResult, inference::EmbeddingRequest, openai::OpenAiEmbeddingModel};
+use self::{
+    error::EmbeddingResult,
+    inference::EmbeddingRequest,
+    ollama::OllamaEmbeddingModel,
+    openai::{AzureOpenAiEmbeddingModel, OpenAiCompatibleEmbeddingModel, OpenAiEmbeddingModel},
+};
+use crate::language_models::{
+    completions::{
+        error::error_for_status,
+        metadata::{Completion, TokenUsage},
+    },
+    pricing::ModelPricing,
+    rate_limit::{estimate_tokens, RateLimiter},
+};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 pub mod error;
 pub mod inference;
+pub mod ollama;
 pub mod openai;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EmbeddingProvider {
     OpenAi(OpenAiEmbeddingModel),
+    OpenAiCompatible(OpenAiCompatibleEmbeddingModel),
+    Ollama(OllamaEmbeddingModel),
+    AzureOpenAi(AzureOpenAiEmbeddingModel),
+}
+
+impl From<OpenAiEmbeddingModel> for EmbeddingProvider {
+    fn from(value: OpenAiEmbeddingModel) -> Self {
+        Self::OpenAi(value)
+    }
+}
+
+impl From<OpenAiCompatibleEmbeddingModel> for EmbeddingProvider {
+    fn from(value: OpenAiCompatibleEmbeddingModel) -> Self {
+        Self::OpenAiCompatible(value)
+    }
+}
+
+impl From<OllamaEmbeddingModel> for EmbeddingProvider {
+    fn from(value: OllamaEmbeddingModel) -> Self {
+        Self::Ollama(value)
+    }
+}
+
+impl From<AzureOpenAiEmbeddingModel> for EmbeddingProvider {
+    fn from(value: AzureOpenAiEmbeddingModel) -> Self {
+        Self::AzureOpenAi(value)
+    }
 }
 
 impl EmbeddingProvider {
     fn inner_request(&self) -> Box<&dyn EmbeddingRequest> {
         match &self {
-            Self::OpenAi(b) => return Box::new(b),
+            Self::OpenAi(b) => Box::new(b),
+            Self::OpenAiCompatible(b) => Box::new(b),
+            Self::Ollama(b) => Box::new(b),
+            Self::AzureOpenAi(b) => Box::new(b),
         }
     }
 }
@@ -22,25 +66,111 @@ impl EmbeddingProvider {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmbeddingModel {
     provider: EmbeddingProvider,
+    /// Overrides the provider's default price, see `EmbeddingModel::pricing`
+    #[serde(default)]
+    pricing: Option<ModelPricing>,
+    #[serde(skip)]
+    rate_limiter: Option<RateLimiter>,
     api_key: String,
     #[serde(skip)]
     client: Client,
 }
 
 impl EmbeddingModel {
+    pub fn new(m: impl Into<EmbeddingProvider>, api_key: &str) -> Self {
+        Self {
+            provider: m.into(),
+            pricing: None,
+            rate_limiter: None,
+            api_key: api_key.to_owned(),
+            client: Client::new(),
+        }
+    }
+
+    /// Model served by anything that implements OpenAi's embeddings API. Pass `None` as
+    /// `api_key` if the server doesn't require auth
+    pub fn openai_compatible(base_url: &str, model: &str, api_key: Option<&str>) -> Self {
+        Self::new(
+            OpenAiCompatibleEmbeddingModel::new(base_url, model),
+            api_key.unwrap_or_default(),
+        )
+    }
+
+    /// Embedding model deployed to an Azure OpenAi resource
+    pub fn azure_openai(
+        resource: &str,
+        deployment: &str,
+        api_version: &str,
+        api_key: &str,
+    ) -> Self {
+        Self::new(
+            AzureOpenAiEmbeddingModel::new(resource, deployment, api_version),
+            api_key,
+        )
+    }
+
+    /// Model served by a local Ollama instance on its default port
+    pub fn ollama(model: &str) -> Self {
+        Self::new(
+            OllamaEmbeddingModel::new(
+                crate::language_models::completions::ollama::builder::DEFAULT_OLLAMA_URL,
+                model,
+            ),
+            "",
+        )
+    }
+
     pub fn default_openai(api_key: &str) -> Self {
         let client = Client::new();
         Self {
             provider: EmbeddingProvider::OpenAi(OpenAiEmbeddingModel::default()),
+            pricing: None,
+            rate_limiter: None,
             api_key: api_key.to_owned(),
             client,
         }
     }
 
+    /// Price embeddings from this model at `pricing` instead of the provider's default
+    pub fn with_pricing(mut self, pricing: ModelPricing) -> Self {
+        self.pricing = Some(pricing);
+        self
+    }
+
+    /// Queue requests behind `limiter`, which can be shared with `CompletionModel`s using the same
+    /// api key
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Price of this model, `None` if it was never set and the provider doesn't know it
+    pub fn pricing(&self) -> Option<ModelPricing> {
+        self.pricing
+            .or_else(|| self.provider.inner_request().pricing())
+    }
+
+    /// Dollar cost of `usage` on this model. Free if the price isn't known
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        self.pricing().map(|p| p.cost(usage)).unwrap_or_default()
+    }
+
     pub async fn get_embedding(&self, text: &str) -> EmbeddingResult<Vec<f32>> {
+        Ok(self.get_embedding_with_metadata(text).await?.into_content())
+    }
+
+    /// Same as `get_embedding`, along with the tokens the provider billed for it
+    pub async fn get_embedding_with_metadata(
+        &self,
+        text: &str,
+    ) -> EmbeddingResult<Completion<Vec<f32>>> {
         let request = self.provider.inner_request();
         let headers = request.headers(&self.api_key);
         let url = request.url_str();
+        let estimated_tokens = estimate_tokens(text);
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(estimated_tokens).await;
+        }
         let response = self
             .client
             .post(url)
@@ -48,6 +178,11 @@ impl EmbeddingModel {
             .json(&request.as_json(text)?)
             .send()
             .await?;
-        Ok(request.process_response(response).await?)
+        let response = error_for_status(response).await?;
+        let embedding = request.process_response(response).await?;
+        if let (Some(limiter), Some(usage)) = (&self.rate_limiter, embedding.metadata.usage) {
+            limiter.reconcile(estimated_tokens, usage.total_tokens());
+        }
+        Ok(embedding)
     }
 }
@@ -0,0 +1,71 @@
+use super::inference::EmbeddingRequest;
+use crate::language_models::completions::{
+    metadata::{Completion, CompletionMetadata},
+    ollama::builder::DEFAULT_OLLAMA_URL,
+};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Embedding model served through Ollama's native API. `base_url` is everything before
+/// `/api/embeddings`, e.g. `http://localhost:11434`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OllamaEmbeddingModel {
+    pub base_url: String,
+    pub model: String,
+}
+
+impl Default for OllamaEmbeddingModel {
+    fn default() -> Self {
+        Self::new(DEFAULT_OLLAMA_URL, "nomic-embed-text")
+    }
+}
+
+impl OllamaEmbeddingModel {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            model: model.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum OllamaEmbeddingResponse {
+    Success { embedding: Vec<f32> },
+    Err { error: String },
+}
+
+impl EmbeddingRequest for OllamaEmbeddingModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+    fn url_str(&self) -> String {
+        format!("{}/api/embeddings", self.base_url)
+    }
+    fn headers(&self, _api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("Content-Type", "application/json".parse().unwrap());
+        map
+    }
+    fn as_json(&self, text: &str) -> super::error::EmbeddingResult<serde_json::Value> {
+        Ok(json!({ "prompt": text, "model": self.model_str()}))
+    }
+    fn process_response(
+        &self,
+        response: reqwest::Response,
+    ) -> super::inference::ProcessEmbeddingResponseReturn<'_> {
+        Box::pin(async {
+            let json = response.json().await?;
+            match serde_json::from_value(json)? {
+                OllamaEmbeddingResponse::Success { embedding } => {
+                    Ok(Completion::new(embedding, CompletionMetadata::default()))
+                }
+                OllamaEmbeddingResponse::Err { error } => {
+                    Err(anyhow::anyhow!("Provider error: {}", error).into())
+                }
+            }
+        })
+    }
+}
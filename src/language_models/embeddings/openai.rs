@@ -1,5 +1,14 @@
 use super::inference::EmbeddingRequest;
-use crate::language_models::completions::openai::requests::OpenAiUsage;
+use crate::language_models::{
+    completions::{
+        metadata::{Completion, CompletionMetadata},
+        openai::{
+            builder::{azure_auth_headers, azure_deployment_url, bearer_auth_headers},
+            requests::OpenAiUsage,
+        },
+    },
+    pricing::{default_pricing, ModelPricing},
+};
 use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -23,6 +32,24 @@ pub struct OpenAiEmbeddingResponse {
     pub usage: OpenAiUsage,
 }
 
+// Shared by every model which speaks OpenAi's embeddings API
+pub(super) fn process_openai_embedding_response(
+    response: reqwest::Response,
+) -> super::inference::ProcessEmbeddingResponseReturn<'static> {
+    Box::pin(async {
+        let json = response.json().await?;
+        let response: OpenAiEmbeddingResponse = serde_json::from_value(json)?;
+        let metadata = CompletionMetadata {
+            usage: Some(response.usage.into()),
+            ..Default::default()
+        };
+        Ok(Completion::new(
+            response.data[0].embedding.to_owned(),
+            metadata,
+        ))
+    })
+}
+
 impl EmbeddingRequest for OpenAiEmbeddingModel {
     fn model_str(&self) -> &str {
         match self {
@@ -31,17 +58,11 @@ impl EmbeddingRequest for OpenAiEmbeddingModel {
             Self::Ada => "text-embedding-ada-002",
         }
     }
-    fn url_str(&self) -> &str {
-        "https://api.openai.com/v1/embeddings"
+    fn url_str(&self) -> String {
+        "https://api.openai.com/v1/embeddings".to_owned()
     }
     fn headers(&self, api_key: &str) -> HeaderMap {
-        let mut map = HeaderMap::new();
-        map.insert(
-            "Authorization",
-            format!("Bearer {}", api_key).parse().unwrap(),
-        );
-        map.insert("Content-Type", "application/json".parse().unwrap());
-        map
+        bearer_auth_headers(api_key)
     }
     fn as_json(&self, text: &str) -> super::error::EmbeddingResult<serde_json::Value> {
         Ok(json!({ "input": text, "model": self.model_str()}))
@@ -50,10 +71,92 @@ impl EmbeddingRequest for OpenAiEmbeddingModel {
         &'r self,
         response: reqwest::Response,
     ) -> super::inference::ProcessEmbeddingResponseReturn {
-        Box::pin(async {
-            let json = response.json().await?;
-            let response: OpenAiEmbeddingResponse = serde_json::from_value(json)?;
-            Ok(response.data[0].embedding.to_owned())
-        })
+        process_openai_embedding_response(response)
+    }
+    fn pricing(&self) -> Option<ModelPricing> {
+        default_pricing(self.model_str())
+    }
+}
+
+/// Any server that implements OpenAi's embeddings API. `base_url` is everything before
+/// `/embeddings`, e.g. `http://localhost:8000/v1`
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OpenAiCompatibleEmbeddingModel {
+    pub base_url: String,
+    pub model: String,
+}
+
+impl OpenAiCompatibleEmbeddingModel {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            model: model.to_owned(),
+        }
+    }
+}
+
+impl EmbeddingRequest for OpenAiCompatibleEmbeddingModel {
+    fn model_str(&self) -> &str {
+        &self.model
+    }
+    fn url_str(&self) -> String {
+        format!("{}/embeddings", self.base_url)
+    }
+    /// Authorization header is only sent if `api_key` isn't empty
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        bearer_auth_headers(api_key)
+    }
+    fn as_json(&self, text: &str) -> super::error::EmbeddingResult<serde_json::Value> {
+        Ok(json!({ "input": text, "model": self.model_str()}))
+    }
+    fn process_response(
+        &self,
+        response: reqwest::Response,
+    ) -> super::inference::ProcessEmbeddingResponseReturn<'_> {
+        process_openai_embedding_response(response)
+    }
+}
+
+/// Embedding model deployed to an Azure OpenAi resource
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AzureOpenAiEmbeddingModel {
+    pub resource: String,
+    pub deployment: String,
+    pub api_version: String,
+}
+
+impl AzureOpenAiEmbeddingModel {
+    pub fn new(resource: &str, deployment: &str, api_version: &str) -> Self {
+        Self {
+            resource: resource.to_owned(),
+            deployment: deployment.to_owned(),
+            api_version: api_version.to_owned(),
+        }
+    }
+}
+
+impl EmbeddingRequest for AzureOpenAiEmbeddingModel {
+    fn model_str(&self) -> &str {
+        &self.deployment
+    }
+    fn url_str(&self) -> String {
+        azure_deployment_url(
+            &self.resource,
+            &self.deployment,
+            &self.api_version,
+            "embeddings",
+        )
+    }
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        azure_auth_headers(api_key)
+    }
+    fn as_json(&self, text: &str) -> super::error::EmbeddingResult<serde_json::Value> {
+        Ok(json!({ "input": text }))
+    }
+    fn process_response(
+        &self,
+        response: reqwest::Response,
+    ) -> super::inference::ProcessEmbeddingResponseReturn<'_> {
+        process_openai_embedding_response(response)
     }
 }
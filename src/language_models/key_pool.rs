@@ -0,0 +1,219 @@
+use super::completions::{error::CompletionError, metadata::TokenUsage};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Several api keys for the same provider, used in turn. Keys the provider rejects or rate limits
+/// are set aside for a while and the request moves on to the next one. Clones share the same keys
+/// and stats
+#[derive(Debug, Clone)]
+pub struct ApiKeyPool {
+    keys: Arc<Mutex<PoolState>>,
+    auth_cooldown: Duration,
+    rate_limit_cooldown: Duration,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    keys: Vec<PooledKey>,
+    next: usize,
+}
+
+#[derive(Debug)]
+struct PooledKey {
+    key: String,
+    disabled_until: Option<Instant>,
+    stats: KeyStats,
+}
+
+impl PooledKey {
+    fn is_available(&self, now: Instant) -> bool {
+        self.disabled_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// What a key of an `ApiKeyPool` has been used for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    /// Last four characters of the key, enough to tell keys apart in logs
+    pub key_hint: String,
+    pub requests: u64,
+    pub errors: u64,
+    /// Tokens used by completions made with the key. Streamed completions only count as requests,
+    /// since their usage isn't known until long after the key was picked
+    pub usage: TokenUsage,
+    /// Whether the key is currently set aside after an auth or rate limit error
+    pub disabled: bool,
+}
+
+impl ApiKeyPool {
+    /// Keys the provider rejects are set aside for ten minutes, rate limited keys for as long as
+    /// the provider asks, or a minute if it doesn't say
+    pub fn new<S: Into<String>>(keys: impl IntoIterator<Item = S>) -> Self {
+        let keys = keys
+            .into_iter()
+            .map(|key| {
+                let key: String = key.into();
+                let hint = key.chars().rev().take(4).collect::<Vec<_>>();
+                PooledKey {
+                    stats: KeyStats {
+                        key_hint: hint.into_iter().rev().collect(),
+                        ..Default::default()
+                    },
+                    key,
+                    disabled_until: None,
+                }
+            })
+            .collect();
+        Self {
+            keys: Arc::new(Mutex::new(PoolState { keys, next: 0 })),
+            auth_cooldown: Duration::from_secs(600),
+            rate_limit_cooldown: Duration::from_secs(60),
+        }
+    }
+
+    /// How long keys are set aside after authentication and rate limit errors
+    pub fn with_cooldowns(mut self, auth: Duration, rate_limit: Duration) -> Self {
+        self.auth_cooldown = auth;
+        self.rate_limit_cooldown = rate_limit;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stats of every key, in the order they were given
+    pub fn stats(&self) -> Vec<KeyStats> {
+        let now = Instant::now();
+        let state = self.keys.lock().unwrap();
+        state
+            .keys
+            .iter()
+            .map(|k| KeyStats {
+                disabled: !k.is_available(now),
+                ..k.stats.clone()
+            })
+            .collect()
+    }
+
+    /// Whether any key isn't set aside
+    pub(crate) fn has_available(&self) -> bool {
+        let now = Instant::now();
+        self.keys
+            .lock()
+            .unwrap()
+            .keys
+            .iter()
+            .any(|k| k.is_available(now))
+    }
+
+    /// Next available key in turn along with its index. If every key is set aside, the one that
+    /// will be back soonest is used anyway. Panics if the pool is empty
+    pub(crate) fn next_key(&self) -> (usize, String) {
+        let now = Instant::now();
+        let mut state = self.keys.lock().unwrap();
+        let len = state.keys.len();
+        let start = state.next;
+        let index = (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&i| state.keys[i].is_available(now))
+            .unwrap_or_else(|| {
+                (0..len)
+                    .min_by_key(|&i| state.keys[i].disabled_until)
+                    .expect("key pool is empty")
+            });
+        state.next = (index + 1) % len;
+        let key = &mut state.keys[index];
+        key.stats.requests += 1;
+        (index, key.key.clone())
+    }
+
+    pub(crate) fn record_success(&self, index: usize, usage: Option<TokenUsage>) {
+        if let Some(usage) = usage {
+            self.keys.lock().unwrap().keys[index].stats.usage += usage;
+        }
+    }
+
+    /// Counts the error against the key, setting it aside if the provider rejected or rate limited
+    /// it. Returns whether it was set aside
+    pub(crate) fn record_error(&self, index: usize, err: &CompletionError) -> bool {
+        let cooldown = match err {
+            CompletionError::Authentication { .. } => Some(self.auth_cooldown),
+            CompletionError::RateLimited { retry_after, .. } => {
+                Some(retry_after.unwrap_or(self.rate_limit_cooldown))
+            }
+            _ => None,
+        };
+        let mut state = self.keys.lock().unwrap();
+        let key = &mut state.keys[index];
+        key.stats.errors += 1;
+        if let Some(cooldown) = cooldown {
+            warn!(
+                "Setting aside key ending in {} for {:?}: {}",
+                key.stats.key_hint, cooldown, err
+            );
+            key.disabled_until = Some(Instant::now() + cooldown);
+        }
+        cooldown.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limited() -> CompletionError {
+        CompletionError::RateLimited {
+            retry_after: None,
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn keys_rotated_and_set_aside() {
+        let pool = ApiKeyPool::new(["key-aaaa", "key-bbbb", "key-cccc"]);
+        let picked: Vec<_> = (0..4).map(|_| pool.next_key().1).collect();
+        assert_eq!(picked, ["key-aaaa", "key-bbbb", "key-cccc", "key-aaaa"]);
+
+        assert!(pool.record_error(1, &rate_limited()));
+        assert!(!pool.record_error(
+            2,
+            &CompletionError::Server {
+                status: 500,
+                body: String::new()
+            }
+        ));
+        let picked: Vec<_> = (0..3).map(|_| pool.next_key().1).collect();
+        assert_eq!(picked, ["key-cccc", "key-aaaa", "key-cccc"]);
+
+        pool.record_success(0, Some(TokenUsage::new(10, 5)));
+        let stats = pool.stats();
+        assert_eq!(stats[0].key_hint, "aaaa");
+        assert_eq!(stats[0].requests, 3);
+        assert_eq!(stats[0].usage, TokenUsage::new(10, 5));
+        assert!(stats[1].disabled);
+        assert_eq!(stats[2].errors, 1);
+        assert!(!stats[2].disabled);
+    }
+
+    #[test]
+    fn soonest_key_used_when_all_set_aside() {
+        let pool = ApiKeyPool::new(["first", "second"]);
+        pool.record_error(
+            0,
+            &CompletionError::Authentication {
+                body: String::new(),
+            },
+        );
+        pool.record_error(1, &rate_limited());
+        assert!(!pool.has_available());
+        assert_eq!(pool.next_key().1, "second");
+    }
+}
@@ -1,2 +1,5 @@
 pub mod completions;
 pub mod embeddings;
+pub mod key_pool;
+pub mod pricing;
+pub mod rate_limit;
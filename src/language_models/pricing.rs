@@ -0,0 +1,55 @@
+use super::completions::metadata::TokenUsage;
+use serde::{Deserialize, Serialize};
+
+/// Dollar price of a model, per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPricing {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl ModelPricing {
+    pub fn new(input_per_million: f64, output_per_million: f64) -> Self {
+        Self {
+            input_per_million,
+            output_per_million,
+        }
+    }
+
+    /// Dollar cost of `usage` at this price
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input_per_million
+            + usage.completion_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// Published price of a model, looked up by the name the provider knows it by. `None` for models
+/// this crate doesn't know the price of
+pub fn default_pricing(model: &str) -> Option<ModelPricing> {
+    let (input, output) = match model {
+        "gpt-3.5-turbo-0125" => (0.5, 1.5),
+        "gpt-4-0125-preview" => (10.0, 30.0),
+        "claude-3-opus-20240229" => (15.0, 75.0),
+        "claude-3-sonnet-20240229" => (3.0, 15.0),
+        "claude-3-haiku-20240307" => (0.25, 1.25),
+        "text-embedding-3-small" => (0.02, 0.0),
+        "text-embedding-3-large" => (0.13, 0.0),
+        "text-embedding-ada-002" => (0.1, 0.0),
+        _ => return None,
+    };
+    Some(ModelPricing::new(input, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_priced_correctly() {
+        let pricing = default_pricing("claude-3-haiku-20240307").unwrap();
+        let cost = pricing.cost(&TokenUsage::new(2_000_000, 400_000));
+        assert!((cost - 1.0).abs() < 1e-9);
+        assert!(default_pricing("llama3").is_none());
+    }
+}
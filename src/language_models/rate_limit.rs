@@ -0,0 +1,202 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+use tracing::info;
+
+/// Client side limit on requests and tokens per minute. Clones share the same buckets, so one
+/// limiter can be given to every `CompletionModel` and `EmbeddingModel` using the same api key.
+/// Requests wait in the order they arrived until there is room for them instead of failing
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+    /// Held by the request at the front of the queue while it waits for room
+    queue: Arc<tokio::sync::Mutex<()>>,
+    /// Wakes the waiting request early when tokens are given back
+    returned: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+/// Holds up to `capacity`, refilled continuously so it's full again after a minute
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            available: per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// How long until `amount` is available. Amounts bigger than the bucket only wait for it to fill
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        match missing > 0.0 {
+            true => Duration::from_secs_f64(missing * 60.0 / self.capacity),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Either limit can be left as `None`. Tokens are estimated from the size of each request
+    /// until the provider reports what it used
+    pub fn new(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> Self {
+        let buckets = Buckets {
+            requests: requests_per_minute.map(|n| TokenBucket::new(n.max(1))),
+            tokens: tokens_per_minute.map(|n| TokenBucket::new(n.max(1))),
+        };
+        Self {
+            buckets: Arc::new(Mutex::new(buckets)),
+            queue: Arc::new(tokio::sync::Mutex::new(())),
+            returned: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Wait until there's room for one request of `estimated_tokens`, then take it
+    pub(crate) async fn acquire(&self, estimated_tokens: u32) {
+        let _turn = self.queue.lock().await;
+        loop {
+            // Created before the buckets are checked so tokens given back in between aren't missed
+            let returned = self.returned.notified();
+            // Buckets are only locked to check them, so finished requests can reconcile while
+            // this one sleeps
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let wait = buckets.wait_for(estimated_tokens as f64);
+                if wait.is_zero() {
+                    buckets.take(estimated_tokens as f64);
+                    return;
+                }
+                wait
+            };
+            info!("Rate limited client side, waiting {:?}", wait);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = returned => {}
+            }
+        }
+    }
+
+    /// Correct a request's estimate once the provider has said how many tokens it used. Can leave
+    /// the bucket in debt, which later requests wait out
+    pub(crate) fn reconcile(&self, estimated_tokens: u32, used_tokens: u32) {
+        if let Some(tokens) = self.buckets.lock().unwrap().tokens.as_mut() {
+            tokens.refill();
+            tokens.available += estimated_tokens as f64 - used_tokens as f64;
+            tokens.available = tokens.available.min(tokens.capacity);
+        }
+        if used_tokens < estimated_tokens {
+            self.returned.notify_waiters();
+        }
+    }
+}
+
+impl Buckets {
+    fn wait_for(&mut self, estimated_tokens: f64) -> Duration {
+        [
+            self.requests.as_mut().map(|b| (b, 1.0)),
+            self.tokens.as_mut().map(|b| (b, estimated_tokens)),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(bucket, amount)| {
+            bucket.refill();
+            bucket.wait_for(amount)
+        })
+        .max()
+        .unwrap_or_default()
+    }
+
+    fn take(&mut self, estimated_tokens: f64) {
+        if let Some(requests) = self.requests.as_mut() {
+            requests.available -= 1.0;
+        }
+        if let Some(tokens) = self.tokens.as_mut() {
+            tokens.available -= estimated_tokens;
+        }
+    }
+}
+
+/// Rough token count of `text`, about four characters a token
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32 / 4).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Time is paused, so `elapsed` is exactly how long the limiter made the test sleep
+    #[tokio::test(start_paused = true)]
+    async fn requests_queue_once_bucket_empty() {
+        // Refills one request every 100ms
+        let limiter = RateLimiter::new(Some(600), None);
+        let start = Instant::now();
+        for _ in 0..600 {
+            limiter.acquire(0).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(0).await;
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(99) && elapsed <= Duration::from_millis(101),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_limited_and_reconciled() {
+        // Refills 100 tokens a second
+        let limiter = RateLimiter::new(None, Some(6000));
+        let start = Instant::now();
+        limiter.acquire(6000).await;
+        // Provider only used half the estimate, so the rest is given back
+        limiter.reconcile(6000, 3000);
+        limiter.acquire(3000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(30).await;
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(299) && elapsed <= Duration::from_millis(301),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_given_back_while_waiting() {
+        let limiter = RateLimiter::new(None, Some(6000));
+        let start = Instant::now();
+        limiter.acquire(6000).await;
+        // Would wait 30 seconds for the bucket to refill
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(3000).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        limiter.reconcile(6000, 1000);
+        waiting.await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
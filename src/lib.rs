@@ -2,6 +2,12 @@ pub mod agents;
 pub mod errors;
 pub mod language_models;
 pub mod telemetry;
+
+/// Used by the code `espionox-derive` generates
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
 #[cfg(feature = "tools")]
 pub mod tools;
 
@@ -1,7 +1,13 @@
-use crate::{init_test, test_anthropic_agent, test_openai_agent};
+use crate::{init_test, test_anthropic_agent, test_gemini_agent, test_openai_agent, StandInServer};
 use espionox::{
-    agents::memory::Message,
-    language_models::completions::{functions::Function, streaming::ProviderStreamHandler},
+    agents::{
+        memory::{Message, MessageImage},
+        tools::ToolBox,
+        Agent, AgentError,
+    },
+    language_models::completions::{
+        functions::Function, streaming::ProviderStreamHandler, CompletionModel,
+    },
 };
 use serde::Deserialize;
 use serde_json::json;
@@ -22,6 +28,12 @@ async fn io_prompt_agent_works() {
     a.cache.push(Message::new_user("Hello!"));
     let result = a.io_completion().await;
     info!("response: {:?}", result);
+    assert!(result.is_ok());
+
+    let mut a = test_gemini_agent();
+    a.cache.push(Message::new_user("Hello!"));
+    let result = a.io_completion().await;
+    info!("response: {:?}", result);
     assert!(result.is_ok())
 }
 
@@ -46,6 +58,15 @@ async fn stream_prompt_agent_works() {
         info!("Anthropic token: {:?}", res)
     }
     info!("CACHE: {:?}", a.cache);
+    assert_eq!(a.cache.len(), 3);
+
+    let mut a = test_gemini_agent();
+    a.cache.push(Message::new_user("Hello!"));
+
+    let mut response: ProviderStreamHandler = a.stream_completion().await.unwrap();
+    while let Ok(Some(res)) = response.receive(&mut a).await {
+        info!("Gemini token: {:?}", res)
+    }
     assert_eq!(a.cache.len(), 3)
 }
 
@@ -65,7 +86,7 @@ async fn function_prompt_agent_works() {
     let message = Message::new_user("What's the weather like in Detroit michigan in celcius?");
     a.cache.push(message);
 
-    let json: Value = a.function_completion(function).await.unwrap();
+    let json: Value = a.function_completion(function).await.unwrap().content;
     // let json: Ret = serde_json::from_str(json).unwrap();
     info!("test got json: {:?}", json);
     assert_eq!(
@@ -77,4 +98,104 @@ async fn function_prompt_agent_works() {
             assert!(false, "Location returned incorrectly")
         }
     }
+
+    let mut a = test_anthropic_agent();
+    let function = Function::try_from(test_func_str).unwrap();
+    let message = Message::new_user("What's the weather like in Detroit michigan in celcius?");
+    a.cache.push(message);
+
+    let json: Value = a.function_completion(function).await.unwrap().content;
+    info!("test got json: {:?}", json);
+    assert_eq!(
+        json.get("format").and_then(|value| value.as_str()).unwrap(),
+        "celcius"
+    );
+}
+
+#[ignore]
+#[tokio::test]
+async fn tool_loop_agent_works() {
+    init_test();
+    let mut a = test_openai_agent();
+    let weather_func_str = r#"get_current_weather(location!: string)
+        where
+            i am 'get the current weather in a given location'
+            location is 'the city and state, e.g. san francisco, ca'
+        "#;
+    let mut tools = ToolBox::new();
+    tools.register(
+        Function::try_from(weather_func_str).unwrap(),
+        |args| async move {
+            info!("weather tool called with: {:?}", args);
+            Ok(json!({"temperature": 22, "unit": "celcius", "conditions": "sunny"}))
+        },
+    );
+    a.cache.push(Message::new_user(
+        "What's the weather like in Detroit michigan right now?",
+    ));
+
+    let answer = a.run_with_tools(&tools, 3).await.unwrap();
+    info!("test got answer: {:?}", answer);
+    assert!(answer.contains("22"));
+    assert_eq!(
+        a.cache.as_ref().last().unwrap(),
+        &Message::new_assistant(&answer)
+    );
+}
+
+#[tokio::test]
+async fn failed_tool_call_leaves_cache_untouched() {
+    init_test();
+    let call = |id: &str, name: &str| json!({"id": id, "type": "function", "function": {"name": name, "arguments": "{}"}});
+    let server = StandInServer::spawn(json!({
+        "id": "chatcmpl-tools",
+        "model": "gpt-4o",
+        "choices": [{
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [call("call_a", "get_time"), call("call_b", "get_date")]
+            },
+            "finish_reason": "tool_calls",
+            "index": 0
+        }]
+    }))
+    .await;
+    let mut a = Agent::new(
+        None,
+        CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None),
+    );
+    let mut tools = ToolBox::new();
+    let get_time = Function {
+        name: "get_time".to_owned(),
+        description: "get the current time".to_owned(),
+        params: Default::default(),
+    };
+    tools.register(get_time, |_| async { Ok(json!("noon")) });
+    a.cache.push(Message::new_user("What time is it?"));
+
+    let err = a.run_with_tools(&tools, 3).await.unwrap_err();
+    assert!(matches!(err, AgentError::ToolNotFound(name) if name == "get_date"));
+    // A call without its result would be rejected by the provider on the next request
+    assert_eq!(a.cache.len(), 1);
+}
+
+#[ignore]
+#[tokio::test]
+async fn vision_prompt_agent_works() {
+    init_test();
+    let image = MessageImage::from_path("./tests/test-screenshot.png").unwrap();
+    let message = Message::new_user_with_images("What is in this image?", vec![image]);
+
+    let mut a = test_openai_agent();
+    a.cache.push(message.clone());
+    let result = a.io_completion().await;
+    info!("response: {:?}", result);
+    assert!(result.is_ok());
+
+    let mut a = test_anthropic_agent();
+    a.cache.push(message);
+    let result = a.io_completion().await;
+    info!("response: {:?}", result);
+    assert!(result.is_ok())
 }
@@ -0,0 +1,208 @@
+use crate::{init_test, StandInServer};
+use espionox::{
+    agents::{memory::Message, memory::MessageStack, Agent},
+    language_models::completions::{
+        error::CompletionResult,
+        functions::{Function, ToolCall, ToolChoice, ToolCompletion},
+        inference::{
+            CompletionRequest, CompletionRequestBuilder, CompletionResponse, ProcessResponseReturn,
+        },
+        streaming::{CompletionStreamStatus, ProviderStreamHandler, StreamResponse},
+        CompletionModel, CompletionProvider, ModelParameters,
+    },
+};
+use futures::TryStreamExt;
+use reqwest::header::HeaderMap;
+use reqwest_streams::JsonStreamResponse;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// A made up in-house backend that takes a flat prompt and streams newline delimited deltas
+#[derive(Debug)]
+struct InHouseModel {
+    base_url: String,
+}
+
+#[derive(Debug)]
+struct InHouseRequest {
+    prompt: String,
+    stream: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InHouseChunk {
+    delta: String,
+    done: bool,
+}
+
+impl StreamResponse for InHouseChunk {}
+
+impl From<InHouseChunk> for CompletionStreamStatus {
+    fn from(value: InHouseChunk) -> Self {
+        match value.done {
+            true => CompletionStreamStatus::Finished,
+            false => CompletionStreamStatus::Working(value.delta),
+        }
+    }
+}
+
+impl CompletionRequest for InHouseRequest {
+    fn as_json(&self) -> CompletionResult<Value> {
+        Ok(json!({"prompt": self.prompt, "stream": self.stream}))
+    }
+
+    fn process_response(&self, response: reqwest::Response) -> ProcessResponseReturn<'_> {
+        Box::pin(async move {
+            match self.stream {
+                false => {
+                    let json: Value = response.json().await?;
+                    let text = json["text"].as_str().unwrap_or_default().to_owned();
+                    Ok(CompletionResponse::from(text))
+                }
+                true => {
+                    let stream = response
+                        .json_nl_stream::<Value>(1024)
+                        .map_err(|err| err.into());
+                    Ok(ProviderStreamHandler::custom::<InHouseChunk>(stream).into())
+                }
+            }
+        })
+    }
+}
+
+impl CompletionRequestBuilder for InHouseModel {
+    fn model_str(&self) -> &str {
+        "in-house"
+    }
+
+    fn url_str(&self) -> String {
+        format!("{}/generate", self.base_url)
+    }
+
+    fn serialize_messages(&self, stack: &MessageStack) -> Value {
+        stack.to_string().into()
+    }
+
+    fn headers(&self, api_key: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("x-in-house-key", api_key.parse().unwrap());
+        map
+    }
+
+    fn into_io_req(
+        &self,
+        stack: &MessageStack,
+        _params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(InHouseRequest {
+            prompt: stack.to_string(),
+            stream: false,
+        }))
+    }
+
+    fn into_stream_req(
+        &self,
+        stack: &MessageStack,
+        _params: &ModelParameters,
+    ) -> CompletionResult<Box<dyn CompletionRequest>> {
+        Ok(Box::new(InHouseRequest {
+            prompt: stack.to_string(),
+            stream: true,
+        }))
+    }
+
+    fn serialize_tools(
+        &self,
+        stack: &MessageStack,
+        _params: &ModelParameters,
+        functions: &[Function],
+        _choice: &ToolChoice,
+    ) -> CompletionResult<Value> {
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        Ok(json!({"prompt": stack.to_string(), "tools": names}))
+    }
+
+    fn process_tools_response(&self, response_json: Value) -> CompletionResult<ToolCompletion> {
+        Ok(ToolCompletion::Calls(vec![ToolCall {
+            id: "in-house-call".to_owned(),
+            name: response_json["call"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            arguments: response_json["call"]["args"].to_owned(),
+        }]))
+    }
+}
+
+fn in_house_agent(server: &StandInServer) -> Agent {
+    let provider = CompletionProvider::custom(InHouseModel {
+        base_url: server.base_url.to_owned(),
+    });
+    let llm = CompletionModel::new(provider, ModelParameters::default(), "in-house-key");
+    let mut a = Agent::new(Some("You are an in-house model"), llm);
+    a.cache.push(Message::new_user("Hello!"));
+    a
+}
+
+#[tokio::test]
+async fn custom_provider_io_completion_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({"text": "Hello from in-house"})).await;
+    let mut a = in_house_agent(&server);
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res.content, "Hello from in-house");
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].starts_with("POST /generate"));
+    assert!(requests[0].contains("x-in-house-key: in-house-key"));
+}
+
+#[tokio::test]
+async fn custom_provider_stream_completion_works() {
+    init_test();
+    let body = [
+        json!({"delta": "Hello", "done": false}),
+        json!({"delta": " in-house", "done": false}),
+        json!({"delta": "", "done": true}),
+    ]
+    .iter()
+    .map(|l| format!("{}\n", l))
+    .collect::<String>();
+    let server = StandInServer::spawn_raw("application/x-ndjson", body).await;
+    let mut a = in_house_agent(&server);
+
+    let mut response = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(status)) = response.receive(&mut a).await {
+        match status {
+            CompletionStreamStatus::Working(token) => tokens.push(token),
+            CompletionStreamStatus::Finished => break,
+        }
+    }
+    assert_eq!(tokens.concat(), "Hello in-house");
+    assert_eq!(a.cache.len(), 3);
+}
+
+#[tokio::test]
+async fn custom_provider_function_completion_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({
+        "call": {"name": "get_current_weather", "args": {"location": "Detroit, MI"}}
+    }))
+    .await;
+    let mut a = in_house_agent(&server);
+    let function = Function::try_from(
+        r#"get_current_weather(location!: string)
+        where
+            i am 'get the current weather in a given location'
+        "#,
+    )
+    .unwrap();
+
+    let res = a.function_completion(function).await.unwrap();
+    assert_eq!(res.content, json!({"location": "Detroit, MI"}));
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].contains("\"tools\":[\"get_current_weather\"]"));
+}
@@ -0,0 +1,168 @@
+use crate::{init_test, StandInResponse, StandInServer};
+use espionox::{
+    agents::{memory::Message, Agent, AgentError},
+    language_models::{
+        completions::{
+            error::CompletionError, functions::Function, ollama::builder::OllamaCompletionModel,
+            retry::RetryPolicy, streaming::CompletionStreamStatus, CompletionModel,
+            ModelParameters,
+        },
+        pricing::ModelPricing,
+    },
+};
+use serde_json::json;
+
+fn local_model(server: &StandInServer) -> CompletionModel {
+    CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None)
+        .with_retry_policy(RetryPolicy::none())
+}
+
+fn overloaded() -> StandInResponse {
+    StandInResponse::error(
+        "529 Overloaded",
+        json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}),
+    )
+}
+
+#[tokio::test]
+async fn falls_back_on_outage_and_records_which_model_answered() {
+    init_test();
+    let primary = StandInServer::spawn_sequence(vec![overloaded()]).await;
+    let backup = StandInServer::spawn(json!({
+        "id": "chatcmpl-backup",
+        "usage": {"prompt_tokens": 1_000_000, "completion_tokens": 0, "total_tokens": 1_000_000},
+        "choices": [{"message": {"role": "assistant", "content": "Backup here"}, "finish_reason": "stop", "index": 0}]
+    }))
+    .await;
+    let llm = local_model(&primary)
+        .with_pricing(ModelPricing::new(100.0, 100.0))
+        .with_fallback(local_model(&backup).with_pricing(ModelPricing::new(2.0, 2.0)));
+    let mut a = Agent::new(None, llm);
+    a.cache.push(Message::new_user("Hello!"));
+
+    // Completions from a model with fallbacks can still be sent off to another task
+    let (res, a) = tokio::spawn(async move {
+        let res = a.io_completion().await;
+        (res, a)
+    })
+    .await
+    .unwrap();
+    let res = res.unwrap();
+    assert_eq!(res.content, "Backup here");
+    assert_eq!(res.metadata.fallback_index, Some(1));
+    // Priced as the backup model, which is the one that answered
+    assert!((a.cost - 2.0).abs() < 1e-9);
+    assert_eq!(primary.requests.lock().unwrap().len(), 1);
+    assert_eq!(backup.requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn does_not_fall_back_on_bad_requests() {
+    init_test();
+    let primary = StandInServer::spawn_with_status(
+        "401 Unauthorized",
+        "",
+        "application/json",
+        json!({"error": {"code": "invalid_api_key"}}).to_string(),
+    )
+    .await;
+    let backup = StandInServer::spawn(json!({})).await;
+    let llm = local_model(&primary).with_fallback(local_model(&backup));
+    let mut a = Agent::new(None, llm);
+
+    assert!(matches!(
+        a.io_completion().await,
+        Err(AgentError::CompletionError(
+            CompletionError::Authentication { .. }
+        ))
+    ));
+    assert!(backup.requests.lock().unwrap().is_empty());
+
+    // When every model fails with a transient error, the last one's error is returned
+    let down = StandInServer::spawn_sequence(vec![overloaded()]).await;
+    let mut a = Agent::new(None, local_model(&down).with_fallback(local_model(&down)));
+    assert!(matches!(
+        a.io_completion().await,
+        Err(AgentError::CompletionError(
+            CompletionError::Overloaded { .. }
+        ))
+    ));
+    assert_eq!(down.requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn stream_falls_back_before_first_token() {
+    init_test();
+    let primary = StandInServer::spawn_raw(
+        "application/x-ndjson",
+        format!("{}\n", json!({"error": "server overloaded"})),
+    )
+    .await;
+    let lines = [
+        json!({"model": "llama3", "message": {"role": "assistant", "content": "Backup"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop"}),
+    ];
+    let backup = StandInServer::spawn_raw(
+        "application/x-ndjson",
+        lines.iter().map(|l| format!("{}\n", l)).collect(),
+    )
+    .await;
+    let ollama = |server: &StandInServer| {
+        CompletionModel::new(
+            OllamaCompletionModel::new(&server.base_url, "llama3"),
+            ModelParameters::default(),
+            "",
+        )
+        .with_retry_policy(RetryPolicy::none())
+    };
+    let mut a = Agent::new(None, ollama(&primary).with_fallback(ollama(&backup)));
+
+    let mut response = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(CompletionStreamStatus::Working(token))) = response.receive(&mut a).await {
+        tokens.push(token);
+    }
+    assert_eq!(tokens.concat(), "Backup");
+    assert_eq!(response.metadata().fallback_index, Some(1));
+}
+
+#[tokio::test]
+async fn function_completion_falls_back() {
+    init_test();
+    let primary = StandInServer::spawn_sequence(vec![StandInResponse::error(
+        "503 Service Unavailable",
+        json!({"error": "unavailable"}),
+    )])
+    .await;
+    let backup = StandInServer::spawn(json!({
+        "choices": [{
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_current_weather", "arguments": "{\"location\":\"Tokyo\"}"}
+                }]
+            },
+            "finish_reason": "tool_calls",
+            "index": 0
+        }]
+    }))
+    .await;
+    let mut a = Agent::new(
+        None,
+        local_model(&primary).with_fallback(local_model(&backup)),
+    );
+    let function = Function::try_from(
+        r#"get_current_weather(location!: string)
+        where
+            i am 'get the current weather in a given location'
+        "#,
+    )
+    .unwrap();
+
+    let res = a.function_completion(function).await.unwrap();
+    assert_eq!(res.content, json!({"location": "Tokyo"}));
+    assert_eq!(res.metadata.fallback_index, Some(1));
+}
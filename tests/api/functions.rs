@@ -1,3 +1,5 @@
+use espionox::language_models::completions::functions::{FunctionParam, ParamType, TypedFunction};
+use espionox_derive::EspxFunction;
 use serde_json::{json, Value};
 // https://cookbook.openai.com/examples/how_to_call_functions_with_chat_models
 
@@ -56,3 +58,79 @@ fn compile_functions_into_actual() {
               },
     });
 }
+
+#[derive(Debug, PartialEq, EspxFunction)]
+enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+/// Get an n-day weather forecast
+#[derive(Debug, PartialEq, EspxFunction)]
+struct WeatherForecast {
+    /// The city and state,
+    /// e.g. San Francisco, CA
+    location: String,
+    unit: Option<TemperatureUnit>,
+    /// The number of days to forecast
+    num_days: u32,
+}
+
+#[test]
+fn derived_function_matches_type() {
+    let function = WeatherForecast::function();
+    assert_eq!(function.name, "weather_forecast");
+    assert_eq!(function.description, "Get an n-day weather forecast");
+    assert_eq!(
+        function.params["location"],
+        FunctionParam {
+            description: Some("The city and state, e.g. San Francisco, CA".to_owned()),
+            typ: ParamType::String,
+            required: true,
+        }
+    );
+    assert_eq!(
+        function.params["unit"],
+        FunctionParam {
+            description: None,
+            typ: ParamType::Enum(vec!["celsius".to_owned(), "fahrenheit".to_owned()]),
+            required: false,
+        }
+    );
+    assert_eq!(function.params["num_days"].typ, ParamType::Integer);
+    assert!(function.params["num_days"].required);
+}
+
+#[test]
+fn derived_parser_reads_arguments() {
+    let forecast = WeatherForecast::from_arguments(json!({
+        "location": "Boston, MA",
+        "unit": "fahrenheit",
+        "num_days": 3
+    }))
+    .unwrap();
+    assert_eq!(
+        forecast,
+        WeatherForecast {
+            location: "Boston, MA".to_owned(),
+            unit: Some(TemperatureUnit::Fahrenheit),
+            num_days: 3,
+        }
+    );
+
+    let forecast =
+        WeatherForecast::from_arguments(json!({"location": "Boston, MA", "num_days": 1})).unwrap();
+    assert_eq!(forecast.unit, None);
+
+    let err = WeatherForecast::from_arguments(json!({
+        "location": "Boston, MA",
+        "unit": "kelvin",
+        "num_days": 1
+    }))
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("field `unit`: unknown variant `kelvin`"));
+    let err = WeatherForecast::from_arguments(json!({"location": "Boston, MA"})).unwrap_err();
+    assert_eq!(err.to_string(), "missing field `num_days`");
+}
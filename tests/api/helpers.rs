@@ -6,6 +6,11 @@ use espionox::{
 };
 use once_cell::sync::Lazy;
 use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -38,6 +43,13 @@ pub fn test_anthropic_agent() -> Agent {
 
     Agent::new(Some("I am running tests, say hello"), llm)
 }
+pub fn test_gemini_agent() -> Agent {
+    dotenv().ok();
+    let api_key = std::env::var("GEMINI_KEY").unwrap();
+    let llm = CompletionModel::default_gemini(&api_key);
+
+    Agent::new(Some("I am running tests, say hello"), llm)
+}
 // pub fn test_embedding_agent() -> Agent {
 //     dotenv().ok();
 //     let openai_api_key = std::env::var("OPENAI_KEY").unwrap();
@@ -68,3 +80,127 @@ pub fn test_function() -> Value {
         }
     })
 }
+
+/// Minimal HTTP server standing in for a model provider. Every request gets `body` back as JSON
+/// and is recorded in `requests` as raw text
+pub struct StandInServer {
+    pub base_url: String,
+    pub requests: Arc<Mutex<Vec<String>>>,
+}
+
+/// One response of a `StandInServer`
+#[derive(Clone)]
+pub struct StandInResponse {
+    pub status: &'static str,
+    /// Sent as is, each ending in `\r\n`
+    pub extra_headers: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl StandInResponse {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: "200 OK",
+            extra_headers: "",
+            content_type,
+            body,
+        }
+    }
+
+    /// JSON error with the given status, e.g. `"429 Too Many Requests"`
+    pub fn error(status: &'static str, body: Value) -> Self {
+        Self {
+            status,
+            extra_headers: "",
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+}
+
+impl StandInServer {
+    pub async fn spawn(body: Value) -> Self {
+        Self::spawn_raw("application/json", body.to_string()).await
+    }
+
+    pub async fn spawn_raw(content_type: &'static str, body: String) -> Self {
+        Self::spawn_sequence(vec![StandInResponse::ok(content_type, body)]).await
+    }
+
+    /// Answers every request with `status`, e.g. `"429 Too Many Requests"`. `extra_headers` are
+    /// sent as is, each ending in `\r\n`
+    pub async fn spawn_with_status(
+        status: &'static str,
+        extra_headers: &'static str,
+        content_type: &'static str,
+        body: String,
+    ) -> Self {
+        Self::spawn_sequence(vec![StandInResponse {
+            status,
+            extra_headers,
+            content_type,
+            body,
+        }])
+        .await
+    }
+
+    /// Answers requests with `responses` in order, repeating the last one once they run out
+    pub async fn spawn_sequence(responses: Vec<StandInResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requests_clone = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                let count = {
+                    let mut requests = requests_clone.lock().unwrap();
+                    requests.push(request);
+                    requests.len()
+                };
+                let res = &responses[count.min(responses.len()) - 1];
+                let response = format!(
+                    "HTTP/1.1 {}\r\n{}content-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    res.status,
+                    res.extra_headers,
+                    res.content_type,
+                    res.body.len(),
+                    res.body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.ok();
+            }
+        });
+        Self { base_url, requests }
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = socket.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&buf).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let content_length = head
+                .lines()
+                .find_map(|l| {
+                    let (k, v) = l.split_once(':')?;
+                    match k.eq_ignore_ascii_case("content-length") {
+                        true => v.trim().parse::<usize>().ok(),
+                        false => None,
+                    }
+                })
+                .unwrap_or(0);
+            if body.len() >= content_length {
+                return text;
+            }
+        }
+        if n == 0 {
+            return String::from_utf8_lossy(&buf).to_string();
+        }
+    }
+}
@@ -1,5 +1,28 @@
-use crate::init_test;
-use espionox::{agents::Agent, language_models::completions::CompletionModel};
+use crate::{init_test, StandInResponse, StandInServer};
+use espionox::{
+    agents::{memory::Message, Agent, AgentError},
+    language_models::{
+        completions::{
+            consistency::Vote,
+            error::CompletionError,
+            functions::{Function, ToolCall, ToolChoice, TypedFunction},
+            metadata::{FinishReason, TokenUsage},
+            ollama::builder::OllamaCompletionModel,
+            openai::builder::OpenAiCompatibleModel,
+            retry::RetryPolicy,
+            streaming::{CompletionStreamStatus, StreamError, StreamEvent},
+            CompletionModel, ModelParameters,
+        },
+        embeddings::{ollama::OllamaEmbeddingModel, EmbeddingModel},
+        key_pool::ApiKeyPool,
+        pricing::ModelPricing,
+        rate_limit::RateLimiter,
+    },
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
 
 #[tokio::test]
 async fn failed_request_does_not_overflow_stack() {
@@ -12,3 +35,738 @@ async fn failed_request_does_not_overflow_stack() {
     println!("{:?}", res);
     assert!(res.is_err());
 }
+
+#[tokio::test]
+async fn openai_compatible_io_completion_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({
+        "id": "chatcmpl-local",
+        "object": "chat.completion",
+        "model": "llama-3-8b-instruct",
+        "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+        "choices": [{
+            "message": {"role": "assistant", "content": "Hello from localhost"},
+            "finish_reason": "stop",
+            "index": 0
+        }]
+    }))
+    .await;
+    let llm = CompletionModel::openai_compatible(
+        &format!("{}/v1", server.base_url),
+        "llama-3-8b-instruct",
+        None,
+    );
+    let mut a = Agent::new(Some("You are a local model"), llm);
+    a.cache.push(Message::new_user("Hello!"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res.content, "Hello from localhost");
+    assert_eq!(res.metadata.id.as_deref(), Some("chatcmpl-local"));
+    assert_eq!(res.metadata.usage, Some(TokenUsage::new(5, 2)));
+    assert_eq!(res.metadata.finish_reason, Some(FinishReason::Stop));
+    assert_eq!(a.usage, TokenUsage::new(5, 2));
+    assert_eq!(a.completion_model.params.total_token_count, 7);
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].starts_with("POST /v1/chat/completions"));
+    assert!(!requests[0].to_lowercase().contains("authorization"));
+    assert!(requests[0].contains("\"model\":\"llama-3-8b-instruct\""));
+}
+
+fn openai_choices(answers: &[&str]) -> serde_json::Value {
+    let choices: Vec<_> = answers
+        .iter()
+        .enumerate()
+        .map(|(index, answer)| {
+            json!({
+                "message": {"role": "assistant", "content": answer},
+                "finish_reason": "stop",
+                "index": index
+            })
+        })
+        .collect();
+    json!({
+        "id": "chatcmpl-choices",
+        "model": "gpt-4o",
+        "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        "choices": choices
+    })
+}
+
+#[tokio::test]
+async fn every_choice_returned_and_voted_on() {
+    init_test();
+    let server = StandInServer::spawn(openai_choices(&["4", "5", " 4"])).await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None);
+    let mut a = Agent::new(Some("Answer with a number"), llm);
+    a.cache.push(Message::new_user("What is 2 + 2?"));
+
+    let choices = a.io_completions(3).await.unwrap();
+    assert_eq!(choices.content, vec!["4", "5", " 4"]);
+    assert_eq!(choices.metadata.usage, Some(TokenUsage::new(5, 3)));
+
+    let consensus = a
+        .self_consistent_completion(3, &Vote::Majority)
+        .await
+        .unwrap();
+    assert_eq!(consensus.answer, "4");
+    assert_eq!(consensus.votes, 2);
+    assert_eq!(a.usage, TokenUsage::new(10, 6));
+
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("\"n\":3"));
+    assert_eq!(a.cache.len(), 2);
+}
+
+#[tokio::test]
+async fn io_completion_keeps_first_of_several_choices() {
+    init_test();
+    let server = StandInServer::spawn(openai_choices(&["4", "5"])).await;
+    let mut llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None);
+    llm.params.n = Some(2);
+    let mut a = Agent::new(Some("Answer with a number"), llm);
+    a.cache.push(Message::new_user("What is 2 + 2?"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res.content, "4");
+    assert!(server.requests.lock().unwrap()[0].contains("\"n\":2"));
+}
+
+#[tokio::test]
+async fn missing_choices_requested_separately() {
+    init_test();
+    // Stands in for a server which ignores `n` and only ever answers with one choice
+    let server = StandInServer::spawn_sequence(
+        ["4", "5", "4"]
+            .iter()
+            .map(|a| StandInResponse::ok("application/json", openai_choices(&[a]).to_string()))
+            .collect(),
+    )
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None);
+    let mut a = Agent::new(Some("Answer with a number"), llm);
+    a.cache.push(Message::new_user("What is 2 + 2?"));
+
+    let mut choices = a.io_completions(3).await.unwrap();
+    choices.content.sort();
+    assert_eq!(choices.content, vec!["4", "4", "5"]);
+    assert_eq!(choices.metadata.usage, Some(TokenUsage::new(15, 9)));
+    assert_eq!(server.requests.lock().unwrap().len(), 3);
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Person {
+    name: String,
+    age: u32,
+}
+
+impl TypedFunction for Person {
+    fn function() -> Function {
+        Function::try_from(
+            r#"person(name!: string, age!: integer)
+            where
+                i am 'a person mentioned in the text'
+                age is 'their age in years'
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn from_arguments(arguments: serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(arguments)
+    }
+}
+
+#[tokio::test]
+async fn typed_completion_repairs_invalid_output() {
+    init_test();
+    let server = StandInServer::spawn_sequence(
+        [r#"{"name": "Ann"}"#, r#"{"name": "Ann", "age": 30}"#]
+            .iter()
+            .map(|a| StandInResponse::ok("application/json", openai_choices(&[a]).to_string()))
+            .collect(),
+    )
+    .await;
+    let model = OpenAiCompatibleModel::new(&server.base_url, "gpt-4o").with_structured_outputs();
+    let llm = CompletionModel::new(model, ModelParameters::default(), "");
+    let mut a = Agent::new(None, llm);
+    a.cache.push(Message::new_user("Ann turned 30 today"));
+
+    let person = a.typed_completion::<Person>().await.unwrap();
+    assert_eq!(
+        person.content,
+        Person {
+            name: "Ann".to_owned(),
+            age: 30
+        }
+    );
+    assert_eq!(a.usage, TokenUsage::new(10, 6));
+    assert_eq!(a.cache.len(), 1);
+
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("\"type\":\"json_schema\""));
+    assert!(requests[0].contains("\"name\":\"person\""));
+    assert!(requests[1].contains("missing field `age`"));
+}
+
+#[tokio::test]
+async fn typed_completion_gives_up_after_repairs() {
+    init_test();
+    // Without structured outputs the model is made to call the function instead
+    let server = StandInServer::spawn(json!({
+        "model": "gpt-4o",
+        "choices": [{
+            "message": {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "person", "arguments": "{\"name\": \"Ann\"}"}
+            }]},
+            "finish_reason": "tool_calls",
+            "index": 0
+        }]
+    }))
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None);
+    let mut a = Agent::new(None, llm);
+    a.cache.push(Message::new_user("Ann turned 30 today"));
+
+    let err = a
+        .typed_completion_with_repairs::<Person>(1)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AgentError::InvalidOutput { attempts: 2, .. }));
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("\"tool_choice\""));
+    assert!(!requests[0].contains("json_schema"));
+    assert!(requests[1].contains("missing field `age`"));
+}
+
+#[tokio::test]
+async fn agent_refuses_completions_over_budget() {
+    init_test();
+    let server = StandInServer::spawn(json!({
+        "id": "chatcmpl-local",
+        "model": "llama-3-8b-instruct",
+        "usage": {"prompt_tokens": 600_000, "completion_tokens": 200_000, "total_tokens": 800_000},
+        "choices": [{
+            "message": {"role": "assistant", "content": "That was expensive"},
+            "finish_reason": "stop",
+            "index": 0
+        }]
+    }))
+    .await;
+    let llm = CompletionModel::openai_compatible(
+        &format!("{}/v1", server.base_url),
+        "llama-3-8b-instruct",
+        None,
+    )
+    .with_pricing(ModelPricing::new(1.0, 2.0));
+    let mut a = Agent::new(None, llm).with_budget(1.5);
+    a.cache.push(Message::new_user("Hello!"));
+
+    a.io_completion().await.unwrap();
+    assert!((a.cost - 1.0).abs() < 1e-9);
+    assert!((a.remaining_budget().unwrap() - 0.5).abs() < 1e-9);
+
+    a.io_completion().await.unwrap();
+    assert!((a.cost - 2.0).abs() < 1e-9);
+    assert_eq!(a.remaining_budget(), Some(0.0));
+
+    match a.io_completion().await {
+        Err(AgentError::BudgetExceeded { spent, budget }) => {
+            assert!((spent - 2.0).abs() < 1e-9);
+            assert_eq!(budget, 1.5);
+        }
+        other => panic!("Expected budget to be exceeded, got {:?}", other),
+    }
+    assert!(a.stream_completion().await.is_err());
+    assert_eq!(server.requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn error_statuses_become_typed_errors() {
+    init_test();
+    let body = json!({"error": {"message": "Rate limit reached", "type": "requests", "code": "rate_limit_exceeded"}});
+    let server = StandInServer::spawn_with_status(
+        "429 Too Many Requests",
+        "retry-after: 7\r\n",
+        "application/json",
+        body.to_string(),
+    )
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", Some("key"))
+        .with_retry_policy(RetryPolicy::none());
+    let mut a = Agent::new(None, llm);
+    a.cache.push(Message::new_user("Hello!"));
+
+    match a.io_completion().await {
+        Err(AgentError::CompletionError(CompletionError::RateLimited { retry_after, body })) => {
+            assert_eq!(retry_after, Some(Duration::from_secs(7)));
+            assert!(body.contains("rate_limit_exceeded"));
+        }
+        other => panic!("Expected rate limit error, got {:?}", other),
+    }
+    assert!(matches!(
+        a.stream_completion().await,
+        Err(AgentError::CompletionError(
+            CompletionError::RateLimited { .. }
+        ))
+    ));
+
+    let body = json!({"type": "error", "error": {"type": "invalid_request_error", "message": "prompt is too long: 201000 tokens > 200000 maximum"}});
+    let server = StandInServer::spawn_with_status(
+        "400 Bad Request",
+        "",
+        "application/json",
+        body.to_string(),
+    )
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", Some("key"))
+        .with_retry_policy(RetryPolicy::none());
+    let mut a = Agent::new(None, llm);
+    let err = a.io_completion().await.unwrap_err();
+    assert!(matches!(
+        err,
+        AgentError::CompletionError(CompletionError::ContextLengthExceeded { .. })
+    ));
+}
+
+fn fast_retries() -> RetryPolicy {
+    RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+}
+
+#[tokio::test]
+async fn transient_errors_retried() {
+    init_test();
+    let overloaded =
+        json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}});
+    let success = json!({
+        "id": "chatcmpl-local",
+        "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        "choices": [{"message": {"role": "assistant", "content": "Third time lucky"}, "finish_reason": "stop", "index": 0}]
+    });
+    let server = StandInServer::spawn_sequence(vec![
+        StandInResponse::error("529 Overloaded", overloaded.clone()),
+        StandInResponse::error("503 Service Unavailable", overloaded),
+        StandInResponse::ok("application/json", success.to_string()),
+    ])
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None)
+        .with_retry_policy(fast_retries());
+    let mut a = Agent::new(None, llm);
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res.content, "Third time lucky");
+    assert_eq!(server.requests.lock().unwrap().len(), 3);
+
+    let server = StandInServer::spawn_with_status(
+        "401 Unauthorized",
+        "",
+        "application/json",
+        json!({"error": {"code": "invalid_api_key"}}).to_string(),
+    )
+    .await;
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None)
+        .with_retry_policy(fast_retries());
+    let mut a = Agent::new(None, llm);
+    assert!(a.io_completion().await.is_err());
+    assert_eq!(server.requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn stream_failing_before_first_token_retried() {
+    init_test();
+    let error_line = format!("{}\n", json!({"error": "server overloaded, try again"}));
+    let lines = [
+        json!({"model": "llama3", "message": {"role": "assistant", "content": "Hello"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop"}),
+    ];
+    let body = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+    let server = StandInServer::spawn_sequence(vec![
+        StandInResponse::ok("application/x-ndjson", error_line.clone()),
+        StandInResponse::ok("application/x-ndjson", body),
+    ])
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::new(&server.base_url, "llama3"),
+        ModelParameters::default(),
+        "",
+    )
+    .with_retry_policy(fast_retries());
+    let mut a = Agent::new(None, llm);
+
+    let mut response = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(CompletionStreamStatus::Working(token))) = response.receive(&mut a).await {
+        tokens.push(token);
+    }
+    assert_eq!(tokens.concat(), "Hello");
+    assert_eq!(server.requests.lock().unwrap().len(), 2);
+
+    let server = StandInServer::spawn_raw("application/x-ndjson", error_line).await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::new(&server.base_url, "llama3"),
+        ModelParameters::default(),
+        "",
+    )
+    .with_retry_policy(fast_retries());
+    let mut a = Agent::new(None, llm);
+    match a.stream_completion().await {
+        Err(AgentError::CompletionError(CompletionError::Stream(StreamError::RetryError {
+            attempts,
+            ..
+        }))) => assert_eq!(attempts, 3),
+        other => panic!("Expected stream to run out of retries, got {:?}", other),
+    }
+    assert_eq!(server.requests.lock().unwrap().len(), 3);
+}
+
+// Time is paused, so elapsed time is only what the limiter made the models wait
+#[tokio::test(start_paused = true)]
+async fn rate_limiter_shared_between_models() {
+    init_test();
+    let completion_server = StandInServer::spawn(json!({
+        "id": "chatcmpl-local",
+        "usage": {"prompt_tokens": 50, "completion_tokens": 6000, "total_tokens": 6050},
+        "choices": [{"message": {"role": "assistant", "content": "A very long answer"}, "finish_reason": "length", "index": 0}]
+    }))
+    .await;
+    let embedding_server = StandInServer::spawn(json!({
+        "data": [{"embedding": [0.1]}],
+        "usage": {"prompt_tokens": 1, "total_tokens": 1}
+    }))
+    .await;
+    // Refills 100 tokens a second
+    let limiter = RateLimiter::new(None, Some(6000));
+    let mut llm = CompletionModel::openai_compatible(&completion_server.base_url, "gpt-4o", None)
+        .with_rate_limiter(limiter.clone());
+    llm.params = ModelParameters {
+        max_tokens: Some(6000),
+        ..Default::default()
+    };
+    let embedder = EmbeddingModel::openai_compatible(&embedding_server.base_url, "small", None)
+        .with_rate_limiter(limiter);
+    let mut a = Agent::new(None, llm);
+
+    let start = tokio::time::Instant::now();
+    a.io_completion().await.unwrap();
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    // The completion used 50 tokens more than the whole bucket, so the embedding waits for them
+    // and what it is estimated to need itself
+    embedder.get_embedding("hello").await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(500) && elapsed <= Duration::from_millis(520),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn streamed_completion_reconciles_rate_limit() {
+    init_test();
+    let lines = [
+        json!({"model": "llama3", "message": {"role": "assistant", "content": "Hi"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop", "prompt_eval_count": 10, "eval_count": 3}),
+    ];
+    let body = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+    let server = StandInServer::spawn_raw("application/x-ndjson", body).await;
+    // Refills 100 tokens a second
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::new(&server.base_url, "llama3"),
+        ModelParameters {
+            max_tokens: Some(6000),
+            ..Default::default()
+        },
+        "",
+    )
+    .with_rate_limiter(RateLimiter::new(None, Some(6000)));
+    let mut a = Agent::new(None, llm);
+
+    let start = tokio::time::Instant::now();
+    for _ in 0..2 {
+        let mut response = a.stream_completion().await.unwrap();
+        while let Ok(Some(CompletionStreamStatus::Working(_))) = response.receive(&mut a).await {}
+    }
+    // The first stream gave back all but the 13 tokens it used, which the second waits for
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(130) && elapsed <= Duration::from_millis(131),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn key_pool_rotates_past_rejected_keys() {
+    init_test();
+    let success = json!({
+        "id": "chatcmpl-local",
+        "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+        "choices": [{"message": {"role": "assistant", "content": "Hello"}, "finish_reason": "stop", "index": 0}]
+    });
+    let server = StandInServer::spawn_sequence(vec![
+        StandInResponse::error(
+            "401 Unauthorized",
+            json!({"error": {"code": "invalid_api_key"}}),
+        ),
+        StandInResponse::ok("application/json", success.to_string()),
+    ])
+    .await;
+    let pool = ApiKeyPool::new(["key-one", "key-two"]);
+    let llm = CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None)
+        .with_retry_policy(RetryPolicy::none())
+        .with_key_pool(pool.clone());
+    let mut a = Agent::new(None, llm);
+
+    assert_eq!(a.io_completion().await.unwrap().content, "Hello");
+    assert_eq!(a.io_completion().await.unwrap().content, "Hello");
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].contains("Bearer key-one"));
+    assert!(requests[1].contains("Bearer key-two"));
+    // The rejected key is left out until its cooldown ends
+    assert!(requests[2].contains("Bearer key-two"));
+
+    let stats = pool.stats();
+    assert_eq!((stats[0].requests, stats[0].errors), (1, 1));
+    assert!(stats[0].disabled);
+    assert_eq!(stats[1].requests, 2);
+    assert_eq!(stats[1].usage, TokenUsage::new(10, 4));
+}
+
+#[tokio::test]
+async fn openai_compatible_embedding_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({
+        "object": "list",
+        "data": [{"object": "embedding", "index": 0, "embedding": [0.1, 0.2, 0.3]}],
+        "model": "nomic-embed-text",
+        "usage": {"prompt_tokens": 2, "total_tokens": 2}
+    }))
+    .await;
+    let embedder = EmbeddingModel::openai_compatible(
+        &format!("{}/v1/", server.base_url),
+        "nomic-embed-text",
+        Some("local-key"),
+    );
+
+    let embedding = embedder.get_embedding("hello").await.unwrap();
+    assert_eq!(embedding, vec![0.1, 0.2, 0.3]);
+
+    let embedder = embedder.with_pricing(ModelPricing::new(0.5, 0.0));
+    let embedding = embedder.get_embedding_with_metadata("hello").await.unwrap();
+    let usage = embedding.metadata.usage.unwrap();
+    assert_eq!(usage, TokenUsage::new(2, 0));
+    assert_eq!(embedder.cost(&usage), 0.000001);
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].starts_with("POST /v1/embeddings"));
+    assert!(requests[0].contains("Bearer local-key"));
+}
+
+#[tokio::test]
+async fn ollama_io_completion_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({
+        "model": "llama3",
+        "created_at": "2024-05-01T18:39:00.000Z",
+        "message": {"role": "assistant", "content": "Hello from ollama"},
+        "done": true,
+        "prompt_eval_count": 12,
+        "eval_count": 4
+    }))
+    .await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::new(&server.base_url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("You are a local model"), llm);
+    a.cache.push(Message::new_user("Hello!"));
+
+    let res = a.io_completion().await.unwrap();
+    assert_eq!(res.content, "Hello from ollama");
+    assert_eq!(res.metadata.model.as_deref(), Some("llama3"));
+    assert_eq!(a.usage, TokenUsage::new(12, 4));
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].starts_with("POST /api/chat"));
+    assert!(requests[0].contains("\"model\":\"llama3\""));
+    assert!(requests[0].contains("\"stream\":false"));
+}
+
+#[tokio::test]
+async fn ollama_stream_completion_works() {
+    init_test();
+    let lines = [
+        json!({"model": "llama3", "message": {"role": "assistant", "content": "Hello"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": " from"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": " ollama"}, "done": false}),
+        json!({"model": "llama3", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop", "prompt_eval_count": 10, "eval_count": 3}),
+    ];
+    let body = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+    let server = StandInServer::spawn_raw("application/x-ndjson", body).await;
+    let llm = CompletionModel::new(
+        OllamaCompletionModel::new(&server.base_url, "llama3"),
+        ModelParameters::default(),
+        "",
+    );
+    let mut a = Agent::new(Some("You are a local model"), llm);
+    a.cache.push(Message::new_user("Hello!"));
+
+    let mut response = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(status)) = response.receive(&mut a).await {
+        match status {
+            CompletionStreamStatus::Working(token) => tokens.push(token),
+            CompletionStreamStatus::Finished => break,
+        }
+    }
+    assert_eq!(tokens.concat(), "Hello from ollama");
+    assert_eq!(a.cache.len(), 3);
+    assert_eq!(response.metadata().finish_reason, Some(FinishReason::Stop));
+    assert_eq!(a.usage, TokenUsage::new(10, 3));
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].contains("\"stream\":true"));
+}
+
+#[tokio::test]
+async fn ollama_embedding_works() {
+    init_test();
+    let server = StandInServer::spawn(json!({"embedding": [0.5, 0.25, 0.125]})).await;
+    let embedder = EmbeddingModel::new(
+        OllamaEmbeddingModel::new(&server.base_url, "nomic-embed-text"),
+        "",
+    );
+
+    let embedding = embedder.get_embedding("hello").await.unwrap();
+    assert_eq!(embedding, vec![0.5, 0.25, 0.125]);
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].starts_with("POST /api/embeddings"));
+    assert!(requests[0].contains("\"prompt\":\"hello\""));
+}
+
+#[tokio::test]
+async fn openai_sse_stream_parsed_and_errors_typed() {
+    init_test();
+    let chunk = |content: &str| json!({"id": "chatcmpl-1", "model": "gpt-4o", "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]});
+    let finish = json!({"id": "chatcmpl-1", "model": "gpt-4o", "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}]});
+    let body = format!(
+        ": keep-alive\n\ndata: {}\n\ndata: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+        chunk("Hello"),
+        chunk(" there"),
+        finish
+    );
+    let server = StandInServer::spawn_raw("text/event-stream", body).await;
+    let mut a = Agent::new(
+        None,
+        CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None),
+    );
+    let mut response = a.stream_completion().await.unwrap();
+    let mut tokens = vec![];
+    while let Ok(Some(CompletionStreamStatus::Working(token))) = response.receive(&mut a).await {
+        tokens.push(token);
+    }
+    assert_eq!(tokens.concat(), "Hello there");
+    assert_eq!(a.cache.len(), 1);
+
+    let body = format!(
+        "data: {}\n\ndata: {}\n\n",
+        chunk("Hello"),
+        json!({"error": {"message": "The server is overloaded", "type": "server_error"}})
+    );
+    let server = StandInServer::spawn_raw("text/event-stream", body).await;
+    let mut a = Agent::new(
+        None,
+        CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None),
+    );
+    let mut response = a.stream_completion().await.unwrap();
+    assert!(matches!(
+        response.receive(&mut a).await,
+        Ok(Some(CompletionStreamStatus::Working(_)))
+    ));
+    match response.receive(&mut a).await {
+        Err(StreamError::Provider(err)) => {
+            assert!(matches!(*err, CompletionError::Overloaded { .. }))
+        }
+        other => panic!("Expected a typed provider error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn tool_calls_streamed_from_openai_compatible_server() {
+    init_test();
+    let delta = |delta: serde_json::Value, finish_reason: Option<&str>| json!({"id": "chatcmpl-1", "model": "gpt-4o", "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]});
+    let chunks = [
+        delta(
+            json!({"role": "assistant", "tool_calls": [{"index": 0, "id": "call_1", "type": "function", "function": {"name": "get_current_weather", "arguments": ""}}]}),
+            None,
+        ),
+        delta(
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": "{\"location\""}}]}),
+            None,
+        ),
+        delta(
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": ": \"Tokyo\"}"}}]}),
+            None,
+        ),
+        delta(json!({}), Some("tool_calls")),
+    ];
+    let body: String = chunks
+        .iter()
+        .map(|c| format!("data: {}\n\n", c))
+        .chain(["data: [DONE]\n\n".to_owned()])
+        .collect();
+    let server = StandInServer::spawn_raw("text/event-stream", body).await;
+    let mut a = Agent::new(
+        None,
+        CompletionModel::openai_compatible(&server.base_url, "gpt-4o", None),
+    );
+    let function = Function::try_from(
+        r#"get_current_weather(location!: string)
+        where
+            i am 'get the current weather in a given location'
+        "#,
+    )
+    .unwrap();
+
+    let mut response = a
+        .stream_tool_completion(&[function], ToolChoice::Auto)
+        .await
+        .unwrap();
+    let mut fragments = vec![];
+    let mut calls = vec![];
+    while let Some(event) = response.next().await {
+        match event.unwrap() {
+            StreamEvent::ToolCallArguments { fragment, .. } => fragments.push(fragment),
+            StreamEvent::ToolCall(call) => calls.push(call),
+            _ => {}
+        }
+    }
+    assert_eq!(fragments.len(), 2);
+    let expected = ToolCall {
+        id: "call_1".to_owned(),
+        name: "get_current_weather".to_owned(),
+        arguments: json!({"location": "Tokyo"}),
+    };
+    assert_eq!(calls, std::slice::from_ref(&expected));
+    assert_eq!(
+        response.metadata().finish_reason,
+        Some(FinishReason::ToolCalls)
+    );
+
+    response.commit(&mut a);
+    assert_eq!(
+        a.cache.as_ref().last().unwrap(),
+        &Message::new_tool_calls("", vec![expected])
+    );
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0].contains("\"stream\":true"));
+    assert!(requests[0].contains("\"tools\":"));
+}
@@ -1,4 +1,6 @@
 pub mod agent;
+pub mod custom_provider;
+pub mod fallback;
 // pub mod environment;
 
 #[cfg(any(feature = "tools"))]
@@ -1,6 +1,10 @@
 #[cfg(test)]
 mod tests {
-    use espionox::agents::memory::{Message, MessageRole, MessageStack};
+    use espionox::{
+        agents::memory::{Message, MessageRole, MessageStack},
+        language_models::completions::functions::ToolCall,
+    };
+    use serde_json::json;
 
     #[test]
     fn message_stack_filter_by_behavior() {
@@ -42,4 +46,30 @@ mod tests {
         let m = stack_ref.pop(Some(MessageRole::System));
         assert_eq!(None, m);
     }
+
+    #[test]
+    fn tool_messages_round_trip_through_serde() {
+        let call = ToolCall {
+            id: "call_abc".to_owned(),
+            name: "get_current_weather".to_owned(),
+            arguments: json!({"location": "Detroit, MI"}),
+        };
+        let mut stack = MessageStack::new("SYSTEM");
+        stack.push(Message::new_user("What's the weather in Detroit?"));
+        stack.push(Message::new_tool_calls("", vec![call.clone()]));
+        stack.push(Message::new_tool_result(&call, "sunny"));
+        stack.push(Message::new_assistant("It's sunny"));
+        assert_eq!(5, stack.len());
+
+        let serialized = serde_json::to_string(&stack).unwrap();
+        let deserialized: MessageStack = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(stack, deserialized);
+        assert_eq!(
+            deserialized.as_ref()[3].role,
+            MessageRole::Tool {
+                tool_call_id: "call_abc".to_owned(),
+                name: "get_current_weather".to_owned()
+            }
+        );
+    }
 }